@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::error::{Result, RustBtcError};
+use crate::storage::StorageBatch;
+use crate::transaction::Transaction;
+use crate::utxo::{BlockUndo, SpentCoin};
+
+/// 地址历史中的一笔交易：交易向该地址支付，或者花费了该地址的输出
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressTx {
+    pub txid: String,
+    pub height: u64,
+    /// 交易在区块中的序号
+    pub position: usize,
+    /// 支付给该地址的金额
+    pub received: Amount,
+    /// 从该地址花费的金额
+    pub sent: Amount,
+}
+
+/// 把连接区块对地址索引的修改加入`batch`：删除被花费的输出，添加新创建的输出，并为涉及的每个地址记录一条历史。
+/// 只索引P2PKH输出，按公钥哈希归类
+pub fn index_block(batch: &mut StorageBatch, block: &Block, undo: &BlockUndo) -> Result<()> {
+    let spent_by_tx = split_undo(block, undo)?;
+    for (position, (tx, spent)) in block.transactions.iter().zip(spent_by_tx).enumerate() {
+        for record in spent {
+            if let Some(pubkey_hash) = record.coin.output.script_pubkey.p2pkh_hash() {
+                batch.delete_address_utxo(pubkey_hash, &record.txid, record.vout);
+            }
+        }
+        for (vout, pubkey_hash, value) in indexed_outputs(tx) {
+            batch.save_address_utxo(pubkey_hash, &tx.id, vout, value)?;
+        }
+        for (pubkey_hash, entry) in address_txs(tx, spent, block.height, position)? {
+            batch.save_address_tx(&pubkey_hash, &entry)?;
+        }
+    }
+    Ok(())
+}
+
+/// 撤销`index_block`的修改。交易按逆序处理，同一区块内创建又被花费的输出才能正确恢复
+pub fn unindex_block(batch: &mut StorageBatch, block: &Block, undo: &BlockUndo) -> Result<()> {
+    let spent_by_tx = split_undo(block, undo)?;
+    for (position, (tx, spent)) in block.transactions.iter().zip(spent_by_tx).enumerate().rev() {
+        for pubkey_hash in address_txs(tx, spent, block.height, position)?.keys() {
+            batch.delete_address_tx(pubkey_hash, block.height, position);
+        }
+        for (vout, pubkey_hash, _) in indexed_outputs(tx) {
+            batch.delete_address_utxo(pubkey_hash, &tx.id, vout);
+        }
+        for record in spent.iter().rev() {
+            if let Some(pubkey_hash) = record.coin.output.script_pubkey.p2pkh_hash() {
+                batch.save_address_utxo(pubkey_hash, &record.txid, record.vout, record.coin.output.value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 按交易拆分撤销数据，coinbase交易没有花费任何输出
+fn split_undo<'a>(block: &Block, undo: &'a BlockUndo) -> Result<Vec<&'a [SpentCoin]>> {
+    let mut rest = undo.spent.as_slice();
+    let mut spent_by_tx = Vec::with_capacity(block.transactions.len());
+    for tx in &block.transactions {
+        let count = if tx.is_coinbase() { 0 } else { tx.vin.len() };
+        if count > rest.len() {
+            return Err(RustBtcError::UTXOError(format!("区块 {} 的撤销数据不完整", block.hash)));
+        }
+        let (spent, remaining) = rest.split_at(count);
+        spent_by_tx.push(spent);
+        rest = remaining;
+    }
+    Ok(spent_by_tx)
+}
+
+/// 交易中进入UTXO集的P2PKH输出
+fn indexed_outputs(tx: &Transaction) -> impl Iterator<Item = (usize, &[u8], Amount)> {
+    tx.vout
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| !output.is_unspendable())
+        .filter_map(|(vout, output)| Some((vout, output.script_pubkey.p2pkh_hash()?, output.value)))
+}
+
+/// 交易涉及的每个公钥哈希及其收到和花费的金额
+fn address_txs(tx: &Transaction, spent: &[SpentCoin], height: u64, position: usize) -> Result<BTreeMap<Vec<u8>, AddressTx>> {
+    let mut entries: BTreeMap<Vec<u8>, AddressTx> = BTreeMap::new();
+    let overflow = || RustBtcError::InvalidAmount(format!("交易 {} 涉及的地址金额溢出", tx.id));
+    let empty = || AddressTx {
+        txid: tx.id.clone(),
+        height,
+        position,
+        received: Amount::ZERO,
+        sent: Amount::ZERO,
+    };
+
+    for record in spent {
+        if let Some(pubkey_hash) = record.coin.output.script_pubkey.p2pkh_hash() {
+            let entry = entries.entry(pubkey_hash.to_vec()).or_insert_with(empty);
+            entry.sent = entry.sent.checked_add(record.coin.output.value).ok_or_else(overflow)?;
+        }
+    }
+    for (_, pubkey_hash, value) in indexed_outputs(tx) {
+        let entry = entries.entry(pubkey_hash.to_vec()).or_insert_with(empty);
+        entry.received = entry.received.checked_add(value).ok_or_else(overflow)?;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::ChainParams;
+    use crate::storage::Storage;
+    use crate::transaction::{TxInput, TxOutput};
+    use crate::utxo::Coin;
+    use crate::wallet::{address_to_pubkey_hash, Wallet};
+
+    fn spend(id: &str, input: (&str, usize, u64), output: TxOutput) -> Transaction {
+        Transaction {
+            id: id.to_string(),
+            vin: vec![TxInput::new(input.0.to_string(), input.1, Amount::from_sat(input.2))],
+            vout: vec![output],
+            lock_time: 0,
+        }
+    }
+
+    #[test]
+    fn test_index_and_unindex_block() -> Result<()> {
+        let wallet = Wallet::new()?;
+        let address = wallet.get_address();
+        let pubkey_hash = address_to_pubkey_hash(&address)?;
+        let storage = Storage::temporary()?;
+        let utxos = |storage: &Storage| -> Result<Vec<(String, usize, Amount)>> {
+            storage.iter_address_utxos(&pubkey_hash).collect()
+        };
+
+        let mut batch = StorageBatch::new();
+        batch.save_address_utxo(&pubkey_hash, "x", 0, Amount::from_sat(50))?;
+        storage.write(batch)?;
+
+        // a花费x，b在同一区块内花费a创建的输出
+        let coinbase = Transaction::new_coinbase(&address, "coinbase", 0, Amount::ZERO, &ChainParams::default())?;
+        let a = spend("a", ("x", 0, 50), TxOutput::new(Amount::from_sat(40), &address)?);
+        let b = spend("b", ("a", 0, 40), TxOutput::new(Amount::from_sat(30), &address)?);
+        let mut block = Block::new(vec![coinbase.clone(), a.clone(), b], "0".to_string())?;
+        block.height = 5;
+        let spent = |txid: &str, value: u64| SpentCoin {
+            txid: txid.to_string(),
+            vout: 0,
+            coin: Coin {
+                output: TxOutput::new(Amount::from_sat(value), &address).unwrap(),
+                height: 4,
+                is_coinbase: false,
+                median_time_past: 0,
+            },
+        };
+        let undo = BlockUndo { spent: vec![spent("x", 50), spent("a", 40)] };
+
+        let mut batch = StorageBatch::new();
+        index_block(&mut batch, &block, &undo)?;
+        storage.write(batch)?;
+        let mut after = utxos(&storage)?;
+        after.sort();
+        let mut expected = vec![
+            ("b".to_string(), 0, Amount::from_sat(30)),
+            (coinbase.id.clone(), 0, coinbase.vout[0].value),
+        ];
+        expected.sort();
+        assert_eq!(after, expected);
+        let history = storage.iter_address_txs(&pubkey_hash).collect::<Result<Vec<_>>>()?;
+        assert_eq!(history.iter().map(|tx| tx.position).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!((history[1].received, history[1].sent), (Amount::from_sat(40), Amount::from_sat(50)));
+
+        // 断开区块后恢复到连接之前
+        let mut batch = StorageBatch::new();
+        unindex_block(&mut batch, &block, &undo)?;
+        storage.write(batch)?;
+        assert_eq!(utxos(&storage)?, vec![("x".to_string(), 0, Amount::from_sat(50))]);
+        assert_eq!(storage.iter_address_txs(&pubkey_hash).count(), 0);
+
+        // 撤销数据与区块不符时拒绝
+        let mut batch = StorageBatch::new();
+        assert!(index_block(&mut batch, &block, &BlockUndo::default()).is_err());
+
+        Ok(())
+    }
+}
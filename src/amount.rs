@@ -0,0 +1,182 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::RustBtcError;
+
+/// 一个币等于多少最小单位（聪）
+pub const COIN: u64 = 100_000_000;
+/// 币的小数位数
+const DECIMALS: usize = 8;
+
+/// 共识允许的最大金额：任何输出、输出总额和手续费都不能超过它
+pub const MAX_MONEY: Amount = Amount(21_000_000 * COIN);
+
+/// 以聪为单位的金额。只提供检查溢出的运算，超过`MAX_MONEY`的值可以表示，由共识检查拒绝
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const ONE_SAT: Amount = Amount(1);
+    pub const ONE_BTC: Amount = Amount(COIN);
+
+    pub const fn from_sat(sat: u64) -> Amount {
+        Amount(sat)
+    }
+
+    pub const fn to_sat(self) -> u64 {
+        self.0
+    }
+
+    /// 整数个币，溢出时返回None
+    pub fn from_btc(btc: u64) -> Option<Amount> {
+        btc.checked_mul(COIN).map(Amount)
+    }
+
+    /// 是否不超过`MAX_MONEY`
+    pub fn is_in_money_range(self) -> bool {
+        self <= MAX_MONEY
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    pub fn checked_mul(self, factor: u64) -> Option<Amount> {
+        self.0.checked_mul(factor).map(Amount)
+    }
+
+    pub fn checked_div(self, divisor: u64) -> Option<Amount> {
+        self.0.checked_div(divisor).map(Amount)
+    }
+
+    /// 金额求和，任何一步溢出时返回None
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts.into_iter().try_fold(Amount::ZERO, Amount::checked_add)
+    }
+
+    /// 共识意义上的求和：每一项和累计总额都不超过`MAX_MONEY`时返回总额
+    pub fn money_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts.into_iter().try_fold(Amount::ZERO, |total, amount| {
+            if !amount.is_in_money_range() {
+                return None;
+            }
+            total.checked_add(amount).filter(|total| total.is_in_money_range())
+        })
+    }
+}
+
+/// 以币为单位显示，省略小数部分末尾的0，例如`1.5 BTC`
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let whole = self.0 / COIN;
+        let fraction = self.0 % COIN;
+        if fraction == 0 {
+            write!(f, "{} BTC", whole)
+        } else {
+            let digits = format!("{:0width$}", fraction, width = DECIMALS);
+            write!(f, "{}.{} BTC", whole, digits.trim_end_matches('0'))
+        }
+    }
+}
+
+/// 解析带单位的金额：`1.5 BTC`（最多8位小数）或`150 sat`，单位不区分大小写，不接受负数和超过`MAX_MONEY`的值
+impl FromStr for Amount {
+    type Err = RustBtcError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| RustBtcError::InvalidAmount(format!("金额 \"{}\" {}", s, reason));
+
+        let trimmed = s.trim();
+        let split = trimmed
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .ok_or_else(|| invalid("缺少单位"))?;
+        let (number, unit) = trimmed.split_at(split);
+        let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+        if whole.is_empty() || !whole.bytes().all(|b| b.is_ascii_digit()) || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid("格式无效"));
+        }
+
+        let amount = match unit.trim().to_ascii_lowercase().as_str() {
+            "btc" => {
+                if fraction.len() > DECIMALS {
+                    return Err(invalid("的小数位数超过8位"));
+                }
+                let whole: u64 = whole.parse().map_err(|_| invalid("超出范围"))?;
+                let fraction: u64 = format!("{:0<width$}", fraction, width = DECIMALS)
+                    .parse()
+                    .map_err(|_| invalid("格式无效"))?;
+                Amount::from_btc(whole)
+                    .and_then(|amount| amount.checked_add(Amount(fraction)))
+                    .ok_or_else(|| invalid("超出范围"))?
+            }
+            "sat" | "sats" => {
+                if number.contains('.') {
+                    return Err(invalid("不能包含小于1聪的部分"));
+                }
+                Amount(whole.parse().map_err(|_| invalid("超出范围"))?)
+            }
+            _ => return Err(invalid("的单位未知")),
+        };
+
+        if !amount.is_in_money_range() {
+            return Err(invalid("超过最大金额"));
+        }
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_format() {
+        let parse = |s: &str| s.parse::<Amount>();
+        assert_eq!(parse("1.5 BTC").unwrap(), Amount::from_sat(150_000_000));
+        assert_eq!(parse("0.00000001btc").unwrap(), Amount::ONE_SAT);
+        assert_eq!(parse("150 sat").unwrap(), Amount::from_sat(150));
+        assert_eq!(parse("21000000 BTC").unwrap(), MAX_MONEY);
+
+        for bad in ["1.5", "-1 BTC", "1.123456789 BTC", "1.5 sat", "21000000.00000001 BTC", "1 ETH", ".5 BTC", "1..2 BTC"] {
+            assert!(matches!(parse(bad), Err(RustBtcError::InvalidAmount(_))), "{}", bad);
+        }
+
+        assert_eq!(Amount::from_sat(150_000_000).to_string(), "1.5 BTC");
+        assert_eq!(Amount::ONE_SAT.to_string(), "0.00000001 BTC");
+        assert_eq!(Amount::from_btc(19).unwrap().to_string(), "19 BTC");
+        assert_eq!(Amount::ZERO.to_string(), "0 BTC");
+        for amount in [Amount::ONE_SAT, Amount::from_sat(123_456_789), MAX_MONEY] {
+            assert_eq!(amount.to_string().parse::<Amount>().unwrap(), amount);
+        }
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let max = Amount::from_sat(u64::MAX);
+        assert_eq!(max.checked_add(Amount::ONE_SAT), None);
+        assert_eq!(Amount::ZERO.checked_sub(Amount::ONE_SAT), None);
+        assert_eq!(max.checked_mul(2), None);
+        assert_eq!(Amount::ONE_BTC.checked_div(0), None);
+        assert_eq!(Amount::checked_sum([max, Amount::ONE_SAT]), None);
+        assert_eq!(
+            Amount::checked_sum([Amount::ONE_BTC, Amount::ONE_SAT]),
+            Some(Amount::from_sat(COIN + 1))
+        );
+        assert!(MAX_MONEY.is_in_money_range());
+        assert!(!MAX_MONEY.checked_add(Amount::ONE_SAT).unwrap().is_in_money_range());
+        assert_eq!(Amount::from_btc(u64::MAX), None);
+
+        assert_eq!(Amount::money_sum([MAX_MONEY]), Some(MAX_MONEY));
+        assert_eq!(Amount::money_sum([MAX_MONEY, Amount::ONE_SAT]), None);
+        assert_eq!(Amount::money_sum([max, Amount::ZERO]), None);
+        assert_eq!(Amount::money_sum([]), Some(Amount::ZERO));
+    }
+}
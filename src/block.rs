@@ -1,48 +1,103 @@
+use std::collections::HashMap;
 use std::time::{SystemTime, UNIX_EPOCH};
 use serde::{Serialize, Deserialize};
 use sha2::{Sha256, Digest};
 use hex;
 use tracing::{info, error, debug};
 
+use crate::amount::Amount;
 use crate::error::{Result, RustBtcError};
-use crate::transaction::Transaction;
+use crate::params::ChainParams;
+use crate::pow::{Target, Work, POW_LIMIT_BITS};
+use crate::transaction::{Transaction, TxOutput};
 use crate::utxo::UTXOSet;
 
-const MINING_DIFFICULTY: usize = 4;
+/// 序列化后的区块头长度
+pub const BLOCK_HEADER_SIZE: usize = 80;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Block {
+/// 区块头，区块哈希只由区块头计算得出
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
     pub version: i32,
-    pub timestamp: u64,
-    pub transactions: Vec<Transaction>,
     pub prev_block_hash: String,
     pub merkle_root: String,
+    pub timestamp: u32,
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    /// 按固定布局序列化：版本(4) | 前置哈希(32) | 默克尔根(32) | 时间(4) | 难度(4) | nonce(4)，整数均为小端序
+    pub fn serialize(&self) -> Result<[u8; BLOCK_HEADER_SIZE]> {
+        let mut data = [0u8; BLOCK_HEADER_SIZE];
+        data[0..4].copy_from_slice(&self.version.to_le_bytes());
+        data[4..36].copy_from_slice(&hash_to_bytes(&self.prev_block_hash)?);
+        data[36..68].copy_from_slice(&hash_to_bytes(&self.merkle_root)?);
+        data[68..72].copy_from_slice(&self.timestamp.to_le_bytes());
+        data[72..76].copy_from_slice(&self.bits.to_le_bytes());
+        data[76..80].copy_from_slice(&self.nonce.to_le_bytes());
+        Ok(data)
+    }
+
+    /// 区块头的双重SHA256哈希
+    pub fn hash(&self) -> Result<String> {
+        Ok(hex::encode(double_sha256(&self.serialize()?)))
+    }
+
+    /// 由`bits`字段解码出的难度目标
+    pub fn target(&self) -> Result<Target> {
+        Target::from_compact(self.bits)
+    }
+}
+
+/// 将十六进制哈希解码为32字节，创世区块的前置哈希（"0"或空串）视为全零
+pub fn hash_to_bytes(hash: &str) -> Result<[u8; 32]> {
+    if hash.is_empty() || hash == "0" {
+        return Ok([0u8; 32]);
+    }
+    hex::decode(hash)
+        .map_err(|e| RustBtcError::HashError(e.to_string()))?
+        .try_into()
+        .map_err(|_| RustBtcError::HashError(format!("哈希长度无效: {}", hash)))
+}
+
+pub fn double_sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(Sha256::digest(data)).into()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
+    /// 缓存的区块头哈希
     pub hash: String,
-    pub nonce: u64,
     pub height: u64,
-    pub bits: u32,
 }
 
 impl Block {
-    pub fn new(transactions: Vec<Transaction>, prev_block_hash: String) -> Result<Block> {
+    /// 创建区块，包含带签名的交易时会在coinbase中写入见证承诺，coinbase的交易ID随之改变
+    pub fn new(mut transactions: Vec<Transaction>, prev_block_hash: String) -> Result<Block> {
         debug!("创建新区块，前置哈希: {}", prev_block_hash);
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
-            .map_err(|e| RustBtcError::TimestampError(e))?
-            .as_secs();
+            .map_err(RustBtcError::TimestampError)?
+            .as_secs() as u32;
 
+        Self::commit_witnesses(&mut transactions)?;
         let merkle_root = Self::calculate_merkle_root(&transactions)?;
         
         let mut block = Block {
-            version: 1,
-            timestamp,
+            header: BlockHeader {
+                version: 1,
+                prev_block_hash,
+                merkle_root,
+                timestamp,
+                bits: POW_LIMIT_BITS,
+                nonce: 0,
+            },
             transactions,
-            prev_block_hash,
-            merkle_root,
             hash: String::new(),
-            nonce: 0,
             height: 0,
-            bits: 0x1d00ffff, // Default difficulty bits
         };
 
         block.hash = block.calculate_hash()?;
@@ -51,14 +106,27 @@ impl Block {
     }
 
     fn calculate_merkle_root(transactions: &[Transaction]) -> Result<String> {
-        if transactions.is_empty() {
-            return Ok(String::from("0000000000000000000000000000000000000000000000000000000000000000"));
-        }
+        let txids = transactions
+            .iter()
+            .map(|tx| tx.txid())
+            .collect::<Result<_>>()?;
+        Ok(Self::merkle_root(txids))
+    }
 
-        let mut hashes: Vec<String> = transactions
+    /// 见证默克尔根：由各交易的wtxid计算，coinbase包含承诺本身，其wtxid视为全零
+    pub fn calculate_witness_root(transactions: &[Transaction]) -> Result<String> {
+        let wtxids = transactions
             .iter()
-            .map(|tx| tx.hash())
+            .enumerate()
+            .map(|(i, tx)| if i == 0 && tx.is_coinbase() { Ok("0".repeat(64)) } else { tx.wtxid() })
             .collect::<Result<_>>()?;
+        Ok(Self::merkle_root(wtxids))
+    }
+
+    fn merkle_root(mut hashes: Vec<String>) -> String {
+        if hashes.is_empty() {
+            return String::from("0000000000000000000000000000000000000000000000000000000000000000");
+        }
 
         while hashes.len() > 1 {
             let mut new_hashes = Vec::new();
@@ -76,59 +144,102 @@ impl Block {
             hashes = new_hashes;
         }
 
-        Ok(hashes[0].clone())
+        hashes.swap_remove(0)
     }
 
-    pub fn new_genesis_block(address: &str) -> Result<Block> {
-        let coinbase = Transaction::new_coinbase(address, "Genesis Block")?;
+    /// 区块包含带签名的交易时，在coinbase中写入见证承诺，使签名数据同样受工作量证明保护
+    fn commit_witnesses(transactions: &mut [Transaction]) -> Result<()> {
+        let needs_commitment = transactions.first().is_some_and(|tx| tx.is_coinbase())
+            && transactions.iter().any(|tx| tx.has_witness());
+        if needs_commitment {
+            let commitment = hash_to_bytes(&Self::calculate_witness_root(transactions)?)?;
+            transactions[0].set_witness_commitment(&commitment)?;
+        }
+        Ok(())
+    }
+
+    /// `params`网络的创世区块，难度为该网络的最低难度
+    pub fn new_genesis_block(address: &str, params: &ChainParams) -> Result<Block> {
+        let coinbase = Transaction::new_coinbase(address, "Genesis Block", 0, Amount::ZERO, params)?;
         let transactions = vec![coinbase];
         let merkle_root = Self::calculate_merkle_root(&transactions)?;
         
         let mut block = Block {
-            version: 1,
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)?
-                .as_secs(),
-            transactions: transactions.clone(),
-            prev_block_hash: String::from("0"),
-            merkle_root,
-            nonce: 0,
+            header: BlockHeader {
+                version: 1,
+                prev_block_hash: String::from("0"),
+                merkle_root,
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)?
+                    .as_secs() as u32,
+                bits: params.pow_limit_bits,
+                nonce: 0,
+            },
+            transactions,
             hash: String::new(),
             height: 0,
-            bits: 0x1d00ffff, // Default difficulty bits
         };
         
-        block.mine_block(MINING_DIFFICULTY)?;
+        block.mine_block()?;
         Ok(block)
     }
-    
-    pub fn mine_block(&mut self, difficulty: usize) -> Result<()> {
-        let target = "0".repeat(difficulty);
-        info!("开始挖矿，难度: {}", difficulty);
-        debug!("目标前缀: {}", target);
+
+    /// 由`bits`字段解码出的难度目标
+    pub fn target(&self) -> Result<Target> {
+        self.header.target()
+    }
+
+    /// 该区块贡献的工作量
+    pub fn work(&self) -> Result<Work> {
+        Ok(self.target()?.work())
+    }
+
+    pub fn mine_block(&mut self) -> Result<()> {
+        let target = self.target()?;
+        info!("开始挖矿，难度: {:#010x}", self.header.bits);
+        debug!("目标值: {}", target);
         
-        let mut attempts = 0;
-        while !self.hash.starts_with(&target) {
-            self.nonce += 1;
+        let mut attempts: u64 = 0;
+        let mut header = self.header.serialize()?;
+        while !target.is_met_by(&double_sha256(&header)) {
+            // nonce用尽时推进时间戳，换一个新的搜索空间
+            self.header.nonce = self.header.nonce.wrapping_add(1);
+            if self.header.nonce == 0 {
+                self.header.timestamp += 1;
+                header[68..72].copy_from_slice(&self.header.timestamp.to_le_bytes());
+            }
+            header[76..80].copy_from_slice(&self.header.nonce.to_le_bytes());
             attempts += 1;
-            self.hash = self.calculate_hash()?;
             
-            if attempts % 100000 == 0 {
-                debug!("挖矿尝试次数: {}, 当前nonce: {}", attempts, self.nonce);
+            if attempts.is_multiple_of(100000) {
+                debug!("挖矿尝试次数: {}, 当前nonce: {}", attempts, self.header.nonce);
             }
         }
+        self.hash = self.calculate_hash()?;
         
-        info!("区块已挖出！Nonce: {}, Hash: {}", self.nonce, self.hash);
+        info!("区块已挖出！Nonce: {}, Hash: {}", self.header.nonce, self.hash);
         Ok(())
     }
 
     pub fn calculate_hash(&self) -> Result<String> {
-        let data = bincode::serialize(self)
-            .map_err(|e| RustBtcError::Serialization(e))?;
-            
-        let hash = Sha256::digest(&data);
-        let hash_str = hex::encode(hash);
-        Ok(hash_str)
+        self.header.hash()
+    }
+
+    fn hash_meets_target(hash: &str, target: &Target) -> bool {
+        match hex::decode(hash) {
+            Ok(bytes) => target.is_met_by(&bytes),
+            Err(_) => false,
+        }
+    }
+
+    /// 验证区块哈希不大于`bits`所编码的目标值
+    pub fn check_proof_of_work(&self) -> Result<bool> {
+        let target = self.target()?;
+        if !Self::hash_meets_target(&self.hash, &target) {
+            error!("区块哈希 {} 未达到目标值 {}", self.hash, target);
+            return Ok(false);
+        }
+        Ok(true)
     }
 
     pub fn get_transactions(&self) -> &Vec<Transaction> {
@@ -147,42 +258,38 @@ impl Block {
         Ok(true)
     }
 
-    pub fn is_valid(&self) -> Result<bool> {
-        debug!("开始验证区块...");
-        
-        // 验证时间戳
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map_err(|e| RustBtcError::TimestampError(e))?
-            .as_secs();
-            
-        if self.timestamp > current_time {
-            error!("区块时间戳 {} 大于当前时间 {}", self.timestamp, current_time);
-            return Ok(false);
-        }
-
-        // 验证交易
-        if self.transactions.is_empty() {
-            error!("区块不包含任何交易");
-            return Ok(false);
-        }
-
-        // 验证第一笔交易是否为coinbase交易
-        if !self.transactions[0].is_coinbase() {
-            error!("区块的第一笔交易不是coinbase交易");
+    /// 区块头中的默克尔根是否与区块内的交易一致
+    pub fn check_merkle_root(&self) -> Result<bool> {
+        let merkle_root = Self::calculate_merkle_root(&self.transactions)?;
+        if merkle_root != self.header.merkle_root {
+            error!("默克尔根不符，区块头: {}, 计算结果: {}", self.header.merkle_root, merkle_root);
             return Ok(false);
         }
+        Ok(true)
+    }
 
-        // 验证所有交易
-        for (i, tx) in self.transactions.iter().enumerate() {
-            debug!("验证第 {} 笔交易: {}", i, tx.id);
-            if !tx.verify_transaction_data()? {
-                error!("交易 {} 数据验证失败", tx.id);
-                return Ok(false);
+    /// coinbase中的见证承诺是否与区块内交易的签名数据一致，没有承诺的区块不能包含带签名的交易
+    pub fn check_witness_commitment(&self) -> Result<bool> {
+        let commitment = self
+            .transactions
+            .first()
+            .filter(|tx| tx.is_coinbase())
+            .and_then(|coinbase| coinbase.witness_commitment());
+        match commitment {
+            Some(commitment) => {
+                let witness_root = Self::calculate_witness_root(&self.transactions)?;
+                if commitment != hash_to_bytes(&witness_root)? {
+                    error!("见证承诺不符，coinbase: {}, 计算结果: {}", hex::encode(commitment), witness_root);
+                    return Ok(false);
+                }
+            }
+            None => {
+                if let Some(tx) = self.transactions.iter().find(|tx| tx.has_witness()) {
+                    error!("区块缺少见证承诺，交易 {} 带有签名数据", tx.id);
+                    return Ok(false);
+                }
             }
         }
-
-        info!("区块验证通过");
         Ok(true)
     }
 
@@ -211,19 +318,64 @@ impl Block {
             return Ok(false);
         }
 
-        // 验证所有交易
-        for tx in self.transactions.iter() {
-            if !tx.verify(utxo_set)? {
-                debug!("交易验证失败");
+        // 验证工作量证明
+        if !self.check_proof_of_work()? {
+            debug!("区块哈希未达到难度目标");
+            return Ok(false);
+        }
+
+        // 验证所有交易的输入金额
+        for tx in self.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            let mut input_values = Vec::with_capacity(tx.vin.len());
+            for input in &tx.vin {
+                input_values.push(utxo_set.find_transaction_output(&input.txid, input.vout)?.value);
+            }
+            let (Some(input_value), Some(output_value)) = (Amount::money_sum(input_values), tx.output_total()) else {
+                debug!("交易 {} 的金额总和超过最大金额", tx.id);
+                return Ok(false);
+            };
+            if input_value < output_value {
+                debug!("交易 {} 的输入金额不足", tx.id);
                 return Ok(false);
             }
         }
 
-        Ok(true)
+        // 并行执行输入脚本
+        self.verify_scripts(utxo_set)
+    }
+
+    /// 并行执行区块内所有输入的脚本，被花费的输出从`utxo_set`或区块内前面的交易中查找
+    pub fn verify_scripts(&self, utxo_set: &UTXOSet) -> Result<bool> {
+        let mut created: HashMap<(&str, usize), &TxOutput> = HashMap::new();
+        let mut spent_outputs = Vec::with_capacity(self.transactions.len());
+        for tx in &self.transactions {
+            let mut outputs = Vec::new();
+            if !tx.is_coinbase() {
+                for input in &tx.vin {
+                    let output = match created.get(&(input.txid.as_str(), input.vout)) {
+                        Some(output) => (*output).clone(),
+                        None => utxo_set.find_transaction_output(&input.txid, input.vout)?,
+                    };
+                    outputs.push(output);
+                }
+            }
+            spent_outputs.push(outputs);
+            for (vout, output) in tx.vout.iter().enumerate() {
+                created.insert((tx.id.as_str(), vout), output);
+            }
+        }
+
+        match Transaction::find_invalid_input(&self.transactions, &spent_outputs)? {
+            Some((t, i, e)) => {
+                debug!("交易 {} 的第 {} 个输入脚本验证失败: {}", self.transactions[t].id, i, e);
+                Ok(false)
+            }
+            None => Ok(true),
+        }
     }
 
     pub fn is_genesis(&self) -> bool {
-        self.prev_block_hash == "0"
+        self.header.prev_block_hash == "0"
     }
 }
 
@@ -242,30 +394,36 @@ impl Block {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::script::Script;
+    use crate::transaction::TxInput;
     use crate::wallet::Wallet;
 
     fn create_test_wallet() -> Result<Wallet> {
         Wallet::new()
     }
 
-    fn create_test_block(prev_hash: &str, nonce: u64) -> Result<Block> {
+    const TEST_PREV_HASH: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+
+    fn create_test_block(prev_hash: &str, nonce: u32) -> Result<Block> {
         let wallet = create_test_wallet()?;
         let address = wallet.get_address();
-        let coinbase = Transaction::new_coinbase(&address, "Test Block")?;
+        let coinbase = Transaction::new_coinbase(&address, "Test Block", 0, Amount::ZERO, &ChainParams::default())?;
         
         let mut block = Block {
-            version: 1,
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
+            header: BlockHeader {
+                version: 1,
+                prev_block_hash: prev_hash.to_string(),
+                merkle_root: Block::calculate_merkle_root(std::slice::from_ref(&coinbase))?,
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs() as u32,
+                bits: POW_LIMIT_BITS,
+                nonce,
+            },
             transactions: vec![coinbase],
-            prev_block_hash: prev_hash.to_string(),
-            merkle_root: Block::calculate_merkle_root(&vec![coinbase])?,
             hash: String::new(),
-            nonce,
             height: 0,
-            bits: 0x1d00ffff, // Default difficulty bits
         };
         
         block.hash = block.calculate_hash()?;
@@ -274,31 +432,95 @@ mod tests {
 
     #[test]
     fn test_block_creation_and_mining() -> Result<()> {
-        let block = create_test_block("test_prev_hash", 0)?;
+        let block = create_test_block(TEST_PREV_HASH, 0)?;
         
         // 验证区块字段
         assert!(!block.transactions.is_empty());
-        assert_eq!(block.prev_block_hash, "test_prev_hash");
+        assert_eq!(block.header.prev_block_hash, TEST_PREV_HASH);
         assert_eq!(block.height, 0);
         
         // 验证挖矿
         let mut mining_block = block.clone();
-        mining_block.mine_block(4)?;
+        mining_block.mine_block()?;
         assert!(mining_block.validate(&UTXOSet::new())?);
+        assert!(mining_block.check_proof_of_work()?);
         
         Ok(())
     }
 
+    #[test]
+    fn test_proof_of_work_uses_bits() -> Result<()> {
+        let mut block = create_test_block(TEST_PREV_HASH, 0)?;
+        block.header.bits = 0x207fffff;
+        block.mine_block()?;
+        assert!(block.check_proof_of_work()?);
+
+        // 提高难度后同一个哈希不再满足目标
+        block.header.bits = 0x1d00ffff;
+        assert!(!block.check_proof_of_work()?);
+        assert!(!block.validate(&UTXOSet::new())?);
+
+        // 无法解码的难度编码直接报错
+        block.header.bits = 0x04923456;
+        assert!(block.check_proof_of_work().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_witness_commitment() -> Result<()> {
+        let wallet = create_test_wallet()?;
+        let coinbase = Transaction::new_coinbase(&wallet.get_address(), "Witness", 0, Amount::ZERO, &ChainParams::default())?;
+
+        // 只有coinbase的区块没有签名数据，不需要承诺
+        let block = Block::new(vec![coinbase.clone()], TEST_PREV_HASH.to_string())?;
+        assert!(block.transactions[0].witness_commitment().is_none());
+        assert!(block.check_witness_commitment()?);
+
+        let mut spend = Transaction {
+            id: String::new(),
+            vin: vec![TxInput::new(coinbase.id.clone(), 0, coinbase.vout[0].value)],
+            vout: vec![TxOutput::new(Amount::from_btc(40).unwrap(), &wallet.get_address())?],
+            lock_time: 0,
+        };
+        spend.id = spend.txid()?;
+        spend.sign(&wallet)?;
+        let block = Block::new(vec![coinbase.clone(), spend], TEST_PREV_HASH.to_string())?;
+        let committed = &block.transactions[0];
+        assert_eq!(
+            committed.witness_commitment(),
+            Some(&hash_to_bytes(&Block::calculate_witness_root(&block.transactions)?)?[..])
+        );
+        assert_eq!(committed.id, committed.txid()?);
+        assert_ne!(committed.id, coinbase.id);
+        assert!(block.check_merkle_root()? && block.check_witness_commitment()?);
+
+        // 签名数据被替换
+        let mut tampered = block.clone();
+        tampered.transactions[1].vin[0].script_sig = Script::new().push_slice(b"other");
+        assert!(tampered.check_merkle_root()?);
+        assert!(!tampered.check_witness_commitment()?);
+
+        // 缺少承诺
+        let mut stripped = block.clone();
+        stripped.transactions[0] = coinbase;
+        assert!(!stripped.check_witness_commitment()?);
+
+        Ok(())
+    }
+
     #[test]
     fn test_genesis_block() -> Result<()> {
         let wallet = create_test_wallet()?;
-        let genesis = Block::new_genesis_block(&wallet.get_address())?;
+        let params = ChainParams::regtest();
+        let genesis = Block::new_genesis_block(&wallet.get_address(), &params)?;
         
         // 验证创世区块
         assert!(genesis.validate(&UTXOSet::new())?);
+        assert_eq!(genesis.header.bits, params.pow_limit_bits);
         assert!(genesis.is_genesis());
         assert_eq!(genesis.height, 0);
-        assert_eq!(genesis.prev_block_hash, "0");
+        assert_eq!(genesis.header.prev_block_hash, "0");
         
         Ok(())
     }
@@ -307,18 +529,20 @@ mod tests {
     fn test_invalid_block() -> Result<()> {
         // 创建一个无效区块（没有交易）
         let mut invalid_block = Block {
-            version: 1,
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
+            header: BlockHeader {
+                version: 1,
+                prev_block_hash: TEST_PREV_HASH.to_string(),
+                merkle_root: Block::calculate_merkle_root(&[])?,
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs() as u32,
+                bits: POW_LIMIT_BITS,
+                nonce: 0,
+            },
             transactions: vec![],
-            prev_block_hash: "test_prev_hash".to_string(),
-            merkle_root: Block::calculate_merkle_root(&vec![])?,
             hash: String::new(),
-            nonce: 0,
             height: 0,
-            bits: 0x1d00ffff, // Default difficulty bits
         };
         
         invalid_block.hash = invalid_block.calculate_hash()?;
@@ -326,4 +550,28 @@ mod tests {
         
         Ok(())
     }
+
+    #[test]
+    fn test_header_hash() -> Result<()> {
+        let block = create_test_block(TEST_PREV_HASH, 7)?;
+        let header = block.header.serialize()?;
+        assert_eq!(header.len(), BLOCK_HEADER_SIZE);
+        assert_eq!(&header[4..36], hex::decode(TEST_PREV_HASH).unwrap().as_slice());
+        assert_eq!(&header[76..80], &7u32.to_le_bytes());
+
+        // 区块哈希只覆盖区块头，与缓存的哈希字段和交易内容本身无关
+        let mut other = block.clone();
+        other.hash = "ff".repeat(32);
+        other.transactions.clear();
+        assert_eq!(other.calculate_hash()?, block.calculate_hash()?);
+
+        other.header.nonce += 1;
+        assert_ne!(other.calculate_hash()?, block.calculate_hash()?);
+
+        // 前置哈希必须是32字节的十六进制串
+        other.header.prev_block_hash = "test_prev_hash".to_string();
+        assert!(other.calculate_hash().is_err());
+
+        Ok(())
+    }
 }
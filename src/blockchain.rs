@@ -1,37 +1,276 @@
-use std::fs;
-use std::path::Path;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
 use tracing::{info, warn, error, debug};
 
-use crate::block::Block;
+use crate::addrindex::{self, AddressTx};
+use crate::block::{Block, BlockHeader};
 use crate::transaction::Transaction;
 use crate::error::{Result, RustBtcError};
+use crate::orphan::OrphanPool;
+use crate::params::{ChainParams, RetargetMode};
+use crate::pow::{Target, Work, U256};
+use crate::storage::{Storage, StorageBatch};
+use crate::utxo::{UTXOSet, DEFAULT_UTXO_CACHE_SIZE};
+use crate::validation::{self, BlockContext, MAX_BLOCK_SIZE};
+use crate::wallet::address_to_pubkey_hash;
 
-const MAX_BLOCK_SIZE: usize = 1_000_000; // 1MB
 const MAX_CHAIN_LENGTH: usize = 1_000_000;
+/// 计算中位时间时使用的区块数
+const MEDIAN_TIME_SPAN: usize = 11;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// 区块索引项：记录每个已知区块（包括侧链区块）在区块树中的位置和累计工作量
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockIndex {
+    pub hash: String,
+    pub header: BlockHeader,
+    pub height: u64,
+    /// 从创世区块到该区块的累计工作量
+    pub chain_work: Work,
+    /// 连接时验证失败的区块及其后代不会再被选为主链
+    pub invalid: bool,
+}
+
+/// 交易在主链上的位置，交易索引中每个交易ID对应一项
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxLocation {
+    pub block_hash: String,
+    pub height: u64,
+    /// 交易在区块中的序号
+    pub position: usize,
+}
+
+/// 主链上的交易及其确认信息
+#[derive(Debug, Clone)]
+pub struct ConfirmedTransaction {
+    pub transaction: Transaction,
+    pub location: TxLocation,
+    /// 所在区块的时间戳
+    pub block_time: u32,
+    /// 确认数，所在区块本身算一个
+    pub confirmations: u64,
+}
+
+/// `add_block`对主链造成的变化
+#[derive(Debug, Default)]
+pub struct ChainUpdate {
+    /// 新连接到主链的区块，按高度升序
+    pub connected: Vec<Block>,
+    /// 重组时从主链断开的区块，按高度降序
+    pub disconnected: Vec<Block>,
+    /// 区块作为孤块暂存时，需要向来源节点请求的缺失祖先区块
+    pub missing_parent: Option<String>,
+}
+
+impl ChainUpdate {
+    /// 是否发生了重组
+    pub fn is_reorg(&self) -> bool {
+        !self.disconnected.is_empty()
+    }
+
+    /// 合并后续的主链变化，被后续重组断开的区块不再计入连接列表
+    fn merge(&mut self, other: ChainUpdate) {
+        for block in other.disconnected {
+            match self.connected.iter().position(|b| b.hash == block.hash) {
+                Some(pos) => {
+                    self.connected.remove(pos);
+                }
+                None => self.disconnected.push(block),
+            }
+        }
+        self.connected.extend(other.connected);
+    }
+}
+
+/// 区块链状态。区块、撤销数据、区块索引和链尾都保存在`Storage`中，内存里只保留区块索引和主链的区块哈希
 pub struct Blockchain {
-    blocks: Vec<Block>,
-    current_hash: String,
+    storage: Arc<Storage>,
+    /// 当前主链（累计工作量最大的有效链）各高度的区块哈希
+    active: Vec<String>,
+    params: ChainParams,
+    index: HashMap<String, BlockIndex>,
+    utxo_set: UTXOSet,
+    /// 是否随区块连接和断开维护交易索引
+    tx_index: bool,
+    /// 是否随区块连接和断开维护地址索引
+    address_index: bool,
+    /// 网络时间相对本地时钟的偏移（秒）
+    time_offset: i64,
+    orphans: OrphanPool,
 }
 
 impl Blockchain {
     pub fn new() -> Result<Self> {
-        info!("创建新的区块链");
-        Ok(Blockchain {
-            blocks: Vec::new(),
-            current_hash: String::new(),
-        })
+        Self::with_params(ChainParams::default())
     }
 
-    pub fn add_block(&mut self, block: Block) -> Result<()> {
-        debug!("开始添加新区块, 前置哈希: {}", block.prev_block_hash);
-        
-        let block_size = bincode::serialize(&block)
-            .map_err(|e| RustBtcError::Serialization(e))?
-            .len();
+    /// 保存在临时存储中的区块链，丢弃后数据随之删除
+    pub fn with_params(params: ChainParams) -> Result<Self> {
+        Self::with_storage(Arc::new(Storage::temporary()?), params)
+    }
+
+    /// 从`storage`加载区块链：只读取区块索引和链尾，再沿索引回溯出主链。
+    /// UTXO集直接使用存储中的条目，只有它与链尾不一致时才重建
+    pub fn with_storage(storage: Arc<Storage>, params: ChainParams) -> Result<Self> {
+        params.validate()?;
+        info!("加载区块链，难度调整模式: {:?}", params.retarget_mode);
+        let index: HashMap<String, BlockIndex> = storage
+            .iter_block_index()
+            .map(|entry| entry.map(|entry| (entry.hash.clone(), entry)))
+            .collect::<Result<_>>()?;
+
+        let mut active = Vec::new();
+        let mut current = match storage.get_tip()? {
+            Some(tip) => Some(index.get(&tip).ok_or_else(|| {
+                RustBtcError::InvalidChain(format!("链尾 {} 不在区块索引中", tip))
+            })?),
+            None => None,
+        };
+        while let Some(entry) = current {
+            active.push(entry.hash.clone());
+            current = if entry.height == 0 {
+                None
+            } else {
+                Some(index.get(&entry.header.prev_block_hash).ok_or_else(|| {
+                    RustBtcError::InvalidChain(format!("区块 {} 的前置区块不在区块索引中", entry.hash))
+                })?)
+            };
+        }
+        active.reverse();
+
+        let tip_height = (active.len() as u64).checked_sub(1);
+        let utxo_set = UTXOSet::with_storage(storage.clone(), DEFAULT_UTXO_CACHE_SIZE, tip_height);
+        let mut blockchain = Blockchain {
+            storage,
+            active,
+            params,
+            index,
+            utxo_set,
+            tx_index: false,
+            address_index: false,
+            time_offset: 0,
+            orphans: OrphanPool::new(),
+        };
+        let tip = blockchain.active.last().cloned();
+        if blockchain.storage.get_utxo_tip()? != tip {
+            warn!("UTXO集与链尾不一致，重建UTXO集");
+            let mut utxo_set = std::mem::take(&mut blockchain.utxo_set);
+            utxo_set.reindex(&blockchain)?;
+            blockchain.utxo_set = utxo_set;
+        }
+        info!("区块链加载完成，已知区块 {} 个，主链高度: {}", blockchain.index.len(), blockchain.active.len());
+        Ok(blockchain)
+    }
+
+    pub fn storage(&self) -> &Arc<Storage> {
+        &self.storage
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// 与主链对应的UTXO集
+    pub fn utxo_set(&self) -> &UTXOSet {
+        &self.utxo_set
+    }
+
+    /// 启用或停用交易索引。启用时如果存储中的索引与链尾不一致就重建；
+    /// 停用后已有的索引数据保留，但不再更新
+    pub fn set_tx_index(&mut self, enabled: bool) -> Result<()> {
+        let tip = self.active.last().map(String::as_str);
+        if enabled && self.storage.get_tx_index_tip()?.as_deref() != tip {
+            self.rebuild_tx_index()?;
+        }
+        self.tx_index = enabled;
+        Ok(())
+    }
+
+    pub fn tx_index_enabled(&self) -> bool {
+        self.tx_index
+    }
+
+    /// 启用或停用地址索引，与交易索引一样在落后于链尾时重建。
+    /// 启用后UTXO集按地址查询余额和可花费输出时使用索引
+    pub fn set_address_index(&mut self, enabled: bool) -> Result<()> {
+        let tip = self.active.last().map(String::as_str);
+        if enabled && self.storage.get_address_index_tip()?.as_deref() != tip {
+            self.rebuild_address_index()?;
+        }
+        self.address_index = enabled;
+        self.utxo_set.set_address_index(enabled);
+        Ok(())
+    }
+
+    pub fn address_index_enabled(&self) -> bool {
+        self.address_index
+    }
+
+    /// 设置由对等节点时间得出的网络时间偏移
+    pub fn set_time_offset(&mut self, offset: i64) {
+        self.time_offset = offset;
+    }
+
+    /// 网络调整时间：本地时间加上网络时间偏移
+    pub fn adjusted_time(&self) -> Result<u64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(now.saturating_add_signed(self.time_offset))
+    }
+
+    pub fn add_block(&mut self, block: Block) -> Result<ChainUpdate> {
+        self.process_block(block, None)
+    }
+
+    /// 处理来自`peer`的区块：前置区块未知时暂存到孤块池，区块被接受后自动连接等待它的孤块
+    pub fn process_block(&mut self, block: Block, peer: Option<SocketAddr>) -> Result<ChainUpdate> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        self.orphans.expire(now);
+
+        if !self.index.is_empty() && !self.index.contains_key(&block.header.prev_block_hash) {
+            // 孤块只做与上下文无关的检查
+            self.check_block(&block)?;
+            let hash = block.hash.clone();
+            self.orphans.add(block, peer, now)?;
+            let missing_parent = self.orphans.missing_ancestor(&hash);
+            info!("区块 {} 的前置区块未知，暂存为孤块，缺失的祖先: {:?}", hash, missing_parent);
+            return Ok(ChainUpdate {
+                missing_parent,
+                ..ChainUpdate::default()
+            });
+        }
+
+        let hash = block.hash.clone();
+        let mut update = self.accept_block(block)?;
+
+        let mut parents = vec![hash];
+        while let Some(parent) = parents.pop() {
+            for child in self.orphans.take_children(&parent) {
+                let child_hash = child.hash.clone();
+                debug!("前置区块 {} 已接受，连接孤块 {}", parent, child_hash);
+                match self.accept_block(child) {
+                    Ok(child_update) => {
+                        update.merge(child_update);
+                        parents.push(child_hash);
+                    }
+                    Err(e) => warn!("孤块 {} 连接失败: {}", child_hash, e),
+                }
+            }
+        }
+
+        Ok(update)
+    }
+
+    /// 孤块池中暂存的区块数量
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.len()
+    }
+
+    /// 与链状态无关的区块检查
+    fn check_block(&self, block: &Block) -> Result<()> {
+        let block_size = bincode::serialize(&block)?.len();
             
         if block_size > MAX_BLOCK_SIZE {
             error!("区块大小 {} 超过最大限制 {}", block_size, MAX_BLOCK_SIZE);
@@ -41,84 +280,506 @@ impl Blockchain {
             )));
         }
         
-        if self.blocks.len() >= MAX_CHAIN_LENGTH {
-            error!("区块链长度 {} 超过最大限制 {}", self.blocks.len(), MAX_CHAIN_LENGTH);
+        if self.active.len() >= MAX_CHAIN_LENGTH {
+            error!("区块链长度 {} 超过最大限制 {}", self.active.len(), MAX_CHAIN_LENGTH);
             return Err(RustBtcError::InvalidChain(format!(
                 "区块链长度 {} 超过最大限制 {}",
-                self.blocks.len(), MAX_CHAIN_LENGTH
+                self.active.len(), MAX_CHAIN_LENGTH
             )));
         }
-        
-        if !self.blocks.is_empty() && block.prev_block_hash != self.current_hash {
-            error!("区块的前置哈希 {} 与当前哈希 {} 不匹配", 
-                block.prev_block_hash, self.current_hash);
+
+        if self.index.contains_key(&block.hash) || self.orphans.contains(&block.hash) {
+            return Err(RustBtcError::BlockError(format!("区块 {} 已存在", block.hash)));
+        }
+
+        if !block.verify_hash()? {
             return Err(RustBtcError::InvalidBlock(format!(
-                "区块的前置哈希 {} 与当前哈希 {} 不匹配",
-                block.prev_block_hash, self.current_hash
+                "区块哈希 {} 与区块内容不符",
+                block.hash
+            )));
+        }
+
+        if !block.check_proof_of_work()? {
+            return Err(RustBtcError::InvalidBlock(format!(
+                "区块哈希 {} 未满足难度目标 {:#010x}",
+                block.hash, block.header.bits
             )));
         }
 
-        self.current_hash = block.hash.clone();
-        self.blocks.push(block);
-        info!("成功添加新区块，当前区块链长度: {}", self.blocks.len());
-        
         Ok(())
     }
 
-    pub fn get_block(&self, hash: &str) -> Result<&Block> {
-        debug!("查找哈希为 {} 的区块", hash);
-        self.blocks
-            .iter()
-            .find(|block| block.hash == hash)
-            .ok_or_else(|| {
-                error!("未找到哈希为 {} 的区块", hash);
-                RustBtcError::BlockNotFound(hash.to_string())
-            })
+    /// 将前置区块已知的区块加入区块树，必要时切换主链
+    fn accept_block(&mut self, mut block: Block) -> Result<ChainUpdate> {
+        debug!("开始添加新区块, 前置哈希: {}", block.header.prev_block_hash);
+        self.check_block(&block)?;
+
+        // 第一个区块作为创世区块，其余区块必须连接到已知区块上
+        let (height, parent_work) = if self.index.is_empty() {
+            (0, Work::zero())
+        } else {
+            let parent = self.index.get(&block.header.prev_block_hash).ok_or_else(|| {
+                error!("区块的前置区块 {} 未知", block.header.prev_block_hash);
+                RustBtcError::InvalidBlock(format!(
+                    "区块的前置区块 {} 未知",
+                    block.header.prev_block_hash
+                ))
+            })?;
+            if parent.invalid {
+                return Err(RustBtcError::InvalidBlock(format!(
+                    "区块的前置区块 {} 无效",
+                    parent.hash
+                )));
+            }
+            (parent.height + 1, parent.chain_work)
+        };
+
+        validation::check_block_header(&block, &self.block_context(&block.header.prev_block_hash, height)?)?;
+
+        block.height = height;
+        let chain_work = parent_work + block.work()?;
+        let hash = block.hash.clone();
+        let entry = BlockIndex {
+            hash: hash.clone(),
+            header: block.header.clone(),
+            height,
+            chain_work,
+            invalid: false,
+        };
+
+        // 区块先作为侧链区块保存，连接到主链时只需写入撤销数据和高度
+        let mut batch = StorageBatch::new();
+        batch.save_block(&block)?;
+        batch.save_block_index(&entry)?;
+        self.storage.write(batch)?;
+        self.index.insert(hash.clone(), entry);
+
+        // 只有累计工作量严格更大时才切换主链，工作量相同时保留先收到的链
+        if self.active.is_empty() || chain_work > self.chain_work() {
+            return self.activate_best_chain(&hash);
+        }
+
+        info!("区块 {} 位于侧链，高度: {}", hash, height);
+        Ok(ChainUpdate::default())
     }
 
-    pub fn get_last_hash(&self) -> Result<String> {
-        if self.blocks.is_empty() {
-            warn!("区块链为空，无法获取最后的哈希值");
-            return Ok(String::new());
+    /// 将主链切换到以`new_tip`结尾的分支：断开分叉点之后的旧区块，再依次连接新分支
+    fn activate_best_chain(&mut self, new_tip: &str) -> Result<ChainUpdate> {
+        let branch = self.branch_from_fork(new_tip);
+        let fork_height = self.index[new_tip].height + 1 - branch.len() as u64;
+
+        let mut update = ChainUpdate::default();
+        while self.active.len() as u64 > fork_height {
+            let block = self.disconnect_tip()?;
+            update.disconnected.push(block);
+        }
+        if update.is_reorg() {
+            warn!("区块链重组：断开 {} 个区块，连接 {} 个区块", update.disconnected.len(), branch.len());
+        }
+
+        for hash in &branch {
+            let block = self.load_block(hash)?;
+            if let Err(e) = self.connect_block(&block) {
+                error!("连接区块 {} 失败: {}", hash, e);
+                self.mark_invalid(hash)?;
+                self.rollback_reorg(&update)?;
+                return Err(e);
+            }
+            update.connected.push(block);
         }
-        debug!("获取最后区块哈希: {}", self.current_hash);
-        Ok(self.current_hash.clone())
+
+        info!("主链切换完成，当前高度: {}, 链尾: {}", self.active.len(), self.tip_hash());
+        Ok(update)
+    }
+
+    /// 从新链尾回溯到主链，返回不在主链上的分支区块哈希（按高度升序）
+    fn branch_from_fork(&self, tip: &str) -> Vec<String> {
+        let mut branch = Vec::new();
+        let mut current = self.index.get(tip);
+        while let Some(entry) = current {
+            if self.is_in_active_chain(&entry.hash) {
+                break;
+            }
+            branch.push(entry.hash.clone());
+            current = self.index.get(&entry.header.prev_block_hash);
+        }
+        branch.reverse();
+        branch
+    }
+
+    fn is_in_active_chain(&self, hash: &str) -> bool {
+        self.index
+            .get(hash)
+            .and_then(|entry| self.active.get(entry.height as usize))
+            .is_some_and(|active| active == hash)
+    }
+
+    /// 主链末端区块的哈希，主链为空时为空字符串
+    fn tip_hash(&self) -> &str {
+        self.active.last().map(String::as_str).unwrap_or_default()
+    }
+
+    /// 从存储中读取区块，主链和侧链区块都可以读取
+    fn load_block(&self, hash: &str) -> Result<Block> {
+        self.storage
+            .get_block_by_hash(hash)?
+            .ok_or_else(|| RustBtcError::BlockNotFound(hash.to_string()))
     }
 
-    pub fn save_to_file(&self) -> Result<()> {
-        info!("开始保存区块链到文件");
-        let data = bincode::serialize(self)
-            .map_err(|e| RustBtcError::Serialization(e))?;
-        fs::write("blockchain.dat", data)
-            .map_err(|e| RustBtcError::Io(e))?;
-        info!("区块链成功保存到文件");
+    fn connect_block(&mut self, block: &Block) -> Result<()> {
+        debug!("连接区块 {} 到主链，高度: {}", block.hash, block.height);
+        // 上下文借用了整个链状态，验证期间先把UTXO集取出来
+        let mut utxo_set = std::mem::take(&mut self.utxo_set);
+        let result = self
+            .block_context(&block.header.prev_block_hash, block.height)
+            .and_then(|ctx| validation::connect_block(block, &ctx, &mut utxo_set));
+        self.utxo_set = utxo_set;
+        let undo = result?;
+
+        // 撤销数据、高度、链尾和UTXO集的修改一起写入，写入失败时回滚UTXO集
+        let mut batch = StorageBatch::new();
+        batch.save_block_undo(&block.hash, &undo)?;
+        batch.set_block_hash(block.height, &block.hash);
+        batch.set_tip(Some(&block.hash));
+        batch.set_utxo_tip(Some(&block.hash));
+        if self.tx_index {
+            Self::index_transactions(&mut batch, block)?;
+            batch.set_tx_index_tip(Some(&block.hash));
+        }
+        if self.address_index {
+            addrindex::index_block(&mut batch, block, &undo)?;
+            batch.set_address_index_tip(Some(&block.hash));
+        }
+        if let Err(e) = self.utxo_set.flush_into(&mut batch).and_then(|_| self.storage.write(batch)) {
+            self.utxo_set.disconnect_block(block, &undo)?;
+            return Err(e);
+        }
+        self.utxo_set.mark_flushed();
+        self.active.push(block.hash.clone());
         Ok(())
     }
 
-    pub fn load_from_file() -> Result<Self> {
-        info!("从文件加载区块链");
-        if !Path::new("blockchain.dat").exists() {
-            warn!("区块链文件不存在，创建新的区块链");
-            return Self::new();
+    /// 断开主链末端区块并用撤销数据回滚UTXO集，区块本身保留为侧链区块
+    fn disconnect_tip(&mut self) -> Result<Block> {
+        let hash = self.active.last().cloned().ok_or_else(|| {
+            RustBtcError::InvalidChain("主链为空，无法断开区块".to_string())
+        })?;
+        let block = self.load_block(&hash)?;
+        debug!("从主链断开区块 {}，高度: {}", block.hash, block.height);
+        let undo = self.storage.get_block_undo(&hash)?.ok_or_else(|| {
+            RustBtcError::InvalidChain(format!("区块 {} 缺少撤销数据", block.hash))
+        })?;
+
+        let prev = self.active.iter().rev().nth(1).map(String::as_str);
+        let mut batch = StorageBatch::new();
+        batch.delete_block_undo(&hash);
+        batch.delete_block_hash(block.height);
+        batch.set_tip(prev);
+        batch.set_utxo_tip(prev);
+        if self.tx_index {
+            self.unindex_transactions(&mut batch, &block)?;
+            batch.set_tx_index_tip(prev);
+        }
+        if self.address_index {
+            addrindex::unindex_block(&mut batch, &block, &undo)?;
+            batch.set_address_index_tip(prev);
         }
 
-        let data = fs::read("blockchain.dat")
-            .map_err(|e| RustBtcError::Io(e))?;
-        let blockchain = bincode::deserialize(&data)
-            .map_err(|e| RustBtcError::DeserializationError(e.to_string()))?;
-        info!("成功从文件加载区块链");
-        Ok(blockchain)
+        self.utxo_set.disconnect_block(&block, &undo)?;
+        if let Err(e) = self.utxo_set.flush_into(&mut batch).and_then(|_| self.storage.write(batch)) {
+            let median_time_past = self.median_time_past(&block.header.prev_block_hash).unwrap_or(0);
+            self.utxo_set.connect_block(&block, median_time_past)?;
+            return Err(e);
+        }
+        self.utxo_set.mark_flushed();
+        self.active.pop();
+        Ok(block)
+    }
+
+    fn index_transactions(batch: &mut StorageBatch, block: &Block) -> Result<()> {
+        for (position, tx) in block.transactions.iter().enumerate() {
+            batch.save_tx_location(&tx.id, &TxLocation {
+                block_hash: block.hash.clone(),
+                height: block.height,
+                position,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn unindex_transactions(&self, batch: &mut StorageBatch, block: &Block) -> Result<()> {
+        for tx in &block.transactions {
+            // 交易ID重复时，索引项可能指向更早的区块
+            if self.storage.get_tx_location(&tx.id)?.is_some_and(|location| location.block_hash == block.hash) {
+                batch.delete_tx_location(&tx.id);
+            }
+        }
+        Ok(())
+    }
+
+    /// 清空交易索引并按主链逐个区块重建，最后才记录索引对应的链尾，中途中断时下次启用会重新开始
+    fn rebuild_tx_index(&self) -> Result<()> {
+        info!("重建交易索引，主链高度: {}", self.active.len());
+        self.storage.clear_tx_index()?;
+        for hash in &self.active {
+            let block = self.load_block(hash)?;
+            let mut batch = StorageBatch::new();
+            Self::index_transactions(&mut batch, &block)?;
+            self.storage.write(batch)?;
+        }
+        let mut batch = StorageBatch::new();
+        batch.set_tx_index_tip(self.active.last().map(String::as_str));
+        self.storage.write(batch)?;
+        info!("交易索引重建完成");
+        Ok(())
+    }
+
+    /// 用主链区块及其撤销数据重建地址索引，中断时的处理与交易索引相同
+    fn rebuild_address_index(&self) -> Result<()> {
+        info!("重建地址索引，主链高度: {}", self.active.len());
+        self.storage.clear_address_index()?;
+        for hash in &self.active {
+            let block = self.load_block(hash)?;
+            let undo = self.storage.get_block_undo(hash)?.ok_or_else(|| {
+                RustBtcError::InvalidChain(format!("区块 {} 缺少撤销数据", hash))
+            })?;
+            let mut batch = StorageBatch::new();
+            addrindex::index_block(&mut batch, &block, &undo)?;
+            self.storage.write(batch)?;
+        }
+        let mut batch = StorageBatch::new();
+        batch.set_address_index_tip(self.active.last().map(String::as_str));
+        self.storage.write(batch)?;
+        info!("地址索引重建完成");
+        Ok(())
+    }
+
+    /// 连接新分支失败时恢复原来的主链
+    fn rollback_reorg(&mut self, update: &ChainUpdate) -> Result<()> {
+        for block in &update.connected {
+            self.disconnect_tip()?;
+            debug!("撤销连接区块 {}", block.hash);
+        }
+        for block in update.disconnected.iter().rev() {
+            self.connect_block(block)?;
+        }
+        Ok(())
+    }
+
+    /// 将区块及其所有已知后代标记为无效
+    fn mark_invalid(&mut self, hash: &str) -> Result<()> {
+        let mut batch = StorageBatch::new();
+        let mut pending = vec![hash.to_string()];
+        while let Some(current) = pending.pop() {
+            if let Some(entry) = self.index.get_mut(&current) {
+                entry.invalid = true;
+                batch.save_block_index(entry)?;
+            }
+            pending.extend(
+                self.index
+                    .values()
+                    .filter(|entry| entry.header.prev_block_hash == current && !entry.invalid)
+                    .map(|entry| entry.hash.clone()),
+            );
+        }
+        self.storage.write(batch)
+    }
+
+    /// 主链上的累计工作量
+    pub fn chain_work(&self) -> Work {
+        self.index
+            .get(self.tip_hash())
+            .map(|entry| entry.chain_work)
+            .unwrap_or_default()
+    }
+
+    pub fn get_block_index(&self, hash: &str) -> Option<&BlockIndex> {
+        self.index.get(hash)
+    }
+
+    /// 验证`prev_hash`之后高度为`height`的区块所需的链状态
+    fn block_context(&self, prev_hash: &str, height: u64) -> Result<BlockContext<'_>> {
+        Ok(BlockContext {
+            params: &self.params,
+            height,
+            expected_bits: self.next_bits_after(prev_hash)?,
+            median_time_past: self.median_time_past(prev_hash),
+            adjusted_time: self.adjusted_time()?,
+        })
+    }
+
+    /// `hash`及其之前共11个区块时间戳的中位数，区块未知时返回None
+    pub fn median_time_past(&self, hash: &str) -> Option<u32> {
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut current = self.index.get(hash);
+        while let Some(entry) = current {
+            if timestamps.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            timestamps.push(entry.header.timestamp);
+            current = self.index.get(&entry.header.prev_block_hash);
+        }
+        if timestamps.is_empty() {
+            return None;
+        }
+        timestamps.sort_unstable();
+        Some(timestamps[timestamps.len() / 2])
+    }
+
+    /// 沿`hash`所在分支向前回溯，返回指定高度的祖先
+    fn get_ancestor(&self, hash: &str, height: u64) -> Option<&BlockIndex> {
+        let mut entry = self.index.get(hash)?;
+        while entry.height > height {
+            // 已回到主链时直接按高度定位
+            if self.is_in_active_chain(&entry.hash) {
+                return self.index.get(self.active.get(height as usize)?);
+            }
+            entry = self.index.get(&entry.header.prev_block_hash)?;
+        }
+        (entry.height == height).then_some(entry)
+    }
+
+    /// 在当前链尾之后构造一个待挖矿的区块，难度和高度由链状态决定
+    pub fn new_block(&self, transactions: Vec<Transaction>) -> Result<Block> {
+        let mut block = Block::new(transactions, self.tip_hash().to_string())?;
+        block.header.bits = self.get_next_bits()?;
+        // 时间戳必须大于过去区块的中位时间
+        if let Some(median_time_past) = self.median_time_past(self.tip_hash()) {
+            block.header.timestamp = block.header.timestamp.max(median_time_past + 1);
+        }
+        block.height = self.active.len() as u64;
+        Ok(block)
+    }
+
+    /// 计算主链下一个区块必须使用的难度
+    pub fn get_next_bits(&self) -> Result<u32> {
+        self.next_bits_after(self.tip_hash())
+    }
+
+    /// 计算`parent_hash`之后的区块必须使用的难度
+    fn next_bits_after(&self, parent_hash: &str) -> Result<u32> {
+        let parent = match self.index.get(parent_hash) {
+            Some(parent) => parent,
+            None => return Ok(self.params.pow_limit_bits),
+        };
+
+        let bits = match self.params.retarget_mode {
+            RetargetMode::Window => self.window_retarget(parent)?,
+            RetargetMode::Lwma => self.lwma_retarget(parent)?,
+        };
+        debug!("区块 {} 之后的难度: {:#010x}", parent_hash, bits);
+        Ok(bits)
+    }
+
+    fn window_retarget(&self, parent: &BlockIndex) -> Result<u32> {
+        let next_height = parent.height + 1;
+        let interval = self.params.difficulty_adjustment_interval;
+        if !next_height.is_multiple_of(interval) {
+            return Ok(parent.header.bits);
+        }
+
+        let first = self.get_ancestor(&parent.hash, next_height - interval).ok_or_else(|| {
+            RustBtcError::InvalidChain(format!("找不到高度 {} 的祖先区块", next_height - interval))
+        })?;
+        let timespan = self.params.target_timespan();
+        let actual_timespan = (parent.header.timestamp as u64)
+            .saturating_sub(first.header.timestamp as u64)
+            .clamp(timespan / 4, timespan * 4);
+        info!("难度调整: 期望耗时 {} 秒, 实际耗时 {} 秒", timespan, actual_timespan);
+
+        let target = parent.header.target()?.0;
+        self.limit_target(target.mul_div_u64(actual_timespan, timespan))
+    }
+
+    fn lwma_retarget(&self, parent: &BlockIndex) -> Result<u32> {
+        // 最近n个出块间隔需要n+1个区块
+        let n = self.params.lwma_window.min(parent.height);
+        if n == 0 {
+            return Ok(self.params.pow_limit_bits);
+        }
+
+        let mut window = Vec::with_capacity(n as usize + 1);
+        let mut entry = parent;
+        window.push(&entry.header);
+        for _ in 0..n {
+            entry = self.index.get(&entry.header.prev_block_hash).ok_or_else(|| {
+                RustBtcError::InvalidChain(format!("找不到区块 {} 的前置区块", entry.hash))
+            })?;
+            window.push(&entry.header);
+        }
+        window.reverse();
+
+        let spacing = self.params.target_block_spacing;
+
+        // 越新的出块间隔权重越大，单个间隔限制在 [1, 6T] 内以抵御时间戳操纵
+        let mut weighted_solvetime = 0u64;
+        let mut average_target = U256::ZERO;
+        for (i, pair) in window.windows(2).enumerate() {
+            let solvetime = (pair[1].timestamp as u64)
+                .saturating_sub(pair[0].timestamp as u64)
+                .clamp(1, 6 * spacing);
+            weighted_solvetime += solvetime * (i as u64 + 1);
+            let target = pair[1].target()?.0;
+            average_target = average_target.saturating_add(target.div_u64(n));
+        }
+
+        let k = n * (n + 1) / 2;
+        self.limit_target(average_target.mul_div_u64(weighted_solvetime, k * spacing))
+    }
+
+    /// 将计算出的目标值限制在最低难度以内，乘法溢出同样视为最低难度
+    fn limit_target(&self, target: Option<U256>) -> Result<u32> {
+        let pow_limit = Target::from_compact(self.params.pow_limit_bits)?;
+        match target {
+            Some(value) if value.is_zero() => Ok(Target(U256::from_u64(1)).to_compact()),
+            Some(value) if Target(value) < pow_limit => Ok(Target(value).to_compact()),
+            _ => Ok(pow_limit.to_compact()),
+        }
+    }
+
+    pub fn get_block(&self, hash: &str) -> Result<Block> {
+        debug!("查找哈希为 {} 的区块", hash);
+        if !self.index.contains_key(hash) {
+            error!("未找到哈希为 {} 的区块", hash);
+            return Err(RustBtcError::BlockNotFound(hash.to_string()));
+        }
+        self.load_block(hash)
+    }
+
+    /// 主链上指定高度的区块
+    pub fn get_block_by_height(&self, height: u64) -> Result<Option<Block>> {
+        self.active
+            .get(height as usize)
+            .map(|hash| self.load_block(hash))
+            .transpose()
+    }
+
+    /// 主链末端的区块
+    pub fn tip(&self) -> Result<Option<Block>> {
+        self.active.last().map(|hash| self.load_block(hash)).transpose()
+    }
+
+    pub fn get_last_hash(&self) -> Result<String> {
+        if self.active.is_empty() {
+            warn!("区块链为空，无法获取最后的哈希值");
+            return Ok(String::new());
+        }
+        debug!("获取最后区块哈希: {}", self.tip_hash());
+        Ok(self.tip_hash().to_string())
     }
 
     pub fn validate_chain(&self) -> Result<bool> {
         info!("开始验证区块链");
-        if self.blocks.is_empty() {
+        if self.active.is_empty() {
             warn!("区块链为空，验证通过");
             return Ok(true);
         }
 
+        // 从空UTXO集重放整条链，以执行每个输入的脚本
+        let mut utxo_set = UTXOSet::new();
         let mut prev_hash = String::new();
-        for (i, block) in self.blocks.iter().enumerate() {
+        for (i, block) in self.blocks().enumerate() {
+            let block = block?;
             debug!("验证第 {} 个区块", i + 1);
             
             // 验证区块哈希
@@ -127,8 +788,16 @@ impl Blockchain {
                 return Ok(false);
             }
 
+            // 执行所有输入的脚本
+            if !block.verify_scripts(&utxo_set)? {
+                error!("区块 {} 脚本验证失败", i + 1);
+                return Ok(false);
+            }
+            let median_time_past = self.median_time_past(&block.header.prev_block_hash).unwrap_or(0);
+            utxo_set.connect_block(&block, median_time_past)?;
+
             // 验证前置哈希
-            if i > 0 && block.prev_block_hash != prev_hash {
+            if i > 0 && block.header.prev_block_hash != prev_hash {
                 error!("区块 {} 的前置哈希不匹配", i + 1);
                 return Ok(false);
             }
@@ -141,55 +810,307 @@ impl Blockchain {
     }
 
     pub fn get_block_height(&self) -> usize {
-        debug!("获取区块链高度: {}", self.blocks.len());
-        self.blocks.len()
+        debug!("获取区块链高度: {}", self.active.len());
+        self.active.len()
     }
 
-    pub fn get_blocks_after(&self, hash: &str) -> Result<Vec<&Block>> {
+    pub fn get_blocks_after(&self, hash: &str) -> Result<Vec<Block>> {
         debug!("获取哈希 {} 之后的所有区块", hash);
-        let mut blocks = Vec::new();
-        let mut found = false;
-
-        for block in &self.blocks {
-            if found {
-                blocks.push(block);
-            }
-            if block.hash == hash {
-                found = true;
+        let start = match self.index.get(hash) {
+            Some(entry) if self.is_in_active_chain(hash) => entry.height as usize + 1,
+            _ if hash.is_empty() => self.active.len(),
+            _ => {
+                warn!("未找到哈希为 {} 的区块", hash);
+                return Err(RustBtcError::BlockNotFound(hash.to_string()));
             }
-        }
-
-        if !found && !hash.is_empty() {
-            warn!("未找到哈希为 {} 的区块", hash);
-            return Err(RustBtcError::BlockNotFound(hash.to_string()));
-        }
+        };
 
+        let blocks = self.active[start..]
+            .iter()
+            .map(|hash| self.load_block(hash))
+            .collect::<Result<Vec<_>>>()?;
         debug!("找到 {} 个后续区块", blocks.len());
         Ok(blocks)
     }
 
     pub fn find_transaction(&self, id: &str) -> Option<Transaction> {
         debug!("查找交易ID: {}", id);
-        for block in self.blocks.iter().rev() {
-            if let Some(tx) = block.transactions.iter().find(|tx| tx.id == id) {
+        match self.get_transaction(id) {
+            Ok(Some(confirmed)) => {
                 debug!("找到交易 {}", id);
-                return Some(tx.clone());
+                Some(confirmed.transaction)
+            }
+            Ok(None) => {
+                warn!("未找到交易 {}", id);
+                None
+            }
+            Err(e) => {
+                error!("查找交易 {} 失败: {}", id, e);
+                None
+            }
+        }
+    }
+
+    /// 查找主链上的交易及其确认信息。启用交易索引时直接定位所在区块，否则从链尾向前逐个区块查找
+    pub fn get_transaction(&self, txid: &str) -> Result<Option<ConfirmedTransaction>> {
+        let found = if self.tx_index {
+            match self.storage.get_tx_location(txid)? {
+                Some(location) => {
+                    let block = self.load_block(&location.block_hash)?;
+                    let block_time = block.header.timestamp;
+                    let tx = block
+                        .transactions
+                        .into_iter()
+                        .nth(location.position)
+                        .filter(|tx| tx.id == txid)
+                        .ok_or_else(|| {
+                            RustBtcError::InvalidChain(format!(
+                                "交易索引中 {} 的位置 {}:{} 无效",
+                                txid, location.block_hash, location.position
+                            ))
+                        })?;
+                    Some((tx, location, block_time))
+                }
+                None => None,
+            }
+        } else {
+            self.scan_transaction(txid)?
+        };
+
+        Ok(found.map(|(transaction, location, block_time)| ConfirmedTransaction {
+            confirmations: self.active.len() as u64 - location.height,
+            transaction,
+            location,
+            block_time,
+        }))
+    }
+
+    /// 分页查询向P2PKH地址`address`支付或花费其输出的主链交易，最新的在前。需要启用地址索引
+    pub fn get_address_history(&self, address: &str, offset: usize, limit: usize) -> Result<Vec<AddressTx>> {
+        if !self.address_index {
+            return Err(RustBtcError::Other("地址索引未启用".to_string()));
+        }
+        let pubkey_hash = address_to_pubkey_hash(address)?;
+        self.storage
+            .iter_address_txs(&pubkey_hash)
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .collect()
+    }
+
+    fn scan_transaction(&self, txid: &str) -> Result<Option<(Transaction, TxLocation, u32)>> {
+        for (height, hash) in self.active.iter().enumerate().rev() {
+            let block = self.load_block(hash)?;
+            let block_time = block.header.timestamp;
+            if let Some((position, tx)) = block.transactions.into_iter().enumerate().find(|(_, tx)| tx.id == txid) {
+                let location = TxLocation {
+                    block_hash: hash.clone(),
+                    height: height as u64,
+                    position,
+                };
+                return Ok(Some((tx, location, block_time)));
             }
         }
-        warn!("未找到交易 {}", id);
-        None
+        Ok(None)
     }
 
-    pub fn blocks(&self) -> &Vec<Block> {
-        &self.blocks
+    /// 按高度依次从存储中读取主链区块
+    pub fn blocks(&self) -> impl DoubleEndedIterator<Item = Result<Block>> + '_ {
+        self.active.iter().map(|hash| self.load_block(hash))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::amount::Amount;
+    use crate::transaction::{TxInput, TxOutput};
+    use crate::validation::BlockRejection;
     use crate::wallet::Wallet;
 
+    const EASY_BITS: u32 = 0x207fffff;
+
+    fn test_params(retarget_mode: RetargetMode) -> ChainParams {
+        ChainParams {
+            pow_limit_bits: EASY_BITS,
+            target_block_spacing: 10,
+            retarget_mode,
+            difficulty_adjustment_interval: 4,
+            lwma_window: 3,
+            ..ChainParams::mainnet()
+        }
+    }
+
+    fn easy_params() -> ChainParams {
+        ChainParams {
+            pow_limit_bits: EASY_BITS,
+            ..ChainParams::mainnet()
+        }
+    }
+
+    fn coinbase(wallet: &Wallet, tag: &str) -> Result<Transaction> {
+        Transaction::new_coinbase(&wallet.get_address(), tag, 0, Amount::ZERO, &ChainParams::default())
+    }
+
+    fn btc(n: u64) -> Amount {
+        Amount::from_btc(n).unwrap()
+    }
+
+    fn mine_child(parent: &Block, transactions: Vec<Transaction>) -> Result<Block> {
+        let mut block = Block::new(transactions, parent.hash.clone())?;
+        block.header.bits = EASY_BITS;
+        block.header.timestamp = parent.header.timestamp + 1;
+        block.mine_block()?;
+        Ok(block)
+    }
+
+    // 花费`txid`的第0个输出并签名
+    fn signed_spend(wallet: &Wallet, txid: &str, input_value: Amount, output_value: Amount) -> Result<Transaction> {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TxInput::new(txid.to_string(), 0, input_value)],
+            vout: vec![TxOutput::new(output_value, &wallet.get_address())?],
+            lock_time: 0,
+        };
+        tx.id = tx.txid()?;
+        tx.sign(wallet)?;
+        Ok(tx)
+    }
+
+    fn mine_genesis(wallet: &Wallet) -> Result<Block> {
+        let mut genesis = Block::new(vec![coinbase(wallet, "genesis")?], String::from("0"))?;
+        genesis.header.bits = EASY_BITS;
+        genesis.mine_block()?;
+        Ok(genesis)
+    }
+
+    // 按给定的出块间隔构造并挖出区块
+    fn extend_chain(blockchain: &mut Blockchain, spacings: &[u64]) -> Result<()> {
+        let wallet = Wallet::new()?;
+        for (i, spacing) in spacings.iter().enumerate() {
+            let coinbase = Transaction::new_coinbase(&wallet.get_address(), &format!("Retarget {}", i), 0, Amount::ZERO, &ChainParams::default())?;
+            let mut block = blockchain.new_block(vec![coinbase])?;
+            block.header.timestamp = blockchain
+                .tip()?
+                .map(|tip| tip.header.timestamp + *spacing as u32)
+                .unwrap_or(1_600_000_000);
+            block.mine_block()?;
+            blockchain.add_block(block)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_coinbase_value_limit() -> Result<()> {
+        let params = ChainParams {
+            subsidy_halving_interval: 2,
+            coinbase_maturity: 2,
+            ..easy_params()
+        };
+        let mut blockchain = Blockchain::with_params(params.clone())?;
+        let wallet = Wallet::new()?;
+        let address = wallet.get_address();
+
+        let genesis = mine_genesis(&wallet)?;
+        blockchain.add_block(genesis.clone())?;
+        let block1 = mine_child(&genesis, vec![
+            Transaction::new_coinbase(&address, "1", 1, Amount::ZERO, &params)?,
+        ])?;
+        blockchain.add_block(block1.clone())?;
+
+        // 高度2的奖励已减半，不能再领取全额奖励
+        let greedy = mine_child(&block1, vec![
+            Transaction::new_coinbase(&address, "greedy", 1, Amount::ZERO, &params)?,
+        ])?;
+        assert!(blockchain.add_block(greedy).is_err());
+        assert_eq!(blockchain.get_block_height(), 2);
+
+        // 花费创世区块的coinbase并留下10的手续费
+        let spend = signed_spend(&wallet, &genesis.transactions[0].id, btc(50), btc(40))?;
+        assert_eq!(spend.fee(blockchain.utxo_set())?, btc(10));
+
+        let mut too_much = Transaction::new_coinbase(&address, "too much", 2, spend.fee(blockchain.utxo_set())?, &params)?;
+        too_much.vout[0].value = too_much.vout[0].value.checked_add(Amount::ONE_SAT).unwrap();
+        let block = mine_child(&block1, vec![too_much, spend.clone()])?;
+        assert!(blockchain.add_block(block).is_err());
+        assert!(blockchain.utxo_set().find_utxo(&genesis.transactions[0].id, 0)?.is_some());
+
+        let coinbase = Transaction::new_coinbase(&address, "fees", 2, spend.fee(blockchain.utxo_set())?, &params)?;
+        assert_eq!(coinbase.vout[0].value, btc(25 + 10));
+        let block = mine_child(&block1, vec![coinbase, spend])?;
+        blockchain.add_block(block)?;
+        assert_eq!(blockchain.get_block_height(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_coinbase_maturity() -> Result<()> {
+        let params = ChainParams {
+            coinbase_maturity: 2,
+            ..easy_params()
+        };
+        let mut blockchain = Blockchain::with_params(params)?;
+        let wallet = Wallet::new()?;
+
+        let genesis = mine_genesis(&wallet)?;
+        blockchain.add_block(genesis.clone())?;
+        let spend = signed_spend(&wallet, &genesis.transactions[0].id, btc(50), btc(50))?;
+
+        // 高度1时创世区块的coinbase只有1个确认
+        let early = mine_child(&genesis, vec![coinbase(&wallet, "early")?, spend.clone()])?;
+        assert!(matches!(
+            blockchain.add_block(early),
+            Err(RustBtcError::BlockRejected(BlockRejection::ImmatureCoinbase { .. }))
+        ));
+        assert!(blockchain.utxo_set().find_utxo(&genesis.transactions[0].id, 0)?.is_some());
+
+        let block1 = mine_child(&genesis, vec![coinbase(&wallet, "1")?])?;
+        blockchain.add_block(block1.clone())?;
+        let block2 = mine_child(&block1, vec![coinbase(&wallet, "2")?, spend])?;
+        blockchain.add_block(block2)?;
+        assert_eq!(blockchain.get_block_height(), 3);
+        assert!(blockchain.utxo_set().find_utxo(&genesis.transactions[0].id, 0)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_time_rules() -> Result<()> {
+        let mut blockchain = Blockchain::with_params(easy_params())?;
+        let wallet = Wallet::new()?;
+        let genesis = mine_genesis(&wallet)?;
+        blockchain.add_block(genesis.clone())?;
+        extend_chain(&mut blockchain, &[1; 11])?;
+
+        // 时间戳不大于最近11个区块的中位时间
+        let tip = blockchain.tip()?.unwrap();
+        let median = blockchain.median_time_past(&tip.hash).unwrap();
+        let mut old = blockchain.new_block(vec![coinbase(&wallet, "old")?])?;
+        old.header.timestamp = median;
+        old.mine_block()?;
+        assert!(matches!(
+            blockchain.add_block(old),
+            Err(RustBtcError::BlockRejected(BlockRejection::TimeTooOld { .. }))
+        ));
+
+        // 超前网络时间两小时以上的区块被拒绝，网络时间偏移计入后可以接受
+        let drift = blockchain.params().max_future_block_time;
+        let mut future = blockchain.new_block(vec![coinbase(&wallet, "future")?])?;
+        future.header.timestamp = (blockchain.adjusted_time()? + drift + 60) as u32;
+        future.mine_block()?;
+        assert!(matches!(
+            blockchain.add_block(future.clone()),
+            Err(RustBtcError::BlockRejected(BlockRejection::TimeTooNew { .. }))
+        ));
+        blockchain.set_time_offset(120);
+        blockchain.add_block(future)?;
+        assert_eq!(blockchain.get_block_height(), 13);
+
+        Ok(())
+    }
+
     #[test]
     fn test_blockchain_basic_operations() -> Result<()> {
         let mut blockchain = Blockchain::new()?;
@@ -197,9 +1118,9 @@ mod tests {
 
         // 创建创世区块
         let wallet = Wallet::new()?;
-        let coinbase_tx = Transaction::new_coinbase(&wallet.get_address(), "Genesis Block")?;
+        let coinbase_tx = Transaction::new_coinbase(&wallet.get_address(), "Genesis Block", 0, Amount::ZERO, &ChainParams::default())?;
         let mut genesis_block = Block::new(vec![coinbase_tx], String::new())?;
-        genesis_block.mine_block(4)?;
+        genesis_block.mine_block()?;
 
         // 添加创世区块
         blockchain.add_block(genesis_block.clone())?;
@@ -213,6 +1134,170 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_reorganize_to_heaviest_chain() -> Result<()> {
+        let mut blockchain = Blockchain::with_params(easy_params())?;
+        let miner_a = Wallet::new()?;
+        let miner_b = Wallet::new()?;
+
+        let genesis = mine_genesis(&miner_a)?;
+        blockchain.add_block(genesis.clone())?;
+
+        let a1 = mine_child(&genesis, vec![coinbase(&miner_a, "a1")?])?;
+        let a2 = mine_child(&a1, vec![coinbase(&miner_a, "a2")?])?;
+        blockchain.add_block(a1.clone())?;
+        blockchain.add_block(a2.clone())?;
+
+        // 较轻或等重的分支只作为侧链保存
+        let b1 = mine_child(&genesis, vec![coinbase(&miner_b, "b1")?])?;
+        let b2 = mine_child(&b1, vec![coinbase(&miner_b, "b2")?])?;
+        assert!(blockchain.add_block(b1.clone())?.connected.is_empty());
+        assert!(blockchain.add_block(b2.clone())?.connected.is_empty());
+        assert_eq!(blockchain.get_last_hash()?, a2.hash);
+        assert!(blockchain.get_block(&b2.hash).is_ok());
+
+        // 重复的区块被拒绝
+        assert!(blockchain.add_block(b2.clone()).is_err());
+
+        // 侧链累计工作量超过主链后发生重组
+        let b3 = mine_child(&b2, vec![coinbase(&miner_b, "b3")?])?;
+        let update = blockchain.add_block(b3.clone())?;
+        assert!(update.is_reorg());
+        let disconnected: Vec<_> = update.disconnected.iter().map(|b| b.hash.clone()).collect();
+        let connected: Vec<_> = update.connected.iter().map(|b| b.hash.clone()).collect();
+        assert_eq!(disconnected, vec![a2.hash.clone(), a1.hash.clone()]);
+        assert_eq!(connected, vec![b1.hash.clone(), b2.hash.clone(), b3.hash.clone()]);
+
+        assert_eq!(blockchain.get_last_hash()?, b3.hash);
+        assert_eq!(blockchain.get_block_height(), 4);
+        assert!(blockchain.get_block_index(&b3.hash).unwrap().chain_work
+            > blockchain.get_block_index(&a2.hash).unwrap().chain_work);
+
+        // UTXO集跟随新主链
+        assert_eq!(blockchain.utxo_set().get_balance(&miner_a.get_address())?, btc(50));
+        assert_eq!(blockchain.utxo_set().get_balance(&miner_b.get_address())?, btc(150));
+
+        // 原分支再次超过时切换回去
+        let a3 = mine_child(&a2, vec![coinbase(&miner_a, "a3")?])?;
+        let a4 = mine_child(&a3, vec![coinbase(&miner_a, "a4")?])?;
+        assert!(!blockchain.add_block(a3)?.is_reorg());
+        let update = blockchain.add_block(a4.clone())?;
+        assert_eq!(update.disconnected.len(), 3);
+        assert_eq!(update.connected.len(), 4);
+        assert_eq!(blockchain.get_last_hash()?, a4.hash);
+        assert_eq!(blockchain.utxo_set().get_balance(&miner_a.get_address())?, btc(250));
+        assert_eq!(blockchain.utxo_set().get_balance(&miner_b.get_address())?, btc(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_orphan_blocks_connect_when_parent_arrives() -> Result<()> {
+        let mut blockchain = Blockchain::with_params(easy_params())?;
+        let miner = Wallet::new()?;
+        let peer: SocketAddr = "127.0.0.1:8001".parse().unwrap();
+
+        let genesis = mine_genesis(&miner)?;
+        let b1 = mine_child(&genesis, vec![coinbase(&miner, "b1")?])?;
+        let b2 = mine_child(&b1, vec![coinbase(&miner, "b2")?])?;
+        let b3 = mine_child(&b2, vec![coinbase(&miner, "b3")?])?;
+        blockchain.add_block(genesis)?;
+
+        // 逆序到达的区块暂存为孤块，并指出真正缺失的祖先
+        let update = blockchain.process_block(b3.clone(), Some(peer))?;
+        assert_eq!(update.missing_parent, Some(b2.hash.clone()));
+        let update = blockchain.process_block(b2.clone(), Some(peer))?;
+        assert_eq!(update.missing_parent, Some(b1.hash.clone()));
+        assert!(update.connected.is_empty());
+        assert_eq!(blockchain.orphan_count(), 2);
+        assert_eq!(blockchain.get_block_height(), 1);
+
+        // 重复的孤块被拒绝
+        assert!(blockchain.process_block(b3.clone(), Some(peer)).is_err());
+
+        // 缺失的区块到达后，后代孤块被自动连接
+        let update = blockchain.process_block(b1.clone(), Some(peer))?;
+        let connected: Vec<_> = update.connected.iter().map(|b| b.hash.clone()).collect();
+        assert_eq!(connected, vec![b1.hash, b2.hash, b3.hash.clone()]);
+        assert!(update.missing_parent.is_none());
+        assert_eq!(blockchain.orphan_count(), 0);
+        assert_eq!(blockchain.get_last_hash()?, b3.hash);
+        assert_eq!(blockchain.utxo_set().get_balance(&miner.get_address())?, btc(200));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_window_retarget() -> Result<()> {
+        let mut blockchain = Blockchain::with_params(test_params(RetargetMode::Window))?;
+
+        // 窗口内难度保持不变
+        extend_chain(&mut blockchain, &[0, 1, 1])?;
+        assert_eq!(blockchain.blocks().count(), 3);
+        assert_eq!(blockchain.get_next_bits()?, EASY_BITS);
+
+        // 出块过快：实际耗时3秒，被限制为期望耗时40秒的1/4，目标值缩小为1/4
+        extend_chain(&mut blockchain, &[1])?;
+        assert_eq!(blockchain.get_next_bits()?, 0x201fffff);
+
+        // 难度不符的区块被拒绝
+        let wallet = Wallet::new()?;
+        let coinbase = Transaction::new_coinbase(&wallet.get_address(), "Wrong bits", 0, Amount::ZERO, &ChainParams::default())?;
+        let mut block = blockchain.new_block(vec![coinbase])?;
+        block.header.bits = EASY_BITS;
+        block.mine_block()?;
+        assert!(matches!(
+            blockchain.add_block(block),
+            Err(RustBtcError::BlockRejected(BlockRejection::BadDifficulty { .. }))
+        ));
+
+        // 出块过慢：难度降低，单次最多放宽4倍，且不会低于最低难度
+        extend_chain(&mut blockchain, &[100, 100, 100, 100])?;
+        let eased = Target::from_compact(blockchain.get_next_bits()?)?;
+        assert!(eased > Target::from_compact(0x201fffff)?);
+        assert!(eased < Target::from_compact(EASY_BITS)?);
+
+        extend_chain(&mut blockchain, &[100, 100, 100, 100])?;
+        assert_eq!(blockchain.get_next_bits()?, EASY_BITS);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_zero_retarget_params() {
+        for mode in [RetargetMode::Window, RetargetMode::Lwma] {
+            let zero_spacing = ChainParams {
+                target_block_spacing: 0,
+                ..test_params(mode)
+            };
+            assert!(Blockchain::with_params(zero_spacing).is_err());
+            let zero_interval = ChainParams {
+                difficulty_adjustment_interval: 0,
+                ..test_params(mode)
+            };
+            assert!(Blockchain::with_params(zero_interval).is_err());
+        }
+    }
+
+    #[test]
+    fn test_lwma_retarget() -> Result<()> {
+        let mut blockchain = Blockchain::with_params(test_params(RetargetMode::Lwma))?;
+
+        // 出块间隔等于期望值时难度不变
+        extend_chain(&mut blockchain, &[0, 10, 10, 10])?;
+        let target = Target::from_compact(EASY_BITS)?;
+        let next = Target::from_compact(blockchain.get_next_bits()?)?;
+        assert!(next <= target);
+        assert!(next.0 > target.0.div_u64(100).checked_mul_u64(99).unwrap());
+
+        // 出块间隔减半，难度逐块上升
+        extend_chain(&mut blockchain, &[5, 5, 5])?;
+        let faster = Target::from_compact(blockchain.get_next_bits()?)?;
+        assert!(faster < next);
+
+        Ok(())
+    }
+
     #[test]
     fn test_blockchain_invalid_block() -> Result<()> {
         let mut blockchain = Blockchain::new()?;
@@ -231,25 +1316,195 @@ mod tests {
 
     #[test]
     fn test_blockchain_persistence() -> Result<()> {
-        let mut blockchain = Blockchain::new()?;
+        let temp_dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(temp_dir.path().to_str().unwrap())?;
+        let open = || -> Result<Blockchain> {
+            Blockchain::with_storage(Arc::new(storage.reopen()?), easy_params())
+        };
+        let wallet = Wallet::new()?;
+
+        let genesis = mine_genesis(&wallet)?;
+        let a1 = mine_child(&genesis, vec![coinbase(&wallet, "a1")?])?;
+        let a2 = mine_child(&a1, vec![coinbase(&wallet, "a2")?])?;
+        let b1 = mine_child(&genesis, vec![coinbase(&wallet, "b1")?])?;
+        {
+            let mut blockchain = open()?;
+            for block in [genesis.clone(), a1.clone(), b1.clone(), a2.clone()] {
+                blockchain.add_block(block)?;
+            }
+            assert_eq!(blockchain.get_last_hash()?, a2.hash);
+        }
+
+        // 重新打开后恢复主链、侧链区块和UTXO集，UTXO集直接从存储读取
+        let mut blockchain = open()?;
+        assert_eq!(blockchain.storage().get_utxo_tip()?, Some(a2.hash.clone()));
+        assert_eq!(blockchain.utxo_set().pending_changes(), 0);
+        assert_eq!(blockchain.get_last_hash()?, a2.hash);
+        assert_eq!(blockchain.get_block_height(), 3);
+        assert_eq!(blockchain.get_block_by_height(1)?.map(|b| b.hash), Some(a1.hash.clone()));
+        assert_eq!(blockchain.get_block(&b1.hash)?.height, 1);
+        assert_eq!(blockchain.chain_work(), blockchain.get_block_index(&a2.hash).unwrap().chain_work);
+        assert_eq!(blockchain.utxo_set().get_balance(&wallet.get_address())?, btc(150));
+
+        // 侧链在重启后仍可触发重组
+        let b2 = mine_child(&b1, vec![coinbase(&wallet, "b2")?])?;
+        let b3 = mine_child(&b2, vec![coinbase(&wallet, "b3")?])?;
+        blockchain.add_block(b2)?;
+        assert!(blockchain.add_block(b3.clone())?.is_reorg());
+        drop(blockchain);
 
-        // 创建并添加区块
+        let blockchain = open()?;
+        assert_eq!(blockchain.get_last_hash()?, b3.hash);
+        assert_eq!(blockchain.utxo_set().pending_changes(), 0);
+        assert_eq!(blockchain.utxo_set().get_balance(&wallet.get_address())?, btc(200));
+        assert_eq!(blockchain.get_blocks_after(&genesis.hash)?.len(), 3);
+        assert!(blockchain.validate_chain()?);
+        drop(blockchain);
+
+        // UTXO链尾缺失时重新打开会在新表中重建UTXO集，再与链尾一起替换原来的表
+        let mut batch = StorageBatch::new();
+        batch.set_utxo_tip(None);
+        storage.write(batch)?;
+        let blockchain = open()?;
+        assert_eq!(blockchain.storage().get_utxo_tip()?, Some(b3.hash.clone()));
+        assert_eq!(blockchain.utxo_set().pending_changes(), 0);
+        assert_eq!(blockchain.storage().iter_utxos().count(), 4);
+        assert_eq!(blockchain.utxo_set().get_balance(&wallet.get_address())?, btc(200));
+        drop(blockchain);
+
+        // 替换后的表在再次打开时继续使用，不需要重建
+        let blockchain = open()?;
+        assert_eq!(blockchain.storage().iter_utxos().count(), 4);
+        assert_eq!(blockchain.utxo_set().get_balance(&wallet.get_address())?, btc(200));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tx_index() -> Result<()> {
+        let storage = Arc::new(Storage::temporary()?);
         let wallet = Wallet::new()?;
-        let coinbase_tx = Transaction::new_coinbase(&wallet.get_address(), "Test Block")?;
-        let mut block = Block::new(vec![coinbase_tx], String::new())?;
-        block.mine_block(4)?;
-        blockchain.add_block(block)?;
+        let genesis = mine_genesis(&wallet)?;
+        let a1 = mine_child(&genesis, vec![coinbase(&wallet, "a1")?])?;
+        let b1 = mine_child(&genesis, vec![coinbase(&wallet, "b1")?])?;
+        let b2 = mine_child(&b1, vec![coinbase(&wallet, "b2")?])?;
+        let b3 = mine_child(&b2, vec![coinbase(&wallet, "b3")?])?;
+        let txid = |block: &Block| block.transactions[0].id.clone();
 
-        // 保存区块链
-        blockchain.save_to_file()?;
+        // 启用时为已有的区块补建索引
+        let mut blockchain = Blockchain::with_storage(storage.clone(), easy_params())?;
+        blockchain.add_block(genesis.clone())?;
+        blockchain.set_tx_index(true)?;
+        assert_eq!(storage.get_tx_index_tip()?, Some(genesis.hash.clone()));
+        blockchain.add_block(a1.clone())?;
 
-        // 加载区块链
-        let loaded_blockchain = Blockchain::load_from_file()?;
+        let confirmed = blockchain.get_transaction(&txid(&a1))?.unwrap();
+        assert_eq!(confirmed.location, TxLocation { block_hash: a1.hash.clone(), height: 1, position: 0 });
+        assert_eq!(confirmed.block_time, a1.header.timestamp);
+        assert_eq!(confirmed.confirmations, 1);
+        assert_eq!(blockchain.get_transaction(&txid(&genesis))?.map(|c| c.confirmations), Some(2));
+
+        // 重组后被断开的交易不再出现在索引中
+        blockchain.add_block(b1.clone())?;
+        assert!(blockchain.add_block(b2.clone())?.is_reorg());
+        assert!(storage.get_tx_location(&txid(&a1))?.is_none());
+        assert!(blockchain.get_transaction(&txid(&a1))?.is_none());
         assert_eq!(
-            blockchain.get_last_hash()?,
-            loaded_blockchain.get_last_hash()?
+            blockchain.get_transaction(&txid(&b1))?.map(|c| (c.location.height, c.confirmations)),
+            Some((1, 2))
         );
 
+        // 不使用索引时逐块查找得到相同的结果
+        let indexed: Vec<_> = [&genesis, &b1, &b2]
+            .iter()
+            .map(|block| blockchain.get_transaction(&txid(block)).map(|c| c.map(|c| c.location)))
+            .collect::<Result<_>>()?;
+        blockchain.set_tx_index(false)?;
+        for (block, location) in [&genesis, &b1, &b2].iter().zip(&indexed) {
+            assert_eq!(&blockchain.get_transaction(&txid(block))?.map(|c| c.location), location);
+        }
+        assert_eq!(blockchain.find_transaction(&txid(&b2)).map(|tx| tx.id), Some(txid(&b2)));
+
+        // 停用期间连接的区块在重新启用时补上
+        blockchain.add_block(b3.clone())?;
+        assert_eq!(storage.get_tx_index_tip()?, Some(b2.hash.clone()));
+        blockchain.set_tx_index(true)?;
+        assert_eq!(storage.get_tx_index_tip()?, Some(b3.hash.clone()));
+        assert_eq!(blockchain.get_transaction(&txid(&b3))?.map(|c| c.location.height), Some(3));
+        assert!(blockchain.get_transaction(&txid(&a1))?.is_none());
+
+        // 重建失败时索引保持停用
+        let b4 = mine_child(&b3, vec![coinbase(&wallet, "b4")?])?;
+        blockchain.set_tx_index(false)?;
+        blockchain.add_block(b4.clone())?;
+        let mut batch = StorageBatch::new();
+        batch.delete_block(&b4.hash);
+        storage.write(batch)?;
+        assert!(blockchain.set_tx_index(true).is_err());
+        assert!(!blockchain.tx_index_enabled());
+        assert_eq!(storage.get_tx_index_tip()?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_address_index() -> Result<()> {
+        let params = ChainParams {
+            coinbase_maturity: 1,
+            ..easy_params()
+        };
+        let mut blockchain = Blockchain::with_params(params.clone())?;
+        let alice = Wallet::new()?;
+        let bob = Wallet::new()?;
+        let miner = Wallet::new()?;
+
+        // 启用时为已有的区块补建索引
+        let genesis = mine_genesis(&alice)?;
+        blockchain.add_block(genesis.clone())?;
+        assert!(blockchain.get_address_history(&alice.get_address(), 0, 10).is_err());
+        blockchain.set_address_index(true)?;
+        assert_eq!(blockchain.storage().get_address_index_tip()?, Some(genesis.hash.clone()));
+
+        let tx = Transaction::new(&alice, &bob.get_address(), btc(30), btc(1), blockchain.utxo_set())?;
+        let a1 = mine_child(&genesis, vec![
+            Transaction::new_coinbase(&miner.get_address(), "a1", 1, tx.fee(blockchain.utxo_set())?, &params)?,
+            tx.clone(),
+        ])?;
+        blockchain.add_block(a1.clone())?;
+
+        let utxo_set = blockchain.utxo_set();
+        assert_eq!(utxo_set.get_balance(&alice.get_address())?, btc(19));
+        assert_eq!(utxo_set.get_balance(&bob.get_address())?, btc(30));
+        assert_eq!(utxo_set.list_unspent(&bob.get_address(), 0, 10)?.len(), 1);
+        assert!(utxo_set.list_unspent(&bob.get_address(), 1, 10)?.is_empty());
+
+        // 索引查询与遍历整个UTXO集的结果一致
+        let mut scanned = utxo_set.clone();
+        scanned.set_address_index(false);
+        for wallet in [&alice, &bob, &miner] {
+            assert_eq!(scanned.get_balance(&wallet.get_address())?, utxo_set.get_balance(&wallet.get_address())?);
+        }
+
+        // 历史按时间倒序分页
+        let history = blockchain.get_address_history(&alice.get_address(), 0, 10)?;
+        assert_eq!(history.iter().map(|entry| entry.txid.clone()).collect::<Vec<_>>(),
+            vec![tx.id.clone(), genesis.transactions[0].id.clone()]);
+        assert_eq!((history[0].received, history[0].sent), (btc(19), btc(50)));
+        assert_eq!(blockchain.get_address_history(&alice.get_address(), 1, 1)?, history[1..].to_vec());
+        assert_eq!(blockchain.get_address_history(&bob.get_address(), 0, 10)?.len(), 1);
+
+        // 重组后索引跟随新主链
+        let b1 = mine_child(&genesis, vec![coinbase(&miner, "b1")?])?;
+        let b2 = mine_child(&b1, vec![coinbase(&miner, "b2")?])?;
+        blockchain.add_block(b1)?;
+        assert!(blockchain.add_block(b2.clone())?.is_reorg());
+        assert_eq!(blockchain.storage().get_address_index_tip()?, Some(b2.hash.clone()));
+        assert_eq!(blockchain.utxo_set().get_balance(&alice.get_address())?, btc(50));
+        assert_eq!(blockchain.utxo_set().get_balance(&bob.get_address())?, btc(0));
+        assert!(blockchain.get_address_history(&bob.get_address(), 0, 10)?.is_empty());
+        assert_eq!(blockchain.get_address_history(&alice.get_address(), 0, 10)?.len(), 1);
+        assert_eq!(blockchain.utxo_set().list_unspent(&miner.get_address(), 0, 10)?.len(), 2);
+
         Ok(())
     }
 }
@@ -1,93 +1,304 @@
 use std::path::Path;
+use parking_lot::RwLock;
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
 use sled::IVec;
 use crate::error::{Result, RustBtcError};
 
 const BLOCK_BUCKET: &str = "blocks";
 const ADDR_BUCKET: &str = "addresses";
 const UTXO_BUCKET: &str = "utxos";
+const UNDO_BUCKET: &str = "undo";
+const HEIGHT_BUCKET: &str = "heights";
+const BLOCK_INDEX_BUCKET: &str = "block_index";
+const META_BUCKET: &str = "meta";
+const TX_INDEX_BUCKET: &str = "tx_index";
+const ADDR_UTXO_BUCKET: &str = "address_utxos";
+const ADDR_HISTORY_BUCKET: &str = "address_history";
 
-#[derive(Debug, Clone, Copy)]
+/// Meta key naming the tree that currently holds `table`, written when a rebuilt tree is swapped in
+fn tree_name_key(table: DbTable) -> Vec<u8> {
+    format!("tree:{}", table.as_str()).into_bytes()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DbTable {
     Block,
     Address,
     UTXO,
+    Undo,
+    /// Height -> block hash on the active chain
+    Height,
+    /// Block hash -> `BlockIndex` for every known block
+    BlockIndex,
+    /// Chain state such as the tip hash
+    Meta,
+    /// Txid -> location of the transaction on the active chain
+    TxIndex,
+    /// Pubkey hash + outpoint -> value of an unspent output paying that hash
+    AddressUtxo,
+    /// Pubkey hash + height + position -> a transaction funding or spending from that hash
+    AddressHistory,
 }
 
 impl DbTable {
+    const ALL: [DbTable; 10] = [
+        DbTable::Block,
+        DbTable::Address,
+        DbTable::UTXO,
+        DbTable::Undo,
+        DbTable::Height,
+        DbTable::BlockIndex,
+        DbTable::Meta,
+        DbTable::TxIndex,
+        DbTable::AddressUtxo,
+        DbTable::AddressHistory,
+    ];
+
     fn as_str(&self) -> &'static str {
         match self {
             DbTable::Block => BLOCK_BUCKET,
             DbTable::Address => ADDR_BUCKET,
             DbTable::UTXO => UTXO_BUCKET,
+            DbTable::Undo => UNDO_BUCKET,
+            DbTable::Height => HEIGHT_BUCKET,
+            DbTable::BlockIndex => BLOCK_INDEX_BUCKET,
+            DbTable::Meta => META_BUCKET,
+            DbTable::TxIndex => TX_INDEX_BUCKET,
+            DbTable::AddressUtxo => ADDR_UTXO_BUCKET,
+            DbTable::AddressHistory => ADDR_HISTORY_BUCKET,
         }
     }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// When writes are made durable on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// Flush after every `put`, `delete` and `write`
+    #[default]
+    EveryWrite,
+    /// Let sled flush in the background every given number of milliseconds
+    Periodic(u64),
+    /// Only flush when `Database::flush` is called
+    Manual,
+}
+
+/// A set of inserts and removals across tables, applied atomically by `Database::write`
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    ops: Vec<(DbTable, Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, table: DbTable, key: &[u8], value: &[u8]) {
+        self.ops.push((table, key.to_vec(), Some(value.to_vec())));
+    }
+
+    pub fn delete(&mut self, table: DbTable, key: &[u8]) {
+        self.ops.push((table, key.to_vec(), None));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
 }
 
+/// One open sled database; every table is a tree inside it
 pub struct Database {
-    path: String,
+    db: sled::Db,
+    trees: RwLock<Vec<sled::Tree>>,
+    flush_policy: FlushPolicy,
 }
 
 impl Database {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let path_str = path.as_ref()
-            .to_str()
-            .ok_or_else(|| RustBtcError::Database("Invalid path".to_string()))?
-            .to_string();
-            
-        // Test database connection
-        sled::open(&path_str)
+        Self::with_flush_policy(path, FlushPolicy::default())
+    }
+
+    pub fn with_flush_policy<P: AsRef<Path>>(path: P, flush_policy: FlushPolicy) -> Result<Self> {
+        Self::open(sled::Config::new().path(path), flush_policy)
+    }
+
+    /// A database that lives only as long as this handle and is removed when dropped
+    pub fn temporary() -> Result<Self> {
+        Self::open(sled::Config::new().temporary(true), FlushPolicy::Manual)
+    }
+
+    fn open(config: sled::Config, flush_policy: FlushPolicy) -> Result<Self> {
+        let flush_every_ms = match flush_policy {
+            FlushPolicy::Periodic(ms) => Some(ms),
+            FlushPolicy::EveryWrite | FlushPolicy::Manual => None,
+        };
+        let db = config
+            .flush_every_ms(flush_every_ms)
+            .open()
+            .map_err(|e| RustBtcError::Database(e.to_string()))?;
+        Self::from_db(db, flush_policy)
+    }
+
+    fn from_db(db: sled::Db, flush_policy: FlushPolicy) -> Result<Self> {
+        let meta = db.open_tree(META_BUCKET)
             .map_err(|e| RustBtcError::Database(e.to_string()))?;
-            
+        let trees = DbTable::ALL
+            .iter()
+            .map(|table| {
+                let name = meta.get(tree_name_key(*table))?.unwrap_or_else(|| IVec::from(table.as_str()));
+                db.open_tree(name)
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| RustBtcError::Database(e.to_string()))?;
+
         Ok(Database {
-            path: path_str,
+            db,
+            trees: RwLock::new(trees),
+            flush_policy,
         })
     }
 
-    fn get_table(&self, table: DbTable) -> Result<sled::Tree> {
-        let db = sled::open(&self.path)
+    /// A second handle on the same sled database, as if it had been closed and opened again.
+    /// Opening the path a second time within one process races the first handle for the file lock
+    #[cfg(test)]
+    pub(crate) fn reopen(&self) -> Result<Self> {
+        Self::from_db(self.db.clone(), self.flush_policy)
+    }
+
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.flush_policy
+    }
+
+    fn get_table(&self, table: DbTable) -> sled::Tree {
+        self.trees.read()[table.index()].clone()
+    }
+
+    fn flush_if_needed(&self) -> Result<()> {
+        if self.flush_policy == FlushPolicy::EveryWrite {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write everything buffered so far to disk
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()
             .map_err(|e| RustBtcError::Database(e.to_string()))?;
-            
-        db.open_tree(table.as_str())
-            .map_err(|e| RustBtcError::Database(e.to_string()))
+        Ok(())
     }
 
     pub fn put(&self, table: DbTable, key: &[u8], value: &[u8]) -> Result<()> {
-        let tree = self.get_table(table)?;
-        tree.insert(key, value)
+        self.get_table(table).insert(key, value)
             .map_err(|e| RustBtcError::Database(e.to_string()))?;
-        tree.flush()
-            .map_err(|e| RustBtcError::Database(e.to_string()))?;
-        Ok(())
+        self.flush_if_needed()
     }
 
     pub fn view(&self, table: DbTable, key: &[u8]) -> Result<Option<IVec>> {
-        let tree = self.get_table(table)?;
-        tree.get(key)
+        self.get_table(table).get(key)
             .map_err(|e| RustBtcError::Database(e.to_string()))
     }
 
     pub fn delete(&self, table: DbTable, key: &[u8]) -> Result<()> {
-        let tree = self.get_table(table)?;
-        tree.remove(key)
+        self.get_table(table).remove(key)
             .map_err(|e| RustBtcError::Database(e.to_string()))?;
-        tree.flush()
+        self.flush_if_needed()
+    }
+
+    /// Apply all operations of `batch` in one transaction: after a crash either all or none of them are visible
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        self.trees
+            .read()
+            .as_slice()
+            .transaction(|trees| {
+                for (table, key, value) in &batch.ops {
+                    let tree = &trees[table.index()];
+                    match value {
+                        Some(value) => tree.insert(key.as_slice(), value.as_slice())?,
+                        None => tree.remove(key.as_slice())?,
+                    };
+                }
+                Ok::<_, ConflictableTransactionError<RustBtcError>>(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => RustBtcError::Database(e.to_string()),
+            })?;
+        self.flush_if_needed()
+    }
+
+    /// Remove every entry of `table`; unlike `write` this is not atomic with other changes
+    pub fn clear(&self, table: DbTable) -> Result<()> {
+        self.get_table(table).clear()
             .map_err(|e| RustBtcError::Database(e.to_string()))?;
-        Ok(())
+        self.flush_if_needed()
+    }
+
+    /// A handle on the same database in which `table` is a new, empty tree that other handles don't see.
+    /// Fill it through the returned handle, then make it current with `swap_in`
+    pub fn fresh_table(&self, table: DbTable) -> Result<Database> {
+        let current = self.get_table(table).name();
+        let name = if current == table.as_str().as_bytes() {
+            format!("{}_rebuild", table.as_str())
+        } else {
+            table.as_str().to_string()
+        };
+        let tree = self.db.open_tree(name)
+            .map_err(|e| RustBtcError::Database(e.to_string()))?;
+        // Whatever an interrupted rebuild left behind
+        tree.clear()
+            .map_err(|e| RustBtcError::Database(e.to_string()))?;
+
+        let mut trees = self.trees.read().clone();
+        trees[table.index()] = tree;
+        Ok(Database {
+            db: self.db.clone(),
+            trees: RwLock::new(trees),
+            flush_policy: self.flush_policy,
+        })
+    }
+
+    /// Make the `table` of `fresh`, a handle from `fresh_table`, the current one. `batch` is written through
+    /// `fresh` in the same transaction that records the switch, so after a crash either the old table or the
+    /// complete new one is current
+    pub fn swap_in(&self, table: DbTable, fresh: &Database, mut batch: WriteBatch) -> Result<()> {
+        let tree = fresh.get_table(table);
+        batch.put(DbTable::Meta, &tree_name_key(table), &tree.name());
+        fresh.write(batch)?;
+
+        let old = std::mem::replace(&mut self.trees.write()[table.index()], tree);
+        old.clear()
+            .map_err(|e| RustBtcError::Database(e.to_string()))?;
+        self.flush_if_needed()
+    }
+
+    /// Every entry of `table` in key order; a read error is yielded in place of the entry
+    pub fn iterate(&self, table: DbTable) -> impl Iterator<Item = Result<(IVec, IVec)>> {
+        self.get_table(table).iter().map(|r| r.map_err(|e| RustBtcError::Database(e.to_string())))
     }
 
-    pub fn iterate(&self, table: DbTable) -> Result<impl Iterator<Item = (IVec, IVec)>> {
-        let tree = self.get_table(table)?;
-        Ok(tree.iter().filter_map(|r| match r {
-            Ok(item) => Some(item),
-            Err(_) => None
-        }))
+    /// Entries of `table` whose key starts with `prefix`, in key order
+    pub fn scan_prefix(&self, table: DbTable, prefix: &[u8]) -> impl DoubleEndedIterator<Item = Result<(IVec, IVec)>> {
+        self.get_table(table)
+            .scan_prefix(prefix)
+            .map(|r| r.map_err(|e| RustBtcError::Database(e.to_string())))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
     use tempfile::tempdir;
 
     #[test]
@@ -112,4 +323,32 @@ mod tests {
         
         Ok(())
     }
+
+    #[test]
+    fn test_write_batch() -> Result<()> {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_db");
+
+        let db = Database::with_flush_policy(&db_path, FlushPolicy::Manual)?;
+        db.put(DbTable::UTXO, b"spent", b"coin")?;
+
+        let mut batch = WriteBatch::new();
+        batch.put(DbTable::Block, b"block", b"data");
+        batch.put(DbTable::UTXO, b"created", b"coin");
+        batch.delete(DbTable::UTXO, b"spent");
+        assert_eq!(batch.len(), 3);
+        db.write(batch)?;
+
+        assert_eq!(db.view(DbTable::Block, b"block")?.as_deref(), Some(b"data".as_ref()));
+        assert!(db.view(DbTable::UTXO, b"spent")?.is_none());
+        assert_eq!(db.iterate(DbTable::UTXO).count(), 1);
+        db.flush()?;
+
+        // Reopening sees the flushed batch
+        let reopened = db.reopen()?;
+        assert_eq!(reopened.view(DbTable::UTXO, b"created")?.as_deref(), Some(b"coin".as_ref()));
+        assert!(reopened.view(DbTable::UTXO, b"spent")?.is_none());
+
+        Ok(())
+    }
 }
@@ -1,6 +1,8 @@
 use thiserror::Error;
 use std::time::SystemTimeError;
 
+use crate::validation::BlockRejection;
+
 #[derive(Error, Debug)]
 pub enum RustBtcError {
     #[error("IO错误: {0}")]
@@ -15,6 +17,9 @@ pub enum RustBtcError {
     #[error("Base58编码错误: {0}")]
     Base58(String),
 
+    #[error("脚本验证失败: {0}")]
+    ScriptError(String),
+
     #[error("无效的签名: {0}")]
     InvalidSignature(String),
 
@@ -45,6 +50,9 @@ pub enum RustBtcError {
     #[error("无效区块: {0}")]
     InvalidBlock(String),
 
+    #[error("区块被拒绝: {0}")]
+    BlockRejected(BlockRejection),
+
     #[error("无效区块链: {0}")]
     InvalidChain(String),
 
@@ -90,6 +98,12 @@ pub enum RustBtcError {
     #[error("UTXO未找到: {0}")]
     UTXONotFound(String),
 
+    #[error("coinbase输出尚未成熟: {0}")]
+    ImmatureCoinbase(String),
+
+    #[error("交易尚未最终确定: {0}")]
+    NonFinalTransaction(String),
+
     #[error("其他错误: {0}")]
     Other(String),
 
@@ -1,28 +1,38 @@
 // 导出所有模块
+pub mod addrindex;
+pub mod amount;
 pub mod block;
 pub mod blockchain;
 pub mod error;
 pub mod mempool;
 pub mod merkle;
 pub mod network;
+pub mod orphan;
+pub mod params;
+pub mod sigcache;
+pub mod sighash;
 pub mod pow;
+pub mod script;
 pub mod storage;
 pub mod transaction;
 pub mod utxo;
+pub mod validation;
 pub mod wallet;
 pub mod models;
 pub mod db;
 
 // 导出常用类型
-pub use block::Block;
-pub use blockchain::Blockchain;
+pub use addrindex::AddressTx;
+pub use amount::Amount;
+pub use block::{Block, BlockHeader};
+pub use blockchain::{Blockchain, ChainUpdate, ConfirmedTransaction, TxLocation};
 pub use error::{RustBtcError, Result};
 pub use mempool::Mempool;
 pub use merkle::MerkleTree;
 pub use network::P2PNetwork;
-pub use pow::ProofOfWork;
+pub use params::ChainParams;
 pub use storage::Storage;
 pub use transaction::Transaction;
 pub use utxo::UTXOSet;
 pub use wallet::Wallet;
-pub  use models::{WalletData, UTXOEntry};
\ No newline at end of file
+pub  use models::WalletData;
\ No newline at end of file
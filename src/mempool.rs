@@ -5,8 +5,13 @@ use dashmap::DashMap;
 use lru::LruCache;
 use parking_lot::RwLock;
 
-use crate::transaction::Transaction;
-use crate::utxo::UTXOSet;
+use tracing::debug;
+
+use crate::amount::Amount;
+use crate::blockchain::{Blockchain, ChainUpdate};
+use crate::params::ChainParams;
+use crate::transaction::{Transaction, TxOutput};
+use crate::utxo::{Coin, UTXOSet};
 use super::error::{Result, RustBtcError};
 
 const MAX_CACHE_SIZE: usize = 10000;
@@ -19,12 +24,10 @@ struct TransactionEntry {
 }
 
 impl TransactionEntry {
-    fn new(transaction: Transaction) -> Result<Self> {
-        let _fee = transaction.calculate_fee_rate();
-        
-        Ok(Self {
+    fn new(transaction: Transaction) -> Self {
+        Self {
             transaction,
-        })
+        }
     }
 }
 
@@ -33,21 +36,101 @@ pub struct Mempool {
     max_size: usize,
     recent_txs: RwLock<LruCache<String, ()>>,
     utxo_set: Arc<UTXOSet>,
+    coinbase_maturity: u64,
+    /// 主链末端的中位时间，用于判断时间锁
+    median_time_past: u32,
 }
 
 impl Mempool {
     pub fn new(utxo_set: Arc<UTXOSet>) -> Self {
+        Self::with_max_size(utxo_set, MAX_MEMPOOL_SIZE)
+    }
+
+    pub fn with_max_size(utxo_set: Arc<UTXOSet>, max_size: usize) -> Self {
         Self {
             transactions: DashMap::new(),
-            max_size: MAX_MEMPOOL_SIZE,
+            max_size,
             recent_txs: RwLock::new(LruCache::new(NonZeroUsize::new(MAX_CACHE_SIZE).unwrap())),
             utxo_set,
+            coinbase_maturity: ChainParams::default().coinbase_maturity,
+            median_time_past: 0,
+        }
+    }
+
+    /// 按链参数创建内存池
+    pub fn with_params(utxo_set: Arc<UTXOSet>, params: &ChainParams) -> Self {
+        Self {
+            coinbase_maturity: params.coinbase_maturity,
+            ..Self::new(utxo_set)
         }
     }
 
+    /// 替换用于验证交易的UTXO集，通常在主链变化后调用
+    pub fn set_utxo_set(&mut self, utxo_set: Arc<UTXOSet>) {
+        self.utxo_set = utxo_set;
+    }
+
+    /// 设置主链末端的中位时间，交易的时间锁按下一个区块检查
+    pub fn set_median_time_past(&mut self, median_time_past: u32) {
+        self.median_time_past = median_time_past;
+    }
+
+    /// 根据`blockchain`的主链变化更新内存池：移除已被打包或与新区块冲突的交易，并将重组中断开区块里的交易放回内存池
+    pub fn update_for_chain(&mut self, update: &ChainUpdate, blockchain: &Blockchain) -> Result<()> {
+        self.set_utxo_set(Arc::new(blockchain.utxo_set().clone()));
+        let tip = blockchain.get_last_hash()?;
+        self.set_median_time_past(blockchain.median_time_past(&tip).unwrap_or(0));
+
+        for block in &update.connected {
+            for tx in &block.transactions {
+                self.transactions.remove(&tx.txid()?);
+            }
+        }
+
+        // 按高度升序放回，保证父交易先于子交易，子交易花费的输出从内存池中的父交易查找
+        for block in update.disconnected.iter().rev() {
+            for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+                if let Err(e) = self.add_transaction(tx.clone()) {
+                    debug!("断开区块中的交易 {} 未能放回内存池: {}", tx.id, e);
+                }
+            }
+        }
+
+        // 输入既不在UTXO集中、也不是内存池中交易的输出时与新主链冲突，移除后其子交易也随之冲突
+        loop {
+            let conflicting: Vec<String> = self
+                .get_all_transactions()
+                .into_iter()
+                .filter(|tx| {
+                    tx.vin.iter().any(|input| {
+                        !matches!(self.find_coin(&input.txid, input.vout), Ok(Some(_)))
+                    })
+                })
+                .map(|tx| tx.id)
+                .collect();
+            if conflicting.is_empty() {
+                break;
+            }
+            for tx_hash in conflicting {
+                debug!("移除与新主链冲突的交易: {}", tx_hash);
+                self.transactions.remove(&tx_hash);
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn add_transaction(&mut self, tx: Transaction) -> Result<()> {
+        // coinbase交易只能出现在区块中
+        if tx.is_coinbase() {
+            return Err(RustBtcError::InvalidTransaction(format!(
+                "coinbase交易 {} 不能进入内存池",
+                tx.id
+            )));
+        }
+
         let tx_size = bincode::serialize(&tx)
-            .map_err(|e| RustBtcError::Serialization(e))?
+            .map_err(RustBtcError::Serialization)?
             .len();
 
         if tx_size > MAX_TRANSACTION_SIZE {
@@ -64,7 +147,13 @@ impl Mempool {
             )));
         }
 
-        let tx_hash = tx.hash()?;
+        let tx_hash = tx.txid()?;
+        if tx.id != tx_hash {
+            return Err(RustBtcError::InvalidTransaction(format!(
+                "交易ID {} 与交易内容不符，应为 {}",
+                tx.id, tx_hash
+            )));
+        }
         if self.transactions.contains_key(&tx_hash) {
             return Err(RustBtcError::DuplicateTransaction(format!(
                 "交易 {} 已存在于内存池中",
@@ -76,7 +165,7 @@ impl Mempool {
             return Err(RustBtcError::ValidationError("交易验证失败".to_string()));
         }
 
-        let entry = TransactionEntry::new(tx)?;
+        let entry = TransactionEntry::new(tx);
         self.transactions.insert(tx_hash.clone(), entry);
         self.recent_txs.write().put(tx_hash, ());
         Ok(())
@@ -127,74 +216,123 @@ impl Mempool {
             return Err(RustBtcError::ValidationError("交易的输入或输出不能为空".to_string()));
         }
 
-        // 验证所有输入
-        let mut total_input = 0;
-        for input in &tx.vin {
-            if input.value <= 0 {
-                return Err(RustBtcError::InvalidAmount("输入金额必须为正数".to_string()));
-            }
-            total_input += input.value;
+        // 验证所有输出，输入总额以花费的UTXO为准，由`Transaction::verify`与输出总额比较
+        if tx.vout.iter().any(|output| output.value == Amount::ZERO) {
+            return Err(RustBtcError::InvalidAmount("输出金额必须为正数".to_string()));
         }
-
-        // 验证所有输出
-        let mut total_output = 0;
-        for output in &tx.vout {
-            if output.value <= 0 {
-                return Err(RustBtcError::InvalidAmount("输出金额必须为正数".to_string()));
-            }
-            total_output += output.value;
+        if tx.output_total().is_none() {
+            return Err(RustBtcError::InvalidAmount("输出总额超过最大金额".to_string()));
         }
 
-        // 验证输入总额大于输出总额
-        if total_input <= total_output {
-            return Err(RustBtcError::InvalidAmount(format!(
-                "输入总额 {} 必须大于输出总额 {}",
-                total_input, total_output
+        // 交易最早在下一个区块中被打包，时间锁必须在该区块到期
+        let spend_height = self.utxo_set.next_height();
+        if !tx.is_final(spend_height, self.median_time_past) {
+            return Err(RustBtcError::NonFinalTransaction(format!(
+                "交易 {} 的时间锁 {} 在高度 {} 尚未到期",
+                tx.id, tx.lock_time, spend_height
             )));
         }
 
-        // 验证所有输入的 UTXO
+        // 验证所有输入花费的输出，coinbase输出在此之前必须已经成熟
+        let mut coins = Vec::with_capacity(tx.vin.len());
         for input in &tx.vin {
-            if !self.utxo_set.verify_input(input)? {
-                return Err(RustBtcError::UTXOError(format!(
-                    "UTXO {}:{} 验证失败: 不存在、已被使用或签名无效",
-                    input.txid, input.vout
+            let coin = match self.find_coin(&input.txid, input.vout)? {
+                Some(coin) if coin.output.value == input.value => coin,
+                _ => {
+                    return Err(RustBtcError::UTXOError(format!(
+                        "UTXO {}:{} 验证失败: 不存在、已被使用或金额不符",
+                        input.txid, input.vout
+                    )));
+                }
+            };
+            if !coin.is_mature(spend_height, self.coinbase_maturity) {
+                return Err(RustBtcError::ImmatureCoinbase(format!(
+                    "高度 {} 的coinbase输出 {}:{} 在高度 {} 尚不能花费",
+                    coin.height, input.txid, input.vout, spend_height
                 )));
             }
+            coins.push(coin);
+        }
+        let coin_refs: Vec<&Coin> = coins.iter().collect();
+        if !tx.sequence_lock(&coin_refs).is_satisfied(spend_height, self.median_time_past) {
+            return Err(RustBtcError::NonFinalTransaction(format!(
+                "交易 {} 的相对时间锁在高度 {} 尚未到期",
+                tx.id, spend_height
+            )));
         }
 
         // 验证交易签名
-        if !tx.verify(&self.utxo_set)? {
+        let spent_outputs: Vec<TxOutput> = coins.into_iter().map(|coin| coin.output).collect();
+        if !tx.verify_spent_outputs(&spent_outputs)? {
             return Err(RustBtcError::ValidationError("交易验证失败".to_string()));
         }
 
         Ok(true)
     }
+
+    /// 输入花费的输出：先在UTXO集中查找，其次是内存池中尚未打包的父交易
+    fn find_coin(&self, txid: &str, vout: usize) -> Result<Option<Coin>> {
+        if let Some(coin) = self.utxo_set.get_coin(txid, vout)? {
+            return Ok(Some(coin));
+        }
+        // 父交易最早与本交易在同一个区块中被打包
+        Ok(self.transactions.get(txid).and_then(|entry| {
+            let output = entry.transaction.vout.get(vout).filter(|output| !output.is_unspendable())?;
+            Some(Coin {
+                output: output.clone(),
+                height: self.utxo_set.next_height(),
+                is_coinbase: false,
+                median_time_past: self.median_time_past,
+            })
+        }))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::block::Block;
+    use crate::blockchain::Blockchain;
+    use crate::params::ChainParams;
+    use crate::transaction::TxInput;
     use crate::wallet::Wallet;
 
     fn create_test_wallet() -> Result<Wallet> {
         Wallet::new()
     }
 
+    fn btc(n: u64) -> Amount {
+        Amount::from_btc(n).unwrap()
+    }
+
+    /// 创世区块的coinbase属于`owner`并且可以立即花费的链
+    fn funded_chain(owner: &Wallet) -> Result<(Blockchain, ChainParams)> {
+        let params = ChainParams {
+            coinbase_maturity: 0,
+            ..ChainParams::regtest()
+        };
+        let mut blockchain = Blockchain::with_params(params.clone())?;
+        let genesis = mine_child("0", 1_600_000_000, vec![
+            Transaction::new_coinbase(&owner.get_address(), "genesis", 0, Amount::ZERO, &params)?,
+        ])?;
+        blockchain.add_block(genesis)?;
+        Ok((blockchain, params))
+    }
+
     #[test]
     fn test_mempool_basic_operations() -> Result<()> {
-        let mut mempool = Mempool::new(Arc::new(UTXOSet::new()));
         let wallet = create_test_wallet()?;
-        let address = wallet.get_address();
+        let (blockchain, params) = funded_chain(&wallet)?;
+        let mut mempool = Mempool::with_params(Arc::new(blockchain.utxo_set().clone()), &params);
         
         // 创建测试交易
-        let tx = Transaction::new_coinbase(&address, "Test Mempool")?;
+        let tx = Transaction::new(&wallet, &create_test_wallet()?.get_address(), btc(10), btc(1), blockchain.utxo_set())?;
         
         // 添加交易到 mempool
         mempool.add_transaction(tx.clone())?;
         
         // 验证交易已添加
-        assert!(mempool.get_transaction(&tx.hash().unwrap()).is_ok());
+        assert!(mempool.get_transaction(&tx.txid().unwrap()).is_ok());
         assert_eq!(mempool.size(), 1);
         
         Ok(())
@@ -202,13 +340,17 @@ mod tests {
 
     #[test]
     fn test_mempool_capacity() -> Result<()> {
-        let mut mempool = Mempool::new(Arc::new(UTXOSet::new()));
         let wallet = create_test_wallet()?;
-        let address = wallet.get_address();
+        let (blockchain, params) = funded_chain(&wallet)?;
+        let mut mempool = Mempool {
+            max_size: 2,
+            ..Mempool::with_params(Arc::new(blockchain.utxo_set().clone()), &params)
+        };
+        let address = create_test_wallet()?.get_address();
         
         // 创建并添加多个交易
         for i in 0..3 {
-            let tx = Transaction::new_coinbase(&address, &format!("Test {}", i))?;
+            let tx = Transaction::new(&wallet, &address, btc(10 + i), btc(1), blockchain.utxo_set())?;
             let result = mempool.add_transaction(tx);
             
             if i < 2 {
@@ -224,12 +366,12 @@ mod tests {
 
     #[test]
     fn test_mempool_duplicate_transaction() -> Result<()> {
-        let mut mempool = Mempool::new(Arc::new(UTXOSet::new()));
         let wallet = create_test_wallet()?;
-        let address = wallet.get_address();
+        let (blockchain, params) = funded_chain(&wallet)?;
+        let mut mempool = Mempool::with_params(Arc::new(blockchain.utxo_set().clone()), &params);
         
         // 创建测试交易
-        let tx = Transaction::new_coinbase(&address, "Test Duplicate")?;
+        let tx = Transaction::new(&wallet, &create_test_wallet()?.get_address(), btc(10), btc(1), blockchain.utxo_set())?;
         
         // 第一次添加应该成功
         mempool.add_transaction(tx.clone())?;
@@ -245,21 +387,231 @@ mod tests {
     }
 
     #[test]
-    fn test_mempool_coinbase_transaction() -> Result<()> {
+    fn test_mempool_rejects_mismatched_txid() -> Result<()> {
+        let wallet = create_test_wallet()?;
+        let (blockchain, params) = funded_chain(&wallet)?;
+        let mut mempool = Mempool::with_params(Arc::new(blockchain.utxo_set().clone()), &params);
+
+        let mut tx = Transaction::new(&wallet, &create_test_wallet()?.get_address(), btc(10), btc(1), blockchain.utxo_set())?;
+        tx.id = "ab".repeat(32);
+        assert!(matches!(
+            mempool.add_transaction(tx),
+            Err(RustBtcError::InvalidTransaction(_))
+        ));
+        assert_eq!(mempool.size(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mempool_rejects_coinbase_transaction() -> Result<()> {
         let mut mempool = Mempool::new(Arc::new(UTXOSet::new()));
         let wallet = create_test_wallet()?;
         let address = wallet.get_address();
         
-        // 创建 coinbase 交易
-        let tx = Transaction::new_coinbase(&address, "Test Coinbase")?;
+        // coinbase 交易只能由矿工放进区块
+        let tx = Transaction::new_coinbase(&address, "Test Coinbase", 0, Amount::ZERO, &ChainParams::default())?;
+        assert!(matches!(
+            mempool.add_transaction(tx.clone()),
+            Err(RustBtcError::InvalidTransaction(_))
+        ));
+        assert!(mempool.get_transaction(&tx.txid()?).is_err());
+        assert_eq!(mempool.size(), 0);
         
-        // 添加 coinbase 交易
+        Ok(())
+    }
+
+    #[test]
+    fn test_mempool_rejects_immature_coinbase_spend() -> Result<()> {
+        let params = ChainParams::regtest();
+        let mut blockchain = Blockchain::with_params(params.clone())?;
+        let wallet1 = create_test_wallet()?;
+        let wallet2 = create_test_wallet()?;
+
+        let genesis = mine_child("0", 1_600_000_000, vec![
+            Transaction::new_coinbase(&wallet1.get_address(), "genesis", 0, Amount::ZERO, &params)?,
+        ])?;
+        blockchain.add_block(genesis)?;
+
+        let tx = Transaction::new(&wallet1, &wallet2.get_address(), btc(30), btc(1), blockchain.utxo_set())?;
+        let mut mempool = Mempool::with_params(Arc::new(blockchain.utxo_set().clone()), &params);
+        assert!(matches!(
+            mempool.add_transaction(tx),
+            Err(RustBtcError::ImmatureCoinbase(_))
+        ));
+        assert_eq!(mempool.size(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mempool_rejects_spend_of_foreign_output() -> Result<()> {
+        let params = ChainParams {
+            coinbase_maturity: 0,
+            ..ChainParams::regtest()
+        };
+        let mut blockchain = Blockchain::with_params(params.clone())?;
+        let owner = create_test_wallet()?;
+        let thief = create_test_wallet()?;
+
+        let genesis = mine_child("0", 1_600_000_000, vec![
+            Transaction::new_coinbase(&owner.get_address(), "genesis", 0, Amount::ZERO, &params)?,
+        ])?;
+        blockchain.add_block(genesis.clone())?;
+        let mut mempool = Mempool::with_params(Arc::new(blockchain.utxo_set().clone()), &params);
+
+        // 小偷用自己的密钥签名花费别人的coinbase输出
+        let mut stolen = Transaction::new(&owner, &thief.get_address(), btc(30), btc(1), blockchain.utxo_set())?;
+        stolen.sign(&thief)?;
+        assert!(mempool.add_transaction(stolen).is_err());
+
+        // 与区块验证一致，0手续费的交易可以进入内存池
+        let honest = Transaction::new(&owner, &thief.get_address(), btc(30), Amount::ZERO, blockchain.utxo_set())?;
+        mempool.add_transaction(honest)?;
+        assert_eq!(mempool.size(), 1);
+
+        Ok(())
+    }
+
+    fn mine_child(parent_hash: &str, timestamp: u32, transactions: Vec<Transaction>) -> Result<Block> {
+        let mut block = Block::new(transactions, parent_hash.to_string())?;
+        block.header.bits = 0x207fffff;
+        block.header.timestamp = timestamp;
+        block.mine_block()?;
+        Ok(block)
+    }
+
+    #[test]
+    fn test_mempool_update_for_chain() -> Result<()> {
+        let params = ChainParams {
+            coinbase_maturity: 1,
+            ..ChainParams::regtest()
+        };
+        let mut blockchain = Blockchain::with_params(params.clone())?;
+        let wallet1 = create_test_wallet()?;
+        let wallet2 = create_test_wallet()?;
+        let address = wallet1.get_address();
+
+        let genesis = mine_child("0", 1_600_000_000, vec![Transaction::new_coinbase(&address, "genesis", 0, Amount::ZERO, &ChainParams::default())?])?;
+        blockchain.add_block(genesis.clone())?;
+
+        let tx = Transaction::new(&wallet1, &wallet2.get_address(), btc(30), btc(1), blockchain.utxo_set())?;
+        let mut mempool = Mempool::with_params(Arc::new(blockchain.utxo_set().clone()), &params);
         mempool.add_transaction(tx.clone())?;
-        
-        // 验证交易已添加
-        assert!(mempool.get_transaction(&tx.hash().unwrap()).is_ok());
+
+        // 交易被打包后从内存池移除
+        let a1 = mine_child(&genesis.hash, 1_600_000_001, vec![
+            Transaction::new_coinbase(&address, "a1", 0, Amount::ZERO, &ChainParams::default())?,
+            tx.clone(),
+        ])?;
+        let update = blockchain.add_block(a1)?;
+        mempool.update_for_chain(&update, &blockchain)?;
+        assert_eq!(mempool.size(), 0);
+
+        // 重组断开包含该交易的区块后，交易回到内存池
+        let b1 = mine_child(&genesis.hash, 1_600_000_001, vec![Transaction::new_coinbase(&address, "b1", 0, Amount::ZERO, &ChainParams::default())?])?;
+        let b2 = mine_child(&b1.hash, 1_600_000_002, vec![Transaction::new_coinbase(&address, "b2", 0, Amount::ZERO, &ChainParams::default())?])?;
+        blockchain.add_block(b1)?;
+        let update = blockchain.add_block(b2)?;
+        assert!(update.is_reorg());
+        mempool.update_for_chain(&update, &blockchain)?;
         assert_eq!(mempool.size(), 1);
-        
+        assert!(mempool.get_transaction(&tx.txid()?).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mempool_update_for_chain_restores_chained_transactions() -> Result<()> {
+        let params = ChainParams {
+            coinbase_maturity: 0,
+            ..ChainParams::regtest()
+        };
+        let mut blockchain = Blockchain::with_params(params.clone())?;
+        let wallet1 = create_test_wallet()?;
+        let wallet2 = create_test_wallet()?;
+        let address = wallet1.get_address();
+
+        let genesis = mine_child("0", 1_600_000_000, vec![Transaction::new_coinbase(&address, "genesis", 0, Amount::ZERO, &params)?])?;
+        blockchain.add_block(genesis.clone())?;
+
+        // 子交易花费同一区块中父交易的输出
+        let parent = Transaction::new(&wallet1, &wallet2.get_address(), btc(30), btc(1), blockchain.utxo_set())?;
+        let mut child = Transaction {
+            id: String::new(),
+            vin: vec![TxInput::new(parent.id.clone(), 0, btc(30))],
+            vout: vec![TxOutput::new(btc(29), &address)?],
+            lock_time: 0,
+        };
+        child.id = child.txid()?;
+        child.sign(&wallet2)?;
+
+        let mut mempool = Mempool::with_params(Arc::new(blockchain.utxo_set().clone()), &params);
+        let a1 = mine_child(&genesis.hash, 1_600_000_001, vec![
+            Transaction::new_coinbase(&address, "a1", 0, Amount::ZERO, &params)?,
+            parent.clone(),
+            child.clone(),
+        ])?;
+        let update = blockchain.add_block(a1)?;
+        mempool.update_for_chain(&update, &blockchain)?;
+        assert_eq!(mempool.size(), 0);
+
+        // 重组后父交易和子交易都回到内存池，中位时间跟随新的链尾
+        let b1 = mine_child(&genesis.hash, 1_600_000_001, vec![Transaction::new_coinbase(&address, "b1", 0, Amount::ZERO, &params)?])?;
+        let b2 = mine_child(&b1.hash, 1_600_000_002, vec![Transaction::new_coinbase(&address, "b2", 0, Amount::ZERO, &params)?])?;
+        blockchain.add_block(b1)?;
+        let update = blockchain.add_block(b2.clone())?;
+        assert!(update.is_reorg());
+        mempool.update_for_chain(&update, &blockchain)?;
+        assert_eq!(mempool.size(), 2);
+        assert!(mempool.get_transaction(&parent.id).is_ok());
+        assert!(mempool.get_transaction(&child.id).is_ok());
+        assert_eq!(Some(mempool.median_time_past), blockchain.median_time_past(&b2.hash));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mempool_rejects_non_final_transaction() -> Result<()> {
+        let params = ChainParams {
+            coinbase_maturity: 0,
+            ..ChainParams::regtest()
+        };
+        let mut blockchain = Blockchain::with_params(params.clone())?;
+        let owner = create_test_wallet()?;
+        let recipient = create_test_wallet()?.get_address();
+
+        let genesis = mine_child("0", 1_600_000_000, vec![
+            Transaction::new_coinbase(&owner.get_address(), "genesis", 0, Amount::ZERO, &params)?,
+        ])?;
+        blockchain.add_block(genesis)?;
+        let mut mempool = Mempool::with_params(Arc::new(blockchain.utxo_set().clone()), &params);
+        mempool.set_median_time_past(1_600_000_000);
+
+        let timelocked = |lock_time: u32, sequence: u32| -> Result<Transaction> {
+            let mut tx = Transaction::new(&owner, &recipient, btc(30), btc(1), blockchain.utxo_set())?;
+            tx.lock_time = lock_time;
+            tx.vin[0].sequence = sequence;
+            tx.id = tx.txid()?;
+            tx.sign(&owner)?;
+            Ok(tx)
+        };
+
+        // 下一个区块高度为1
+        assert!(matches!(
+            mempool.add_transaction(timelocked(1, 0)?),
+            Err(RustBtcError::NonFinalTransaction(_))
+        ));
+        // 输入在高度0创建，相对2个区块的锁要到高度2才能打包
+        assert!(matches!(
+            mempool.add_transaction(timelocked(0, 2)?),
+            Err(RustBtcError::NonFinalTransaction(_))
+        ));
+        assert_eq!(mempool.size(), 0);
+
+        mempool.add_transaction(timelocked(1_599_999_999, 1)?)?;
+        assert_eq!(mempool.size(), 1);
+
         Ok(())
     }
 }
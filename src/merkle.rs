@@ -49,7 +49,7 @@ impl MerkleNode {
         for sibling in proof {
             let mut hasher = Sha256::new();
             
-            if current_index % 2 == 0 {
+            if current_index.is_multiple_of(2) {
                 hasher.update(&current_hash);
                 hasher.update(sibling);
             } else {
@@ -140,7 +140,7 @@ impl MerkleTree {
         let mut nodes = self.leaves.clone();
 
         while nodes.len() > 1 {
-            let sibling_index = if current_index % 2 == 0 {
+            let sibling_index = if current_index.is_multiple_of(2) {
                 current_index + 1
             } else {
                 current_index - 1
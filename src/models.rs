@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use crate::amount::Amount;
 use crate::error::Result;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,7 +18,7 @@ pub struct TxInput {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TxOutput {
-    pub value: u64,
+    pub value: Amount,
     pub script_pubkey: String,
 }
 
@@ -28,15 +29,6 @@ pub struct WalletData {
     pub private_key: Vec<u8>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct UTXOEntry {
-    pub txid: String,
-    pub vout: u32,
-    pub value: u64,
-    pub script_pubkey: String,
-    pub address: String,
-}
-
 impl WalletData {
     pub fn serialize(&self) -> Result<Vec<u8>> {
         bincode::serialize(self)
@@ -48,15 +40,3 @@ impl WalletData {
             .map_err(|e| e.into())
     }
 }
-
-impl UTXOEntry {
-    pub fn serialize(&self) -> Result<Vec<u8>> {
-        bincode::serialize(self)
-            .map_err(|e| e.into())
-    }
-
-    pub fn deserialize(data: &[u8]) -> Result<Self> {
-        bincode::deserialize(data)
-            .map_err(|e| e.into())
-    }
-}
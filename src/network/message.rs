@@ -1,10 +1,22 @@
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
 use crate::block::Block;
+use crate::error::{Result, RustBtcError};
+use crate::validation::MAX_BLOCK_SIZE;
+
+/// 单条消息的最大长度，超过时视为对端出错而不是为其分配内存
+pub const MAX_MESSAGE_SIZE: usize = 2 * MAX_BLOCK_SIZE;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
     // Node discovery messages
+    Version {
+        version: u32,
+        best_height: u32,
+        timestamp: u64,
+    },
     Ping,
     Pong,
     GetPeers,
@@ -14,6 +26,7 @@ pub enum Message {
     // Block synchronization messages
     NewBlock(Block),
     GetBlock(u64), // height
+    GetBlockByHash(String),
     Block(Block),
     GetBlockHeight,
     BlockHeight(u64),
@@ -32,6 +45,29 @@ impl Message {
     pub fn deserialize(data: &[u8]) -> Option<Self> {
         bincode::deserialize(data).ok()
     }
+
+    /// 以4字节大端长度前缀加消息体的格式写入连接
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        let data = bincode::serialize(self)?;
+        writer.write_u32(data.len() as u32).await?;
+        writer.write_all(&data).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// 从连接读取一条由`write_to`写入的消息
+    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self> {
+        let len = reader.read_u32().await? as usize;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(RustBtcError::InvalidMessage(format!(
+                "消息长度 {} 超过最大限制 {}",
+                len, MAX_MESSAGE_SIZE
+            )));
+        }
+        let mut data = vec![0u8; len];
+        reader.read_exact(&mut data).await?;
+        Ok(bincode::deserialize(&data)?)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -2,6 +2,6 @@ mod peer;
 pub mod message;
 pub mod p2p;
 
-pub use peer::{Peer, PeerInfo};
+pub use peer::{median_time_offset, Peer, PeerInfo};
 pub use message::Message;
 pub use p2p::P2PNetwork;
@@ -6,24 +6,28 @@ use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, Mutex, RwLock};
 use tokio::time;
-use tracing::info;
+use tracing::{debug, info, warn};
 
-use crate::error::Result;
-use crate::network::message::Message;
-use crate::network::peer::Peer;
+use crate::block::Block;
+use crate::blockchain::{Blockchain, ChainUpdate};
+use crate::error::{Result, RustBtcError};
+use crate::network::message::{Message, NetworkMessage};
+use crate::network::peer::{median_time_offset, Peer};
 use crate::storage::Storage;
 
 pub struct P2PNetwork {
     peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
+    #[allow(dead_code)]
     storage: Arc<Storage>,
     listen_addr: SocketAddr,
-    message_receiver: Arc<Mutex<mpsc::Receiver<Message>>>,
-    message_sender: mpsc::Sender<Message>,
+    /// 所有对等节点的读任务把收到的消息汇总到这里
+    message_receiver: Arc<Mutex<mpsc::Receiver<NetworkMessage>>>,
+    message_sender: mpsc::Sender<NetworkMessage>,
 }
 
 impl P2PNetwork {
     pub async fn new(listen_addr: SocketAddr, storage: Arc<Storage>) -> Result<Arc<Self>> {
-        let (tx, rx) = mpsc::channel::<Message>(32);
+        let (tx, rx) = mpsc::channel::<NetworkMessage>(32);
         
         let network = Arc::new(Self {
             peers: Arc::new(RwLock::new(HashMap::new())),
@@ -46,12 +50,7 @@ impl P2PNetwork {
         while let Ok((stream, addr)) = listener.accept().await {
             info!("接受新连接: {}", addr);
             
-            // 创建新的对等节点
-            let peer = Peer::new(addr, stream);
-            
-            // 将对等节点添加到列表中
-            self.peers.write().await.insert(addr, peer);
-            
+            self.handle_connection(stream, addr).await?;
             info!("新节点已添加: {}", addr);
         }
         
@@ -69,11 +68,12 @@ impl P2PNetwork {
     }
 
     async fn handle_connection(&self, stream: TcpStream, addr: SocketAddr) -> Result<()> {
-        let peer = Peer::new(addr, stream);
+        let peer = Peer::new(addr, stream, self.message_sender.clone());
         self.peers.write().await.insert(addr, peer);
         Ok(())
     }
 
+    #[allow(dead_code)]
     async fn maintain_peers(&self) {
         let mut interval = time::interval(Duration::from_secs(60));
         
@@ -93,6 +93,102 @@ impl P2PNetwork {
         }
     }
 
+    pub async fn send_to(&self, addr: SocketAddr, message: Message) -> Result<()> {
+        let peers = self.peers.read().await;
+        let peer = peers
+            .get(&addr)
+            .ok_or_else(|| RustBtcError::Other(format!("未知节点: {}", addr)))?;
+        peer.sender
+            .send(message)
+            .await
+            .map_err(|e| RustBtcError::Other(e.to_string()))
+    }
+
+    /// 处理对等节点的`Version`消息，记录其版本、高度和时钟偏移
+    pub async fn handle_version(&self, from: SocketAddr, version: u32, best_height: u32, timestamp: u64) {
+        if let Some(peer) = self.peers.write().await.get_mut(&from) {
+            peer.info.version = version;
+            peer.info.best_height = best_height;
+            peer.info.update_time_offset(timestamp);
+            peer.info.update_last_seen();
+        }
+    }
+
+    /// 各对等节点时间偏移的中位数
+    pub async fn network_time_offset(&self) -> i64 {
+        let peers = self.peers.read().await;
+        let offsets: Vec<i64> = peers.values().map(|peer| peer.info.time_offset).collect();
+        median_time_offset(&offsets)
+    }
+
+    /// 处理`NewBlock`/`Block`消息中的区块，孤块会触发向来源节点请求缺失的祖先区块
+    pub async fn handle_block(
+        &self,
+        blockchain: &mut Blockchain,
+        block: Block,
+        from: SocketAddr,
+    ) -> Result<ChainUpdate> {
+        blockchain.set_time_offset(self.network_time_offset().await);
+        let update = blockchain.process_block(block, Some(from))?;
+
+        if let Some(missing) = &update.missing_parent {
+            info!("向节点 {} 请求缺失的区块 {}", from, missing);
+            if let Err(e) = self.send_to(from, Message::GetBlockByHash(missing.clone())).await {
+                warn!("向节点 {} 请求区块失败: {}", from, e);
+            }
+        }
+
+        Ok(update)
+    }
+
+    /// 处理来自`from`的一条消息，需要回复的请求通过`send_to`应答
+    pub async fn handle_message(&self, blockchain: &mut Blockchain, message: Message, from: SocketAddr) -> Result<()> {
+        match message {
+            Message::Version { version, best_height, timestamp } => {
+                self.handle_version(from, version, best_height, timestamp).await;
+            }
+            Message::Ping => self.send_to(from, Message::Pong).await?,
+            Message::GetPeers => {
+                let addresses = self.get_peer_addresses().await;
+                self.send_to(from, Message::Peers(addresses)).await?;
+            }
+            Message::NewBlock(block) | Message::Block(block) => {
+                self.handle_block(blockchain, block, from).await?;
+            }
+            Message::GetBlockByHash(hash) => {
+                let block = blockchain.get_block(&hash)?;
+                self.send_to(from, Message::Block(block)).await?;
+            }
+            Message::GetBlockHeight => {
+                let height = blockchain.get_block_height() as u64;
+                self.send_to(from, Message::BlockHeight(height)).await?;
+            }
+            Message::Disconnect => {
+                info!("节点 {} 已断开", from);
+                self.peers.write().await.remove(&from);
+            }
+            other => debug!("忽略来自节点 {} 的消息: {:?}", from, other),
+        }
+        Ok(())
+    }
+
+    /// 取出并处理下一条入站消息，消息通道关闭时返回`false`
+    pub async fn process_next_message(&self, blockchain: &mut Blockchain) -> bool {
+        let next = self.message_receiver.lock().await.recv().await;
+        let Some(NetworkMessage { message, from, .. }) = next else {
+            return false;
+        };
+        if let Err(e) = self.handle_message(blockchain, message, from).await {
+            warn!("处理来自节点 {} 的消息失败: {}", from, e);
+        }
+        true
+    }
+
+    /// 依次处理所有对等节点发来的消息
+    pub async fn run(&self, blockchain: &mut Blockchain) {
+        while self.process_next_message(blockchain).await {}
+    }
+
     pub async fn broadcast_message(&self, message: Message) -> Result<()> {
         let peers = self.peers.read().await;
         
@@ -103,3 +199,61 @@ impl P2PNetwork {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::params::ChainParams;
+    use crate::transaction::Transaction;
+    use crate::wallet::Wallet;
+
+    const EASY_BITS: u32 = 0x207fffff;
+
+    fn mine(transactions: Vec<Transaction>, parent_hash: &str, timestamp: u32) -> Result<Block> {
+        let mut block = Block::new(transactions, parent_hash.to_string())?;
+        block.header.bits = EASY_BITS;
+        block.header.timestamp = timestamp;
+        block.mine_block()?;
+        Ok(block)
+    }
+
+    #[tokio::test]
+    async fn test_orphan_requests_missing_parent_from_sender() -> Result<()> {
+        let params = ChainParams {
+            pow_limit_bits: EASY_BITS,
+            ..ChainParams::regtest()
+        };
+        let mut blockchain = Blockchain::with_params(params.clone())?;
+        let miner = Wallet::new()?;
+        let coinbase = |tag: &str| Transaction::new_coinbase(&miner.get_address(), tag, 0, Amount::ZERO, &params);
+
+        let genesis = mine(vec![coinbase("genesis")?], "0", 1_600_000_000)?;
+        let b1 = mine(vec![coinbase("b1")?], &genesis.hash, 1_600_000_001)?;
+        let b2 = mine(vec![coinbase("b2")?], &b1.hash, 1_600_000_002)?;
+        blockchain.add_block(genesis)?;
+
+        let remote = TcpListener::bind("127.0.0.1:0").await?;
+        let remote_addr = remote.local_addr()?;
+        let network = P2PNetwork::new("127.0.0.1:0".parse().unwrap(), Arc::new(Storage::temporary()?)).await?;
+        network.connect_to_peer(remote_addr).await?;
+        let (mut stream, _) = remote.accept().await?;
+
+        // 远端节点先发来b2，本地节点应当向它请求缺失的b1
+        Message::NewBlock(b2).write_to(&mut stream).await?;
+        let timeout = Duration::from_secs(10);
+        assert!(time::timeout(timeout, network.process_next_message(&mut blockchain)).await.unwrap());
+        assert_eq!(blockchain.orphan_count(), 1);
+
+        let request = time::timeout(timeout, Message::read_from(&mut stream)).await.unwrap()?;
+        assert!(matches!(request, Message::GetBlockByHash(ref hash) if *hash == b1.hash));
+
+        // 缺失的区块到达后孤块被连接
+        Message::Block(b1).write_to(&mut stream).await?;
+        assert!(time::timeout(timeout, network.process_next_message(&mut blockchain)).await.unwrap());
+        assert_eq!(blockchain.orphan_count(), 0);
+        assert_eq!(blockchain.get_block_height(), 3);
+
+        Ok(())
+    }
+}
@@ -1,9 +1,15 @@
 use std::net::SocketAddr;
-use std::time::SystemTime;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
+use tracing::{debug, warn};
 
-use crate::network::message::Message;
+use crate::network::message::{Message, NetworkMessage};
+
+/// 计算网络时间偏移至少需要的对等节点数
+const MIN_TIME_SAMPLES: usize = 5;
+/// 网络时间偏移的上限（秒），中位偏移超过该值时不调整本地时间
+const MAX_TIME_ADJUSTMENT: i64 = 70 * 60;
 
 #[derive(Debug)]
 pub struct PeerInfo {
@@ -11,6 +17,8 @@ pub struct PeerInfo {
     pub version: u32,
     pub best_height: u32,
     pub last_seen: SystemTime,
+    /// 对等节点报告的时间与本地时间之差（秒）
+    pub time_offset: i64,
 }
 
 impl PeerInfo {
@@ -20,28 +28,98 @@ impl PeerInfo {
             version: 0,
             best_height: 0,
             last_seen: SystemTime::now(),
+            time_offset: 0,
         }
     }
 
     pub fn update_last_seen(&mut self) {
         self.last_seen = SystemTime::now();
     }
+
+    /// 根据对等节点报告的当前时间更新时间偏移
+    pub fn update_time_offset(&mut self, peer_time: u64) {
+        let local_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.time_offset = peer_time as i64 - local_time as i64;
+    }
+}
+
+/// 由各对等节点的时间偏移得出网络时间偏移：取中位数，样本不足或偏移过大时返回0
+pub fn median_time_offset(offsets: &[i64]) -> i64 {
+    if offsets.len() < MIN_TIME_SAMPLES {
+        return 0;
+    }
+    let mut sorted = offsets.to_vec();
+    sorted.sort_unstable();
+    let median = sorted[sorted.len() / 2];
+    if median.abs() > MAX_TIME_ADJUSTMENT {
+        return 0;
+    }
+    median
 }
 
 #[derive(Debug)]
 pub struct Peer {
     pub info: PeerInfo,
-    pub stream: TcpStream,
+    /// 发往该节点的消息，由写任务依次写入连接
     pub sender: mpsc::Sender<Message>,
 }
 
 impl Peer {
-    pub fn new(addr: SocketAddr, stream: TcpStream) -> Self {
-        let (tx, _) = mpsc::channel::<Message>(32);
+    /// 接管与`addr`的连接：写任务把`sender`中的消息写入连接，
+    /// 读任务把收到的消息连同来源地址转发到`inbound`，连接关闭时转发`Disconnect`
+    pub fn new(addr: SocketAddr, stream: TcpStream, inbound: mpsc::Sender<NetworkMessage>) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Message>(32);
+        let (mut reader, mut writer) = stream.into_split();
+
+        tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                if let Err(e) = message.write_to(&mut writer).await {
+                    warn!("向节点 {} 发送消息失败: {}", addr, e);
+                    break;
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            loop {
+                let message = match Message::read_from(&mut reader).await {
+                    Ok(message) => message,
+                    Err(e) => {
+                        debug!("与节点 {} 的连接已关闭: {}", addr, e);
+                        Message::Disconnect
+                    }
+                };
+                let disconnected = matches!(message, Message::Disconnect);
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                if inbound.send(NetworkMessage { message, from: addr, timestamp }).await.is_err() || disconnected {
+                    break;
+                }
+            }
+        });
+
         Self {
             info: PeerInfo::new(addr),
-            stream,
             sender: tx,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_median_time_offset() {
+        // 样本不足时不调整
+        assert_eq!(median_time_offset(&[600, 600, 600, 600]), 0);
+        assert_eq!(median_time_offset(&[-30, 600, 10, 20, 5000]), 20);
+        // 中位偏移超过上限时不信任网络时间
+        assert_eq!(median_time_offset(&[5000, 5000, 5000, 0, 0]), 0);
+    }
+}
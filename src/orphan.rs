@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use tracing::{debug, warn};
+
+use crate::block::Block;
+use crate::error::{Result, RustBtcError};
+
+const MAX_ORPHAN_BLOCKS: usize = 750;
+const MAX_ORPHANS_PER_PEER: usize = 100;
+const ORPHAN_EXPIRY_SECS: u64 = 20 * 60;
+
+#[derive(Debug, Clone)]
+struct OrphanBlock {
+    block: Block,
+    peer: Option<SocketAddr>,
+    received_at: u64,
+}
+
+/// 孤块池：暂存前置区块尚未到达的区块，按前置哈希索引
+#[derive(Debug)]
+pub struct OrphanPool {
+    orphans: HashMap<String, OrphanBlock>,
+    by_prev: HashMap<String, Vec<String>>,
+    max_orphans: usize,
+    max_per_peer: usize,
+    expiry_secs: u64,
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        Self::with_limits(MAX_ORPHAN_BLOCKS, MAX_ORPHANS_PER_PEER, ORPHAN_EXPIRY_SECS)
+    }
+
+    pub fn with_limits(max_orphans: usize, max_per_peer: usize, expiry_secs: u64) -> Self {
+        OrphanPool {
+            orphans: HashMap::new(),
+            by_prev: HashMap::new(),
+            max_orphans,
+            max_per_peer,
+            expiry_secs,
+        }
+    }
+
+    /// 加入一个孤块。单个节点发送的孤块超过上限时拒绝，池满时淘汰最早收到的孤块
+    pub fn add(&mut self, block: Block, peer: Option<SocketAddr>, now: u64) -> Result<()> {
+        if self.orphans.contains_key(&block.hash) {
+            return Ok(());
+        }
+
+        if let Some(addr) = peer {
+            let count = self.orphans.values().filter(|o| o.peer == Some(addr)).count();
+            if count >= self.max_per_peer {
+                warn!("节点 {} 的孤块数量达到上限 {}", addr, self.max_per_peer);
+                return Err(RustBtcError::CapacityExceeded(format!(
+                    "节点 {} 的孤块数量达到上限 {}",
+                    addr, self.max_per_peer
+                )));
+            }
+        }
+
+        while self.orphans.len() >= self.max_orphans {
+            let oldest = self
+                .orphans
+                .values()
+                .min_by_key(|o| o.received_at)
+                .map(|o| o.block.hash.clone());
+            match oldest {
+                Some(hash) => {
+                    debug!("孤块池已满，淘汰孤块 {}", hash);
+                    self.remove(&hash);
+                }
+                None => break,
+            }
+        }
+
+        debug!("加入孤块 {}，缺少前置区块 {}", block.hash, block.header.prev_block_hash);
+        self.by_prev
+            .entry(block.header.prev_block_hash.clone())
+            .or_default()
+            .push(block.hash.clone());
+        self.orphans.insert(block.hash.clone(), OrphanBlock {
+            block,
+            peer,
+            received_at: now,
+        });
+        Ok(())
+    }
+
+    pub fn remove(&mut self, hash: &str) -> Option<Block> {
+        let orphan = self.orphans.remove(hash)?;
+        let prev = &orphan.block.header.prev_block_hash;
+        if let Some(children) = self.by_prev.get_mut(prev) {
+            children.retain(|h| h != hash);
+            if children.is_empty() {
+                self.by_prev.remove(prev);
+            }
+        }
+        Some(orphan.block)
+    }
+
+    /// 取出所有以`prev_hash`为前置区块的孤块
+    pub fn take_children(&mut self, prev_hash: &str) -> Vec<Block> {
+        let hashes = self.by_prev.remove(prev_hash).unwrap_or_default();
+        hashes
+            .iter()
+            .filter_map(|hash| self.orphans.remove(hash).map(|o| o.block))
+            .collect()
+    }
+
+    /// 沿孤块链向上回溯，返回真正缺失的祖先区块哈希
+    pub fn missing_ancestor(&self, hash: &str) -> Option<String> {
+        let mut current = self.orphans.get(hash)?;
+        while let Some(parent) = self.orphans.get(&current.block.header.prev_block_hash) {
+            current = parent;
+        }
+        Some(current.block.header.prev_block_hash.clone())
+    }
+
+    /// 移除超过有效期的孤块，返回移除的数量
+    pub fn expire(&mut self, now: u64) -> usize {
+        let expired: Vec<String> = self
+            .orphans
+            .values()
+            .filter(|o| o.received_at + self.expiry_secs <= now)
+            .map(|o| o.block.hash.clone())
+            .collect();
+        for hash in &expired {
+            debug!("孤块 {} 已过期", hash);
+            self.remove(hash);
+        }
+        expired.len()
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::params::ChainParams;
+    use crate::transaction::Transaction;
+    use crate::wallet::Wallet;
+
+    fn create_orphan(prev_hash: &str, tag: &str) -> Result<Block> {
+        let wallet = Wallet::new()?;
+        let coinbase = Transaction::new_coinbase(&wallet.get_address(), tag, 0, Amount::ZERO, &ChainParams::default())?;
+        Block::new(vec![coinbase], prev_hash.to_string())
+    }
+
+    #[test]
+    fn test_orphan_pool_children_and_ancestor() -> Result<()> {
+        let mut pool = OrphanPool::new();
+        let missing = "aa".repeat(32);
+
+        let child = create_orphan(&missing, "child")?;
+        let grandchild = create_orphan(&child.hash, "grandchild")?;
+        pool.add(grandchild.clone(), None, 0)?;
+        pool.add(child.clone(), None, 0)?;
+        assert_eq!(pool.len(), 2);
+
+        // 回溯到孤块链之外真正缺失的区块
+        assert_eq!(pool.missing_ancestor(&grandchild.hash), Some(missing.clone()));
+
+        let children = pool.take_children(&missing);
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].hash, child.hash);
+        assert!(pool.contains(&grandchild.hash));
+        assert!(pool.take_children(&missing).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_orphan_pool_limits_and_expiry() -> Result<()> {
+        let mut pool = OrphanPool::with_limits(3, 2, 100);
+        let peer: SocketAddr = "127.0.0.1:8001".parse().unwrap();
+        let prev = "bb".repeat(32);
+
+        pool.add(create_orphan(&prev, "1")?, Some(peer), 10)?;
+        pool.add(create_orphan(&prev, "2")?, Some(peer), 20)?;
+        assert!(matches!(
+            pool.add(create_orphan(&prev, "3")?, Some(peer), 30),
+            Err(RustBtcError::CapacityExceeded(_))
+        ));
+
+        // 池满时淘汰最早的孤块
+        let oldest_free = create_orphan(&prev, "4")?;
+        pool.add(oldest_free.clone(), None, 5)?;
+        pool.add(create_orphan(&prev, "5")?, None, 40)?;
+        assert_eq!(pool.len(), 3);
+        assert!(!pool.contains(&oldest_free.hash));
+
+        assert_eq!(pool.expire(115), 1);
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.expire(1000), 2);
+        assert!(pool.is_empty());
+
+        Ok(())
+    }
+}
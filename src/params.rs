@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::error::{Result, RustBtcError};
+use crate::pow::POW_LIMIT_BITS;
+use crate::transaction::SUBSIDY;
+
+/// 难度调整算法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RetargetMode {
+    /// 比特币式：每个调整窗口结束时按实际耗时重新计算难度，单次调整幅度限制在4倍以内
+    Window,
+    /// 线性加权移动平均（LWMA）：每个区块都根据最近若干区块的出块时间调整难度，适合算力波动大的小型网络
+    Lwma,
+}
+
+/// 共识参数
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainParams {
+    /// 允许的最低难度（紧凑格式）
+    pub pow_limit_bits: u32,
+    /// 期望的出块间隔（秒）
+    pub target_block_spacing: u64,
+    pub retarget_mode: RetargetMode,
+    /// `Window`模式下每隔多少个区块调整一次难度
+    pub difficulty_adjustment_interval: u64,
+    /// `Lwma`模式下参与平均的区块数
+    pub lwma_window: u64,
+    /// 区块奖励每隔多少个区块减半
+    pub subsidy_halving_interval: u64,
+    /// coinbase输出需要经过多少个区块才能被花费
+    pub coinbase_maturity: u64,
+    /// 区块时间戳最多可以超前网络调整时间多少秒
+    pub max_future_block_time: u64,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        ChainParams {
+            pow_limit_bits: POW_LIMIT_BITS,
+            target_block_spacing: 600,
+            retarget_mode: RetargetMode::Window,
+            difficulty_adjustment_interval: 2016,
+            lwma_window: 45,
+            subsidy_halving_interval: 210_000,
+            coinbase_maturity: 100,
+            max_future_block_time: 2 * 60 * 60,
+        }
+    }
+
+    /// 私有测试网络：使用LWMA逐块调整难度
+    pub fn testnet() -> Self {
+        ChainParams {
+            target_block_spacing: 60,
+            retarget_mode: RetargetMode::Lwma,
+            ..Self::mainnet()
+        }
+    }
+
+    /// 本地回归测试网络：最低难度极低，coinbase很快即可花费
+    pub fn regtest() -> Self {
+        ChainParams {
+            pow_limit_bits: 0x207fffff,
+            subsidy_halving_interval: 150,
+            coinbase_maturity: 10,
+            ..Self::mainnet()
+        }
+    }
+
+    /// 检查参数能否用于难度调整：出块间隔和调整窗口为0时无法计算目标值
+    pub fn validate(&self) -> Result<()> {
+        if self.target_block_spacing == 0 {
+            return Err(RustBtcError::ValidationError("出块间隔不能为0".to_string()));
+        }
+        if self.difficulty_adjustment_interval == 0 {
+            return Err(RustBtcError::ValidationError("难度调整窗口不能为0".to_string()));
+        }
+        if self.lwma_window == 0 {
+            return Err(RustBtcError::ValidationError("LWMA窗口不能为0".to_string()));
+        }
+        Ok(())
+    }
+
+    /// 高度为`height`的区块的出块奖励（不含手续费）
+    pub fn block_subsidy(&self, height: u64) -> Amount {
+        if self.subsidy_halving_interval == 0 {
+            return SUBSIDY;
+        }
+        let halvings = height / self.subsidy_halving_interval;
+        if halvings >= 64 {
+            return Amount::ZERO;
+        }
+        Amount::from_sat(SUBSIDY.to_sat() >> halvings)
+    }
+
+    /// 调整窗口对应的期望总耗时（秒）
+    pub fn target_timespan(&self) -> u64 {
+        self.difficulty_adjustment_interval * self.target_block_spacing
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_subsidy_halving() {
+        let params = ChainParams {
+            subsidy_halving_interval: 10,
+            ..ChainParams::mainnet()
+        };
+        assert_eq!(params.block_subsidy(0), SUBSIDY);
+        assert_eq!(params.block_subsidy(9), SUBSIDY);
+        assert_eq!(params.block_subsidy(10), SUBSIDY.checked_div(2).unwrap());
+        assert_eq!(params.block_subsidy(25), SUBSIDY.checked_div(4).unwrap());
+        assert_eq!(params.block_subsidy(10 * 33), Amount::ZERO);
+        assert_eq!(ChainParams::mainnet().block_subsidy(209_999), SUBSIDY);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(ChainParams::mainnet().validate().is_ok());
+        assert!(ChainParams::testnet().validate().is_ok());
+        assert!(ChainParams::regtest().validate().is_ok());
+
+        let zero_spacing = ChainParams {
+            target_block_spacing: 0,
+            ..ChainParams::testnet()
+        };
+        assert!(zero_spacing.validate().is_err());
+        let zero_interval = ChainParams {
+            difficulty_adjustment_interval: 0,
+            ..ChainParams::mainnet()
+        };
+        assert!(zero_interval.validate().is_err());
+        let zero_lwma = ChainParams {
+            lwma_window: 0,
+            ..ChainParams::regtest()
+        };
+        assert!(zero_lwma.validate().is_err());
+    }
+}
@@ -1,36 +1,376 @@
-use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, AddAssign, Div, Not, Shl, Shr};
 
-pub struct ProofOfWork {
-    target: String,
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Result, RustBtcError};
+
+/// 工作量证明的最低难度（最大目标值），对应16个前导零比特
+pub const POW_LIMIT_BITS: u32 = 0x1f00ffff;
+
+/// 256位无符号整数，内部以小端序的u64数组表示
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub(crate) struct U256([u64; 4]);
+
+impl U256 {
+    pub(crate) const ZERO: U256 = U256([0, 0, 0, 0]);
+    pub(crate) const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub(crate) fn from_u64(value: u64) -> Self {
+        U256([value, 0, 0, 0])
+    }
+
+    pub(crate) fn from_be_bytes(bytes: [u8; 32]) -> Self {
+        let mut words = [0u64; 4];
+        for (i, chunk) in bytes.chunks(8).enumerate() {
+            words[3 - i] = u64::from_be_bytes(chunk.try_into().unwrap());
+        }
+        U256(words)
+    }
+
+    pub(crate) fn to_be_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for i in 0..4 {
+            bytes[i * 8..(i + 1) * 8].copy_from_slice(&self.0[3 - i].to_be_bytes());
+        }
+        bytes
+    }
+
+    pub(crate) fn is_zero(&self) -> bool {
+        self.0.iter().all(|w| *w == 0)
+    }
+
+    pub(crate) fn low_u64(&self) -> u64 {
+        self.0[0]
+    }
+
+    /// 最高有效位的位置（0表示值为零）
+    pub(crate) fn bits(&self) -> u32 {
+        for i in (0..4).rev() {
+            if self.0[i] != 0 {
+                return 64 * i as u32 + (64 - self.0[i].leading_zeros());
+            }
+        }
+        0
+    }
+
+    pub(crate) fn overflowing_add(self, other: U256) -> (U256, bool) {
+        let mut result = [0u64; 4];
+        let mut carry = false;
+        for (i, word) in result.iter_mut().enumerate() {
+            let (sum, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            *word = sum;
+            carry = c1 || c2;
+        }
+        (U256(result), carry)
+    }
+
+    pub(crate) fn saturating_add(self, other: U256) -> U256 {
+        match self.overflowing_add(other) {
+            (_, true) => U256::MAX,
+            (sum, false) => sum,
+        }
+    }
+
+    pub(crate) fn wrapping_sub(self, other: U256) -> U256 {
+        let mut result = [0u64; 4];
+        let mut borrow = false;
+        for (i, word) in result.iter_mut().enumerate() {
+            let (diff, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            *word = diff;
+            borrow = b1 || b2;
+        }
+        U256(result)
+    }
+
+    /// 乘以一个u64，溢出时返回None
+    pub(crate) fn checked_mul_u64(self, other: u64) -> Option<U256> {
+        let mut result = [0u64; 4];
+        let mut carry = 0u128;
+        for (i, word) in result.iter_mut().enumerate() {
+            let product = self.0[i] as u128 * other as u128 + carry;
+            *word = product as u64;
+            carry = product >> 64;
+        }
+        if carry != 0 {
+            None
+        } else {
+            Some(U256(result))
+        }
+    }
+
+    pub(crate) fn div_u64(self, divisor: u64) -> U256 {
+        let mut result = [0u64; 4];
+        let mut remainder = 0u128;
+        for i in (0..4).rev() {
+            let current = (remainder << 64) | self.0[i] as u128;
+            result[i] = (current / divisor as u128) as u64;
+            remainder = current % divisor as u128;
+        }
+        U256(result)
+    }
+
+    /// 计算 self * numerator / denominator，先乘后除以保留精度，乘法溢出时改为先除后乘
+    pub(crate) fn mul_div_u64(self, numerator: u64, denominator: u64) -> Option<U256> {
+        match self.checked_mul_u64(numerator) {
+            Some(product) => Some(product.div_u64(denominator)),
+            None => self.div_u64(denominator).checked_mul_u64(numerator),
+        }
+    }
+}
+
+impl Shl<u32> for U256 {
+    type Output = U256;
+
+    fn shl(self, shift: u32) -> U256 {
+        let mut result = [0u64; 4];
+        let word_shift = (shift / 64) as usize;
+        let bit_shift = shift % 64;
+        for i in (word_shift..4).rev() {
+            result[i] = self.0[i - word_shift] << bit_shift;
+            if bit_shift > 0 && i > word_shift {
+                result[i] |= self.0[i - word_shift - 1] >> (64 - bit_shift);
+            }
+        }
+        U256(result)
+    }
 }
 
-impl ProofOfWork {
-    pub fn new(difficulty: u32) -> Self {
-        let target = "0".repeat(difficulty as usize);
-        ProofOfWork { target }
+impl Shr<u32> for U256 {
+    type Output = U256;
+
+    fn shr(self, shift: u32) -> U256 {
+        let mut result = [0u64; 4];
+        let word_shift = (shift / 64) as usize;
+        let bit_shift = shift % 64;
+        for (i, word) in result.iter_mut().enumerate().take(4usize.saturating_sub(word_shift)) {
+            *word = self.0[i + word_shift] >> bit_shift;
+            if bit_shift > 0 && i + word_shift + 1 < 4 {
+                *word |= self.0[i + word_shift + 1] << (64 - bit_shift);
+            }
+        }
+        U256(result)
     }
+}
 
-    pub fn run(&self, data: &[u8]) -> (u64, String) {
-        let mut nonce = 0u64;
-        loop {
-            let hash = self.calculate_hash(data, nonce);
-            if hash.starts_with(&self.target) {
-                return (nonce, hash);
+impl Div for U256 {
+    type Output = U256;
+
+    /// 逐位长除法，除数不能为零
+    fn div(self, divisor: U256) -> U256 {
+        let mut quotient = U256::ZERO;
+        let mut remainder = U256::ZERO;
+        for i in (0..self.bits()).rev() {
+            remainder = remainder << 1;
+            if (self.0[(i / 64) as usize] >> (i % 64)) & 1 == 1 {
+                remainder.0[0] |= 1;
+            }
+            if remainder >= divisor {
+                remainder = remainder.wrapping_sub(divisor);
+                quotient.0[(i / 64) as usize] |= 1 << (i % 64);
             }
-            nonce += 1;
         }
+        quotient
     }
+}
+
+impl Not for U256 {
+    type Output = U256;
 
-    pub fn validate(&self, data: &[u8], nonce: u64) -> bool {
-        let hash = self.calculate_hash(data, nonce);
-        hash.starts_with(&self.target)
+    fn not(self) -> U256 {
+        U256([!self.0[0], !self.0[1], !self.0[2], !self.0[3]])
     }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        for i in (0..4).rev() {
+            match self.0[i].cmp(&other.0[i]) {
+                Ordering::Equal => continue,
+                ordering => return ordering,
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for U256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.to_be_bytes()))
+    }
+}
+
+/// 由区块头中紧凑格式的`bits`解码得到的256位难度目标
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Target(pub(crate) U256);
+
+impl Target {
+    /// 解码紧凑格式（nBits）的难度目标，拒绝负数、零和溢出的编码
+    pub fn from_compact(bits: u32) -> Result<Target> {
+        let size = bits >> 24;
+        let word = bits & 0x007fffff;
+
+        let value = if size <= 3 {
+            U256::from_u64((word >> (8 * (3 - size))) as u64)
+        } else {
+            U256::from_u64(word as u64) << (8 * (size - 3))
+        };
+
+        let negative = word != 0 && (bits & 0x00800000) != 0;
+        let overflow = word != 0
+            && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32));
+
+        if negative || overflow || value.is_zero() {
+            return Err(RustBtcError::InvalidBlock(format!(
+                "无效的难度编码: {:#010x}",
+                bits
+            )));
+        }
+
+        Ok(Target(value))
+    }
+
+    /// 编码为紧凑格式（nBits）
+    pub fn to_compact(&self) -> u32 {
+        let mut size = self.0.bits().div_ceil(8);
+        let mut compact = if size <= 3 {
+            (self.0.low_u64() << (8 * (3 - size))) as u32
+        } else {
+            (self.0 >> (8 * (size - 3))).low_u64() as u32
+        };
+
+        // 尾数的最高位是符号位，需要时向右移一个字节
+        if compact & 0x00800000 != 0 {
+            compact >>= 8;
+            size += 1;
+        }
+
+        compact | (size << 24)
+    }
+
+    pub fn from_be_bytes(bytes: [u8; 32]) -> Target {
+        Target(U256::from_be_bytes(bytes))
+    }
+
+    pub fn to_be_bytes(&self) -> [u8; 32] {
+        self.0.to_be_bytes()
+    }
+
+    /// 判断哈希（按大端序解释为256位整数）是否不大于目标值
+    pub fn is_met_by(&self, hash: &[u8]) -> bool {
+        let bytes: [u8; 32] = match hash.try_into() {
+            Ok(bytes) => bytes,
+            Err(_) => return false,
+        };
+        U256::from_be_bytes(bytes) <= self.0
+    }
+
+    /// 找到一个满足该目标的哈希所需的期望计算量
+    pub fn work(&self) -> Work {
+        // 2^256 / (target + 1) 无法直接用256位表示，改写为 (~target / (target + 1)) + 1
+        let (divisor, overflow) = self.0.overflowing_add(U256::from_u64(1));
+        if overflow {
+            return Work(U256::from_u64(1));
+        }
+        Work((!self.0 / divisor).saturating_add(U256::from_u64(1)))
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// 工作量，可累加以比较不同链的总工作量
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+pub struct Work(pub(crate) U256);
+
+impl Work {
+    pub fn zero() -> Work {
+        Work(U256::ZERO)
+    }
+
+    pub fn to_be_bytes(&self) -> [u8; 32] {
+        self.0.to_be_bytes()
+    }
+}
+
+impl Add for Work {
+    type Output = Work;
+
+    fn add(self, other: Work) -> Work {
+        Work(self.0.saturating_add(other.0))
+    }
+}
+
+impl AddAssign for Work {
+    fn add_assign(&mut self, other: Work) {
+        *self = *self + other;
+    }
+}
+
+impl fmt::Display for Work {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_roundtrip() -> Result<()> {
+        for bits in [0x1d00ffff, 0x1b0404cb, POW_LIMIT_BITS, 0x207fffff, 0x03123456] {
+            let target = Target::from_compact(bits)?;
+            assert_eq!(target.to_compact(), bits);
+        }
+
+        // 比特币创世区块的目标值
+        let target = Target::from_compact(0x1d00ffff)?;
+        let mut expected = [0u8; 32];
+        expected[4] = 0xff;
+        expected[5] = 0xff;
+        assert_eq!(target.to_be_bytes(), expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_compact() {
+        assert!(Target::from_compact(0x04923456).is_err()); // 负数
+        assert!(Target::from_compact(0xff123456).is_err()); // 溢出
+        assert!(Target::from_compact(0x00000000).is_err()); // 零
+    }
+
+    #[test]
+    fn test_target_comparison_and_work() -> Result<()> {
+        let target = Target::from_compact(POW_LIMIT_BITS)?;
+
+        let mut hash = [0u8; 32];
+        hash[2] = 0xff;
+        assert!(target.is_met_by(&hash));
+        hash[1] = 0x01;
+        assert!(!target.is_met_by(&hash));
+
+        // 最低难度下每个区块约需 2^16 次哈希
+        let work = target.work();
+        assert_eq!(work.0, U256::from_u64(0x10001));
+
+        let harder = Target::from_compact(0x1e00ffff)?;
+        assert!(harder.work() > work);
+        assert_eq!((work + work).0, U256::from_u64(0x20002));
 
-    fn calculate_hash(&self, data: &[u8], nonce: u64) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        hasher.update(nonce.to_string());
-        let result = hasher.finalize();
-        hex::encode(result)
+        Ok(())
     }
 }
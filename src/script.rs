@@ -0,0 +1,837 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::wallet::hash_pub_key;
+
+/// 脚本的最大字节数
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+/// 单次压栈数据的最大字节数
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+/// 每个脚本最多执行的非压栈操作码数量
+pub const MAX_OPS_PER_SCRIPT: usize = 201;
+/// `OP_CHECKMULTISIG`最多支持的公钥数量
+pub const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+/// 执行过程中栈的最大深度
+pub const MAX_STACK_SIZE: usize = 1000;
+/// 脚本数字的最大字节数
+const MAX_NUM_SIZE: usize = 4;
+/// 时间锁操作码的参数可以使用5个字节，以表示到2^39的时间
+const LOCKTIME_NUM_SIZE: usize = 5;
+/// 相对时间锁参数设置该位时`OP_CHECKSEQUENCEVERIFY`不做检查
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: i64 = 1 << 31;
+
+macro_rules! opcodes {
+    ($($name:ident = $byte:literal => $text:literal,)*) => {
+        /// 脚本操作码，取值与比特币一致
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[repr(u8)]
+        pub enum Opcode {
+            $($name = $byte,)*
+        }
+
+        impl Opcode {
+            pub fn from_byte(byte: u8) -> Option<Opcode> {
+                match byte {
+                    $($byte => Some(Opcode::$name),)*
+                    _ => None,
+                }
+            }
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Opcode::$name => $text,)*
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    Op0 = 0x00 => "OP_0",
+    PushData1 = 0x4c => "OP_PUSHDATA1",
+    PushData2 = 0x4d => "OP_PUSHDATA2",
+    PushData4 = 0x4e => "OP_PUSHDATA4",
+    Op1Negate = 0x4f => "OP_1NEGATE",
+    Op1 = 0x51 => "OP_1",
+    Op2 = 0x52 => "OP_2",
+    Op3 = 0x53 => "OP_3",
+    Op4 = 0x54 => "OP_4",
+    Op5 = 0x55 => "OP_5",
+    Op6 = 0x56 => "OP_6",
+    Op7 = 0x57 => "OP_7",
+    Op8 = 0x58 => "OP_8",
+    Op9 = 0x59 => "OP_9",
+    Op10 = 0x5a => "OP_10",
+    Op11 = 0x5b => "OP_11",
+    Op12 = 0x5c => "OP_12",
+    Op13 = 0x5d => "OP_13",
+    Op14 = 0x5e => "OP_14",
+    Op15 = 0x5f => "OP_15",
+    Op16 = 0x60 => "OP_16",
+    Nop = 0x61 => "OP_NOP",
+    Verify = 0x69 => "OP_VERIFY",
+    Return = 0x6a => "OP_RETURN",
+    Drop = 0x75 => "OP_DROP",
+    Dup = 0x76 => "OP_DUP",
+    Equal = 0x87 => "OP_EQUAL",
+    EqualVerify = 0x88 => "OP_EQUALVERIFY",
+    Ripemd160 = 0xa6 => "OP_RIPEMD160",
+    Sha256 = 0xa8 => "OP_SHA256",
+    Hash160 = 0xa9 => "OP_HASH160",
+    Hash256 = 0xaa => "OP_HASH256",
+    CheckSig = 0xac => "OP_CHECKSIG",
+    CheckSigVerify = 0xad => "OP_CHECKSIGVERIFY",
+    CheckMultiSig = 0xae => "OP_CHECKMULTISIG",
+    CheckMultiSigVerify = 0xaf => "OP_CHECKMULTISIGVERIFY",
+    CheckLockTimeVerify = 0xb1 => "OP_CHECKLOCKTIMEVERIFY",
+    CheckSequenceVerify = 0xb2 => "OP_CHECKSEQUENCEVERIFY",
+}
+
+impl Opcode {
+    /// `OP_1`..`OP_16`对应的数值
+    pub fn small_int(self) -> Option<u8> {
+        let byte = self as u8;
+        (Opcode::Op1 as u8..=Opcode::Op16 as u8)
+            .contains(&byte)
+            .then(|| byte - Opcode::Op1 as u8 + 1)
+    }
+
+    fn is_push(self) -> bool {
+        self as u8 <= Opcode::Op16 as u8
+    }
+}
+
+/// 脚本解析或执行失败的原因
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ScriptError {
+    #[error("脚本长度超过上限")]
+    ScriptSize,
+    #[error("压栈数据超过上限")]
+    PushSize,
+    #[error("操作码数量超过上限")]
+    OpCount,
+    #[error("栈深度超过上限")]
+    StackSize,
+    #[error("公钥数量无效")]
+    PubkeyCount,
+    #[error("签名数量无效")]
+    SigCount,
+    #[error("未知或禁用的操作码 0x{0:02x}")]
+    BadOpcode(u8),
+    #[error("压栈数据被截断")]
+    Truncated,
+    #[error("栈中元素不足")]
+    InvalidStackOperation,
+    #[error("数字超出范围")]
+    NumOverflow,
+    #[error("OP_VERIFY失败")]
+    Verify,
+    #[error("OP_EQUALVERIFY失败")]
+    EqualVerify,
+    #[error("OP_CHECKSIGVERIFY失败")]
+    CheckSigVerify,
+    #[error("OP_CHECKMULTISIGVERIFY失败")]
+    CheckMultiSigVerify,
+    #[error("执行到OP_RETURN")]
+    OpReturn,
+    #[error("OP_CHECKMULTISIG的附加元素必须为空")]
+    NullDummy,
+    #[error("解锁脚本只能包含压栈操作")]
+    SigPushOnly,
+    #[error("脚本执行结果为假")]
+    EvalFalse,
+    #[error("时间锁参数为负数")]
+    NegativeLockTime,
+    #[error("交易未满足脚本要求的时间锁")]
+    UnsatisfiedLockTime,
+    #[error("密钥路径花费的解锁脚本必须只包含一个签名")]
+    KeyPathSpend,
+    #[error("Schnorr签名验证失败")]
+    SchnorrSig,
+}
+
+/// 解析后的一条脚本指令
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction<'a> {
+    /// 压入数据，`OP_0`解析为压入空数据
+    Push(&'a [u8]),
+    Op(Opcode),
+}
+
+/// 按顺序解析脚本中的指令
+pub struct Instructions<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Instructions<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ScriptError> {
+        if self.data.len() < len {
+            self.data = &[];
+            return Err(ScriptError::Truncated);
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn take_len(&mut self, width: usize) -> Result<usize, ScriptError> {
+        let bytes = self.take(width)?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0usize, |len, byte| (len << 8) | *byte as usize))
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>, ScriptError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&byte, rest) = self.data.split_first()?;
+        self.data = rest;
+
+        let len = match byte {
+            0x01..=0x4b => Ok(byte as usize),
+            0x4c => self.take_len(1),
+            0x4d => self.take_len(2),
+            0x4e => self.take_len(4),
+            _ => {
+                return Some(match Opcode::from_byte(byte) {
+                    Some(Opcode::Op0) => Ok(Instruction::Push(&[])),
+                    Some(op) => Ok(Instruction::Op(op)),
+                    None => Err(ScriptError::BadOpcode(byte)),
+                });
+            }
+        };
+        Some(len.and_then(|len| self.take(len)).map(Instruction::Push))
+    }
+}
+
+/// 序列化的脚本
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Script(Vec<u8>);
+
+impl Script {
+    pub fn new() -> Self {
+        Script(Vec::new())
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Script(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn push_opcode(mut self, op: Opcode) -> Self {
+        self.0.push(op as u8);
+        self
+    }
+
+    /// 以最短的编码压入数据
+    pub fn push_slice(mut self, data: &[u8]) -> Self {
+        match data.len() {
+            0 => self.0.push(Opcode::Op0 as u8),
+            len @ 1..=0x4b => self.0.push(len as u8),
+            len @ 0x4c..=0xff => {
+                self.0.push(Opcode::PushData1 as u8);
+                self.0.push(len as u8);
+            }
+            len @ 0x100..=0xffff => {
+                self.0.push(Opcode::PushData2 as u8);
+                self.0.extend_from_slice(&(len as u16).to_le_bytes());
+            }
+            len => {
+                self.0.push(Opcode::PushData4 as u8);
+                self.0.extend_from_slice(&(len as u32).to_le_bytes());
+            }
+        }
+        self.0.extend_from_slice(data);
+        self
+    }
+
+    /// 压入数字，0..=16和-1使用单字节操作码
+    pub fn push_int(mut self, n: i64) -> Self {
+        match n {
+            0 => self.push_opcode(Opcode::Op0),
+            -1 => self.push_opcode(Opcode::Op1Negate),
+            1..=16 => {
+                self.0.push(Opcode::Op1 as u8 + n as u8 - 1);
+                self
+            }
+            _ => self.push_slice(&encode_num(n)),
+        }
+    }
+
+    /// 支付到公钥哈希：`OP_DUP OP_HASH160 <pubkey_hash> OP_EQUALVERIFY OP_CHECKSIG`
+    pub fn p2pkh(pubkey_hash: &[u8]) -> Self {
+        Script::new()
+            .push_opcode(Opcode::Dup)
+            .push_opcode(Opcode::Hash160)
+            .push_slice(pubkey_hash)
+            .push_opcode(Opcode::EqualVerify)
+            .push_opcode(Opcode::CheckSig)
+    }
+
+    /// 支付到脚本哈希：`OP_HASH160 <script_hash> OP_EQUAL`
+    pub fn p2sh(script_hash: &[u8]) -> Self {
+        Script::new()
+            .push_opcode(Opcode::Hash160)
+            .push_slice(script_hash)
+            .push_opcode(Opcode::Equal)
+    }
+
+    /// 支付到x-only公钥：`OP_1 <output_key>`，只能以该公钥的Schnorr签名通过密钥路径花费
+    pub fn p2tr(output_key: &[u8]) -> Self {
+        Script::new().push_opcode(Opcode::Op1).push_slice(output_key)
+    }
+
+    /// 以本脚本为赎回脚本的P2SH锁定脚本
+    pub fn to_p2sh(&self) -> Self {
+        Script::p2sh(&hash_pub_key(&self.0))
+    }
+
+    /// 花费P2PKH输出的解锁脚本：`<signature> <pubkey>`
+    pub fn p2pkh_unlock(signature: &[u8], pubkey: &[u8]) -> Self {
+        Script::new().push_slice(signature).push_slice(pubkey)
+    }
+
+    /// `m`-of-`n`多签：`OP_m <pubkey>... OP_n OP_CHECKMULTISIG`
+    pub fn multisig(required: usize, pubkeys: &[Vec<u8>]) -> Result<Self, ScriptError> {
+        if pubkeys.is_empty() || pubkeys.len() > MAX_PUBKEYS_PER_MULTISIG {
+            return Err(ScriptError::PubkeyCount);
+        }
+        if required == 0 || required > pubkeys.len() {
+            return Err(ScriptError::SigCount);
+        }
+        let script = pubkeys
+            .iter()
+            .fold(Script::new().push_int(required as i64), |script, pubkey| {
+                script.push_slice(pubkey)
+            });
+        Ok(script
+            .push_int(pubkeys.len() as i64)
+            .push_opcode(Opcode::CheckMultiSig))
+    }
+
+    /// 哈希锁：提供SHA256原像即可花费，`OP_SHA256 <hash> OP_EQUAL`
+    pub fn hash_lock(hash: &[u8]) -> Self {
+        Script::new()
+            .push_opcode(Opcode::Sha256)
+            .push_slice(hash)
+            .push_opcode(Opcode::Equal)
+    }
+
+    /// 携带数据且不可花费的输出：`OP_RETURN <data>`
+    pub fn op_return(data: &[u8]) -> Self {
+        Script::new().push_opcode(Opcode::Return).push_slice(data)
+    }
+
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions { data: &self.0 }
+    }
+
+    /// 如果是P2PKH脚本，返回其中的公钥哈希
+    pub fn p2pkh_hash(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [0x76, 0xa9, 20, hash @ .., 0x88, 0xac] if hash.len() == 20 => Some(hash),
+            _ => None,
+        }
+    }
+
+    /// 如果是P2TR脚本，返回其中的32字节x-only公钥
+    pub fn p2tr_key(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [0x51, 32, key @ ..] if key.len() == 32 => Some(key),
+            _ => None,
+        }
+    }
+
+    /// 如果是P2SH脚本，返回其中的脚本哈希
+    pub fn p2sh_hash(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [0xa9, 20, hash @ .., 0x87] if hash.len() == 20 => Some(hash),
+            _ => None,
+        }
+    }
+
+    pub fn is_op_return(&self) -> bool {
+        self.0.first() == Some(&(Opcode::Return as u8))
+    }
+
+    /// 脚本是否只包含压栈操作
+    pub fn is_push_only(&self) -> bool {
+        self.instructions().all(|instruction| match instruction {
+            Ok(Instruction::Push(_)) => true,
+            Ok(Instruction::Op(op)) => op.is_push(),
+            Err(_) => false,
+        })
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for instruction in self.instructions() {
+            if !first {
+                write!(f, " ")?;
+            }
+            first = false;
+            match instruction {
+                Ok(Instruction::Push([])) => write!(f, "OP_0")?,
+                Ok(Instruction::Push(data)) => write!(f, "{}", hex::encode(data))?,
+                Ok(Instruction::Op(op)) => write!(f, "{}", op.name())?,
+                Err(_) => return write!(f, "[error]"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 编码脚本数字：小端序，最高字节的最高位为符号位
+pub fn encode_num(n: i64) -> Vec<u8> {
+    if n == 0 {
+        return Vec::new();
+    }
+    let negative = n < 0;
+    let mut abs = n.unsigned_abs();
+    let mut bytes = Vec::new();
+    while abs > 0 {
+        bytes.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    if bytes.last().is_some_and(|last| last & 0x80 != 0) {
+        bytes.push(if negative { 0x80 } else { 0x00 });
+    } else if negative {
+        *bytes.last_mut().unwrap() |= 0x80;
+    }
+    bytes
+}
+
+/// 解码不超过`max_size`字节的脚本数字
+pub fn decode_num(bytes: &[u8], max_size: usize) -> Result<i64, ScriptError> {
+    if bytes.len() > max_size {
+        return Err(ScriptError::NumOverflow);
+    }
+    let Some((&last, _)) = bytes.split_last() else {
+        return Ok(0);
+    };
+    let mut n = bytes
+        .iter()
+        .rev()
+        .fold(0i64, |n, byte| (n << 8) | *byte as i64);
+    if last & 0x80 != 0 {
+        n &= !(0x80i64 << (8 * (bytes.len() - 1)));
+        n = -n;
+    }
+    Ok(n)
+}
+
+/// 栈元素作为布尔值：全零（包括负零）为假
+fn cast_to_bool(data: &[u8]) -> bool {
+    match data.split_last() {
+        Some((&last, rest)) => rest.iter().any(|byte| *byte != 0) || (last != 0 && last != 0x80),
+        None => false,
+    }
+}
+
+fn encode_bool(value: bool) -> Vec<u8> {
+    if value { vec![1] } else { Vec::new() }
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, ScriptError> {
+    stack.pop().ok_or(ScriptError::InvalidStackOperation)
+}
+
+/// 脚本执行时对签名和时间锁的检查，由交易验证提供签名哈希与正在验证的输入
+pub trait SignatureChecker {
+    /// `signature`是否是`pubkey`对交易签名哈希的有效签名，`script_code`是正在执行的脚本，参与签名哈希的计算
+    fn check_sig(&self, signature: &[u8], pubkey: &[u8], script_code: &Script) -> bool;
+
+    /// `signature`是否是x-only公钥`pubkey`对交易签名哈希的有效Schnorr签名
+    fn check_schnorr_sig(&self, _signature: &[u8], _pubkey: &[u8], _script_code: &Script) -> bool {
+        false
+    }
+
+    /// 交易的`lock_time`是否满足`OP_CHECKLOCKTIMEVERIFY`的要求
+    fn check_lock_time(&self, _lock_time: i64) -> bool {
+        false
+    }
+
+    /// 输入的序列号是否满足`OP_CHECKSEQUENCEVERIFY`的要求
+    fn check_sequence(&self, _sequence: i64) -> bool {
+        false
+    }
+}
+
+/// 在`stack`上执行脚本
+pub fn eval_script(
+    stack: &mut Vec<Vec<u8>>,
+    script: &Script,
+    checker: &dyn SignatureChecker,
+) -> Result<(), ScriptError> {
+    if script.len() > MAX_SCRIPT_SIZE {
+        return Err(ScriptError::ScriptSize);
+    }
+
+    let mut op_count = 0;
+    for instruction in script.instructions() {
+        let op = match instruction? {
+            Instruction::Push(data) => {
+                if data.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                    return Err(ScriptError::PushSize);
+                }
+                stack.push(data.to_vec());
+                if stack.len() > MAX_STACK_SIZE {
+                    return Err(ScriptError::StackSize);
+                }
+                continue;
+            }
+            Instruction::Op(op) => op,
+        };
+
+        if !op.is_push() {
+            op_count += 1;
+            if op_count > MAX_OPS_PER_SCRIPT {
+                return Err(ScriptError::OpCount);
+            }
+        }
+
+        match op {
+            Opcode::Op1Negate => stack.push(encode_num(-1)),
+            Opcode::Nop => {}
+            Opcode::Verify => {
+                if !cast_to_bool(&pop(stack)?) {
+                    return Err(ScriptError::Verify);
+                }
+            }
+            Opcode::Return => return Err(ScriptError::OpReturn),
+            Opcode::Drop => {
+                pop(stack)?;
+            }
+            Opcode::Dup => {
+                let top = stack.last().ok_or(ScriptError::InvalidStackOperation)?.clone();
+                stack.push(top);
+            }
+            Opcode::Equal | Opcode::EqualVerify => {
+                let equal = pop(stack)? == pop(stack)?;
+                if op == Opcode::Equal {
+                    stack.push(encode_bool(equal));
+                } else if !equal {
+                    return Err(ScriptError::EqualVerify);
+                }
+            }
+            Opcode::Ripemd160 => {
+                let data = pop(stack)?;
+                stack.push(ripemd::Ripemd160::digest(data).to_vec());
+            }
+            Opcode::Sha256 => {
+                let data = pop(stack)?;
+                stack.push(Sha256::digest(data).to_vec());
+            }
+            Opcode::Hash160 => {
+                let data = pop(stack)?;
+                stack.push(hash_pub_key(&data));
+            }
+            Opcode::Hash256 => {
+                let data = pop(stack)?;
+                stack.push(Sha256::digest(Sha256::digest(data)).to_vec());
+            }
+            Opcode::CheckSig | Opcode::CheckSigVerify => {
+                let pubkey = pop(stack)?;
+                let signature = pop(stack)?;
+                let valid = !signature.is_empty() && checker.check_sig(&signature, &pubkey, script);
+                if op == Opcode::CheckSig {
+                    stack.push(encode_bool(valid));
+                } else if !valid {
+                    return Err(ScriptError::CheckSigVerify);
+                }
+            }
+            Opcode::CheckMultiSig | Opcode::CheckMultiSigVerify => {
+                let key_count = decode_num(&pop(stack)?, MAX_NUM_SIZE)?;
+                if !(0..=MAX_PUBKEYS_PER_MULTISIG as i64).contains(&key_count) {
+                    return Err(ScriptError::PubkeyCount);
+                }
+                op_count += key_count as usize;
+                if op_count > MAX_OPS_PER_SCRIPT {
+                    return Err(ScriptError::OpCount);
+                }
+                let mut pubkeys = (0..key_count).map(|_| pop(stack)).collect::<Result<Vec<_>, _>>()?;
+                pubkeys.reverse();
+
+                let sig_count = decode_num(&pop(stack)?, MAX_NUM_SIZE)?;
+                if !(0..=key_count).contains(&sig_count) {
+                    return Err(ScriptError::SigCount);
+                }
+                let mut signatures = (0..sig_count).map(|_| pop(stack)).collect::<Result<Vec<_>, _>>()?;
+                signatures.reverse();
+
+                // 与比特币一致，OP_CHECKMULTISIG会多弹出一个元素，要求其为空
+                if !pop(stack)?.is_empty() {
+                    return Err(ScriptError::NullDummy);
+                }
+
+                // 签名必须按公钥的顺序排列
+                let mut keys = pubkeys.iter();
+                let valid = signatures.iter().all(|signature| {
+                    !signature.is_empty()
+                        && keys.any(|pubkey| checker.check_sig(signature, pubkey, script))
+                });
+                if op == Opcode::CheckMultiSig {
+                    stack.push(encode_bool(valid));
+                } else if !valid {
+                    return Err(ScriptError::CheckMultiSigVerify);
+                }
+            }
+            Opcode::CheckLockTimeVerify | Opcode::CheckSequenceVerify => {
+                // 参数留在栈上，通常随后使用OP_DROP
+                let top = stack.last().ok_or(ScriptError::InvalidStackOperation)?;
+                let value = decode_num(top, LOCKTIME_NUM_SIZE)?;
+                if value < 0 {
+                    return Err(ScriptError::NegativeLockTime);
+                }
+                let satisfied = if op == Opcode::CheckLockTimeVerify {
+                    checker.check_lock_time(value)
+                } else {
+                    value & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 || checker.check_sequence(value)
+                };
+                if !satisfied {
+                    return Err(ScriptError::UnsatisfiedLockTime);
+                }
+            }
+            Opcode::Op0 | Opcode::PushData1 | Opcode::PushData2 | Opcode::PushData4 => {
+                unreachable!("压栈操作码在解析时已转换为数据")
+            }
+            _ => match op.small_int() {
+                Some(n) => stack.push(encode_num(n as i64)),
+                None => return Err(ScriptError::BadOpcode(op as u8)),
+            },
+        }
+
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackSize);
+        }
+    }
+
+    Ok(())
+}
+
+fn check_top(stack: &[Vec<u8>]) -> Result<(), ScriptError> {
+    match stack.last() {
+        Some(top) if cast_to_bool(top) => Ok(()),
+        _ => Err(ScriptError::EvalFalse),
+    }
+}
+
+/// 先执行解锁脚本，再在同一个栈上执行锁定脚本，栈顶为真时验证通过。
+/// 锁定脚本是P2SH时，解锁脚本最后压入的数据作为赎回脚本，用其余数据再执行一次；
+/// 锁定脚本是P2TR时，解锁脚本只能压入一个签名，以Schnorr签名验证
+pub fn verify_script(
+    script_sig: &Script,
+    script_pubkey: &Script,
+    checker: &dyn SignatureChecker,
+) -> Result<(), ScriptError> {
+    if !script_sig.is_push_only() {
+        return Err(ScriptError::SigPushOnly);
+    }
+
+    let mut stack = Vec::new();
+    eval_script(&mut stack, script_sig, checker)?;
+
+    if let Some(output_key) = script_pubkey.p2tr_key() {
+        let [signature] = stack.as_slice() else {
+            return Err(ScriptError::KeyPathSpend);
+        };
+        if !checker.check_schnorr_sig(signature, output_key, script_pubkey) {
+            return Err(ScriptError::SchnorrSig);
+        }
+        return Ok(());
+    }
+
+    let mut redeem_stack = script_pubkey.p2sh_hash().map(|_| stack.clone());
+    eval_script(&mut stack, script_pubkey, checker)?;
+    check_top(&stack)?;
+
+    // 锁定脚本已验证赎回脚本的哈希
+    if let Some(stack) = redeem_stack.as_mut() {
+        let redeem_script = Script::from_bytes(pop(stack)?);
+        eval_script(stack, &redeem_script, checker)?;
+        check_top(stack)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 只接受签名等于`b"sig:" + pubkey`（Schnorr签名为`b"schnorr:" + pubkey`）的测试检查器
+    struct FakeChecker;
+
+    impl SignatureChecker for FakeChecker {
+        fn check_sig(&self, signature: &[u8], pubkey: &[u8], _script_code: &Script) -> bool {
+            signature.strip_prefix(b"sig:") == Some(pubkey)
+        }
+
+        fn check_schnorr_sig(&self, signature: &[u8], pubkey: &[u8], _script_code: &Script) -> bool {
+            signature.strip_prefix(b"schnorr:") == Some(pubkey)
+        }
+    }
+
+    fn sig(pubkey: &[u8]) -> Vec<u8> {
+        [b"sig:".as_slice(), pubkey].concat()
+    }
+
+    #[test]
+    fn test_script_encoding() {
+        let hash = [7u8; 20];
+        let script = Script::p2pkh(&hash);
+        assert_eq!(script.len(), 25);
+        assert_eq!(script.p2pkh_hash(), Some(hash.as_slice()));
+        assert_eq!(
+            script.to_string(),
+            format!("OP_DUP OP_HASH160 {} OP_EQUALVERIFY OP_CHECKSIG", hex::encode(hash))
+        );
+
+        let long = vec![1u8; 300];
+        let pushed = Script::new().push_slice(&long);
+        assert_eq!(pushed.instructions().collect::<Vec<_>>(), vec![Ok(Instruction::Push(long.as_slice()))]);
+        assert!(pushed.is_push_only());
+        assert!(!script.is_push_only());
+
+        let truncated = Script::from_bytes(vec![0x05, 1, 2]);
+        assert_eq!(truncated.instructions().next(), Some(Err(ScriptError::Truncated)));
+
+        for n in [0, 1, -1, 16, 17, 127, 128, -128, 255, 32767, -32768, 1 << 31] {
+            assert_eq!(decode_num(&encode_num(n), 8), Ok(n));
+        }
+        assert_eq!(encode_num(128), vec![0x80, 0x00]);
+        assert_eq!(encode_num(-1), vec![0x81]);
+    }
+
+    #[test]
+    fn test_p2pkh_and_hash_lock() {
+        let pubkey = b"alice-pubkey".to_vec();
+        let script_pubkey = Script::p2pkh(&hash_pub_key(&pubkey));
+
+        let unlock = Script::p2pkh_unlock(&sig(&pubkey), &pubkey);
+        assert_eq!(verify_script(&unlock, &script_pubkey, &FakeChecker), Ok(()));
+
+        let bad_sig = Script::p2pkh_unlock(b"sig:mallory", &pubkey);
+        assert_eq!(verify_script(&bad_sig, &script_pubkey, &FakeChecker), Err(ScriptError::EvalFalse));
+
+        let wrong_key = Script::p2pkh_unlock(&sig(b"mallory"), b"mallory");
+        assert_eq!(verify_script(&wrong_key, &script_pubkey, &FakeChecker), Err(ScriptError::EqualVerify));
+
+        let lock = Script::hash_lock(&Sha256::digest(b"preimage"));
+        let reveal = Script::new().push_slice(b"preimage");
+        assert_eq!(verify_script(&reveal, &lock, &FakeChecker), Ok(()));
+        let guess = Script::new().push_slice(b"guess");
+        assert_eq!(verify_script(&guess, &lock, &FakeChecker), Err(ScriptError::EvalFalse));
+
+        // 解锁脚本不能包含操作码
+        let sneaky = Script::new().push_opcode(Opcode::Op1).push_opcode(Opcode::Dup);
+        assert_eq!(verify_script(&sneaky, &lock, &FakeChecker), Err(ScriptError::SigPushOnly));
+
+        let data = Script::op_return(b"hello");
+        assert!(data.is_op_return());
+        assert_eq!(verify_script(&Script::new(), &data, &FakeChecker), Err(ScriptError::OpReturn));
+    }
+
+    #[test]
+    fn test_checkmultisig() -> Result<(), ScriptError> {
+        let keys: Vec<Vec<u8>> = vec![b"k1".to_vec(), b"k2".to_vec(), b"k3".to_vec()];
+        let script_pubkey = Script::multisig(2, &keys)?;
+
+        let unlock = |sigs: &[Vec<u8>]| {
+            sigs.iter()
+                .fold(Script::new().push_opcode(Opcode::Op0), |script, sig| script.push_slice(sig))
+        };
+
+        assert_eq!(verify_script(&unlock(&[sig(&keys[0]), sig(&keys[2])]), &script_pubkey, &FakeChecker), Ok(()));
+        assert_eq!(verify_script(&unlock(&[sig(&keys[1]), sig(&keys[2])]), &script_pubkey, &FakeChecker), Ok(()));
+
+        // 签名顺序与公钥顺序不一致
+        assert_eq!(
+            verify_script(&unlock(&[sig(&keys[2]), sig(&keys[0])]), &script_pubkey, &FakeChecker),
+            Err(ScriptError::EvalFalse)
+        );
+        // 同一把钥匙不能签两次
+        assert_eq!(
+            verify_script(&unlock(&[sig(&keys[0]), sig(&keys[0])]), &script_pubkey, &FakeChecker),
+            Err(ScriptError::EvalFalse)
+        );
+        // 附加元素必须为空
+        let dirty = Script::new().push_int(1).push_slice(&sig(&keys[0])).push_slice(&sig(&keys[1]));
+        assert_eq!(verify_script(&dirty, &script_pubkey, &FakeChecker), Err(ScriptError::NullDummy));
+
+        assert_eq!(Script::multisig(4, &keys), Err(ScriptError::SigCount));
+        Ok(())
+    }
+
+    #[test]
+    fn test_p2sh() -> Result<(), ScriptError> {
+        let keys: Vec<Vec<u8>> = vec![b"k1".to_vec(), b"k2".to_vec(), b"k3".to_vec()];
+        let redeem_script = Script::multisig(2, &keys)?;
+        let script_pubkey = redeem_script.to_p2sh();
+        assert_eq!(script_pubkey.len(), 23);
+        assert!(script_pubkey.p2sh_hash().is_some());
+
+        let unlock = |sigs: &[Vec<u8>], redeem_script: &Script| {
+            sigs.iter()
+                .fold(Script::new().push_opcode(Opcode::Op0), |script, sig| script.push_slice(sig))
+                .push_slice(redeem_script.as_bytes())
+        };
+
+        let good = unlock(&[sig(&keys[0]), sig(&keys[1])], &redeem_script);
+        assert_eq!(verify_script(&good, &script_pubkey, &FakeChecker), Ok(()));
+
+        // 赎回脚本的哈希正确，但签名不满足赎回脚本
+        let short = unlock(&[sig(&keys[0]), sig(b"k4")], &redeem_script);
+        assert_eq!(verify_script(&short, &script_pubkey, &FakeChecker), Err(ScriptError::EvalFalse));
+
+        // 提供另一个赎回脚本
+        let other = Script::multisig(1, &keys)?;
+        let swapped = unlock(&[sig(&keys[0])], &other);
+        assert_eq!(verify_script(&swapped, &script_pubkey, &FakeChecker), Err(ScriptError::EvalFalse));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_p2tr_key_path() {
+        let key = [9u8; 32];
+        let script_pubkey = Script::p2tr(&key);
+        assert_eq!(script_pubkey.len(), 34);
+        assert_eq!(script_pubkey.p2tr_key(), Some(key.as_slice()));
+        assert_eq!(script_pubkey.to_string(), format!("OP_1 {}", hex::encode(key)));
+
+        let schnorr = [b"schnorr:".as_slice(), &key].concat();
+        let unlock = Script::new().push_slice(&schnorr);
+        assert_eq!(verify_script(&unlock, &script_pubkey, &FakeChecker), Ok(()));
+
+        // ECDSA签名、多余的数据或空解锁脚本都不能花费
+        assert_eq!(
+            verify_script(&Script::new().push_slice(&sig(&key)), &script_pubkey, &FakeChecker),
+            Err(ScriptError::SchnorrSig)
+        );
+        assert_eq!(
+            verify_script(&unlock.clone().push_slice(&key), &script_pubkey, &FakeChecker),
+            Err(ScriptError::KeyPathSpend)
+        );
+        assert_eq!(verify_script(&Script::new(), &script_pubkey, &FakeChecker), Err(ScriptError::KeyPathSpend));
+    }
+}
@@ -0,0 +1,77 @@
+use std::num::NonZeroUsize;
+
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+
+const MAX_SIGNATURE_CACHE_SIZE: usize = 50_000;
+
+static SIGNATURE_CACHE: Lazy<SignatureCache> =
+    Lazy::new(|| SignatureCache::new(MAX_SIGNATURE_CACHE_SIZE));
+
+/// 进程内共享的签名缓存：交易进入内存池时验证过的签名，在包含它的区块到达时无需再次验证
+pub fn signature_cache() -> &'static SignatureCache {
+    &SIGNATURE_CACHE
+}
+
+/// 已验证签名的有界缓存，以(签名哈希, 公钥, 签名)为键，容量满时淘汰最久未使用的项
+pub struct SignatureCache {
+    entries: Mutex<LruCache<[u8; 32], ()>>,
+}
+
+impl SignatureCache {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        SignatureCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    fn key(sighash: &[u8], pubkey: &[u8], signature: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for part in [sighash, pubkey, signature] {
+            hasher.update((part.len() as u32).to_le_bytes());
+            hasher.update(part);
+        }
+        hasher.finalize().into()
+    }
+
+    pub fn contains(&self, sighash: &[u8], pubkey: &[u8], signature: &[u8]) -> bool {
+        let key = Self::key(sighash, pubkey, signature);
+        self.entries.lock().get(&key).is_some()
+    }
+
+    pub fn insert(&self, sighash: &[u8], pubkey: &[u8], signature: &[u8]) {
+        let key = Self::key(sighash, pubkey, signature);
+        self.entries.lock().put(key, ());
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_cache_is_bounded() {
+        let cache = SignatureCache::new(2);
+        cache.insert(b"hash1", b"pubkey", b"sig");
+        cache.insert(b"hash2", b"pubkey", b"sig");
+        assert!(cache.contains(b"hash1", b"pubkey", b"sig"));
+        assert!(!cache.contains(b"hash1", b"pubkey", b"other"));
+
+        // hash1刚被访问过，淘汰的是hash2
+        cache.insert(b"hash3", b"pubkey", b"sig");
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(b"hash1", b"pubkey", b"sig"));
+        assert!(!cache.contains(b"hash2", b"pubkey", b"sig"));
+    }
+}
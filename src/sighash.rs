@@ -0,0 +1,226 @@
+use sha2::{Digest, Sha256};
+
+use crate::amount::Amount;
+use crate::error::{Result, RustBtcError};
+use crate::script::Script;
+use crate::transaction::{Transaction, TxOutput};
+
+pub const SIGHASH_ALL: u8 = 0x01;
+pub const SIGHASH_NONE: u8 = 0x02;
+pub const SIGHASH_SINGLE: u8 = 0x03;
+pub const SIGHASH_ANYONECANPAY: u8 = 0x80;
+
+/// 签名覆盖交易的哪些部分，以一个字节附加在签名末尾
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SighashType {
+    /// 覆盖所有输入和输出
+    All,
+    /// 覆盖所有输入，不覆盖输出
+    None,
+    /// 覆盖所有输入和与本输入序号相同的输出
+    Single,
+    /// 只覆盖本输入和所有输出
+    AllAnyoneCanPay,
+    /// 只覆盖本输入
+    NoneAnyoneCanPay,
+    /// 只覆盖本输入和与其序号相同的输出
+    SingleAnyoneCanPay,
+}
+
+impl SighashType {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            SighashType::All => SIGHASH_ALL,
+            SighashType::None => SIGHASH_NONE,
+            SighashType::Single => SIGHASH_SINGLE,
+            SighashType::AllAnyoneCanPay => SIGHASH_ALL | SIGHASH_ANYONECANPAY,
+            SighashType::NoneAnyoneCanPay => SIGHASH_NONE | SIGHASH_ANYONECANPAY,
+            SighashType::SingleAnyoneCanPay => SIGHASH_SINGLE | SIGHASH_ANYONECANPAY,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<SighashType> {
+        match byte {
+            SIGHASH_ALL => Some(SighashType::All),
+            SIGHASH_NONE => Some(SighashType::None),
+            SIGHASH_SINGLE => Some(SighashType::Single),
+            0x81 => Some(SighashType::AllAnyoneCanPay),
+            0x82 => Some(SighashType::NoneAnyoneCanPay),
+            0x83 => Some(SighashType::SingleAnyoneCanPay),
+            _ => None,
+        }
+    }
+
+    pub fn anyone_can_pay(self) -> bool {
+        self.to_byte() & SIGHASH_ANYONECANPAY != 0
+    }
+}
+
+/// 计算`tx`第`input_index`个输入的签名哈希，签名与验证共用这一实现。
+///
+/// 该输入的解锁脚本替换为`script_code`（被花费输出的锁定脚本，P2SH时为赎回脚本），其余输入的解锁脚本清空；
+/// 再按签名类型裁剪输入和输出，序列化后附加4字节小端序的类型，做两次SHA256
+pub fn signature_hash(
+    tx: &Transaction,
+    input_index: usize,
+    script_code: &Script,
+    sighash_type: SighashType,
+) -> Result<Vec<u8>> {
+    if input_index >= tx.vin.len() {
+        return Err(RustBtcError::InvalidInput(format!(
+            "交易 {} 没有第 {} 个输入",
+            tx.id, input_index
+        )));
+    }
+
+    let mut copy = tx.clone();
+    copy.id = String::new();
+    for (i, input) in copy.vin.iter_mut().enumerate() {
+        input.script_sig = if i == input_index {
+            script_code.clone()
+        } else {
+            Script::new()
+        };
+    }
+
+    match sighash_type.to_byte() & !SIGHASH_ANYONECANPAY {
+        SIGHASH_NONE => {
+            copy.vout.clear();
+            zero_other_sequences(&mut copy, input_index);
+        }
+        SIGHASH_SINGLE => {
+            if input_index >= copy.vout.len() {
+                return Err(RustBtcError::InvalidTransaction(format!(
+                    "交易 {} 的第 {} 个输入使用SIGHASH_SINGLE，但没有对应的输出",
+                    tx.id, input_index
+                )));
+            }
+            copy.vout.truncate(input_index + 1);
+            for output in &mut copy.vout[..input_index] {
+                *output = TxOutput {
+                    value: Amount::from_sat(u64::MAX),
+                    script_pubkey: Script::new(),
+                };
+            }
+            zero_other_sequences(&mut copy, input_index);
+        }
+        _ => {}
+    }
+
+    if sighash_type.anyone_can_pay() {
+        copy.vin = vec![copy.vin.swap_remove(input_index)];
+    }
+
+    let mut data = bincode::serialize(&copy)?;
+    data.extend_from_slice(&(sighash_type.to_byte() as u32).to_le_bytes());
+    Ok(Sha256::digest(Sha256::digest(&data)).to_vec())
+}
+
+/// 不覆盖全部输出时，其他输入的序列号也不参与签名，以便其他人更新
+fn zero_other_sequences(tx: &mut Transaction, input_index: usize) {
+    for (i, input) in tx.vin.iter_mut().enumerate() {
+        if i != input_index {
+            input.sequence = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TxInput;
+
+    const ALL_TYPES: [SighashType; 6] = [
+        SighashType::All,
+        SighashType::None,
+        SighashType::Single,
+        SighashType::AllAnyoneCanPay,
+        SighashType::NoneAnyoneCanPay,
+        SighashType::SingleAnyoneCanPay,
+    ];
+
+    fn fixture() -> Transaction {
+        Transaction {
+            id: "ignored".to_string(),
+            vin: vec![
+                TxInput::new("11".repeat(32), 0, Amount::from_sat(50)),
+                TxInput { sequence: 7, ..TxInput::new("22".repeat(32), 1, Amount::from_sat(30)) },
+            ],
+            vout: vec![
+                TxOutput { value: Amount::from_sat(60), script_pubkey: Script::p2pkh(&[0xaa; 20]) },
+                TxOutput { value: Amount::from_sat(19), script_pubkey: Script::p2sh(&[0xbb; 20]) },
+            ],
+            lock_time: 0,
+        }
+    }
+
+    #[test]
+    fn test_sighash_vectors() -> Result<()> {
+        let tx = fixture();
+        let script_code = Script::p2pkh(&[0xcc; 20]);
+        let expected = [
+            "429965447bacd49debca2df05c0bce33c11168c7c4d729f9cbf1f5dce1c6fb97",
+            "9e1751d4d2ac2d30d6031e945593999fc95a4dcfa49efe26eec70f39a162a1c0",
+            "c14893fe20866a7846a5ef000236e31b23d8b7f2a567d435c8afd0ea0ae7835c",
+            "44507a16166af87fd37fa7a7bcbf29cc45c8156113a6130628b89adde0da6ca3",
+            "e500dc005d0aeb1a73921599ec419dd60e5b7f538b00029821c2daf07f998500",
+            "b96627fd883c92dbc3ec97dee3f9b211880debfc79e4a9fbd7d782d350e0c456",
+        ];
+        for (sighash_type, expected) in ALL_TYPES.iter().zip(expected) {
+            assert_eq!(
+                hex::encode(signature_hash(&tx, 1, &script_code, *sighash_type)?),
+                expected,
+                "{:?}",
+                sighash_type
+            );
+            assert_eq!(SighashType::from_byte(sighash_type.to_byte()), Some(*sighash_type));
+        }
+        assert_eq!(SighashType::from_byte(0x04), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sighash_coverage() -> Result<()> {
+        let tx = fixture();
+        let script_code = Script::p2pkh(&[0xcc; 20]);
+        let hash = |tx: &Transaction, sighash_type| signature_hash(tx, 0, &script_code, sighash_type);
+
+        // 修改输出
+        let mut other_output = tx.clone();
+        other_output.vout[1].value = Amount::from_sat(18);
+        assert_ne!(hash(&tx, SighashType::All)?, hash(&other_output, SighashType::All)?);
+        assert_eq!(hash(&tx, SighashType::None)?, hash(&other_output, SighashType::None)?);
+        assert_eq!(hash(&tx, SighashType::Single)?, hash(&other_output, SighashType::Single)?);
+
+        let mut same_index_output = tx.clone();
+        same_index_output.vout[0].value = Amount::from_sat(59);
+        assert_ne!(hash(&tx, SighashType::Single)?, hash(&same_index_output, SighashType::Single)?);
+
+        // 修改其他输入
+        let mut other_input = tx.clone();
+        other_input.vin[1].sequence = 8;
+        assert_ne!(hash(&tx, SighashType::All)?, hash(&other_input, SighashType::All)?);
+        assert_eq!(hash(&tx, SighashType::None)?, hash(&other_input, SighashType::None)?);
+        other_input.vin.push(TxInput::new("33".repeat(32), 0, Amount::from_sat(10)));
+        for sighash_type in [SighashType::AllAnyoneCanPay, SighashType::NoneAnyoneCanPay, SighashType::SingleAnyoneCanPay] {
+            assert_eq!(hash(&tx, sighash_type)?, hash(&other_input, sighash_type)?);
+        }
+
+        // 解锁脚本不参与签名哈希，脚本代码参与
+        let mut signed = tx.clone();
+        signed.vin[0].script_sig = Script::new().push_slice(b"signature");
+        assert_eq!(hash(&tx, SighashType::All)?, hash(&signed, SighashType::All)?);
+        assert_ne!(
+            hash(&tx, SighashType::All)?,
+            signature_hash(&tx, 0, &Script::p2pkh(&[0xdd; 20]), SighashType::All)?
+        );
+
+        // SIGHASH_SINGLE没有对应输出
+        let mut single_output = tx.clone();
+        single_output.vout.truncate(1);
+        assert!(signature_hash(&single_output, 1, &script_code, SighashType::Single).is_err());
+        assert!(signature_hash(&tx, 2, &script_code, SighashType::All).is_err());
+
+        Ok(())
+    }
+}
@@ -1,7 +1,173 @@
-use crate::db::{Database, DbTable};
-use crate::error::Result;
-use crate::models::{WalletData, UTXOEntry};
+use crate::db::{Database, DbTable, FlushPolicy, WriteBatch};
+use crate::error::{Result, RustBtcError};
+use crate::models::WalletData;
 use crate::block::Block;
+use crate::addrindex::AddressTx;
+use crate::amount::Amount;
+use crate::blockchain::{BlockIndex, TxLocation};
+use crate::utxo::{BlockUndo, Coin};
+
+const TIP_KEY: &[u8] = b"tip";
+const UTXO_TIP_KEY: &[u8] = b"utxo_tip";
+const TX_INDEX_TIP_KEY: &[u8] = b"tx_index_tip";
+const ADDRESS_INDEX_TIP_KEY: &[u8] = b"address_index_tip";
+
+fn height_key(height: u64) -> [u8; 8] {
+    height.to_be_bytes()
+}
+
+fn utxo_key(txid: &str, vout: usize) -> String {
+    format!("{}:{}", txid, vout)
+}
+
+// Address index keys start with the pubkey hash so one address is a prefix scan
+fn address_utxo_key(pubkey_hash: &[u8], txid: &str, vout: usize) -> Vec<u8> {
+    let mut key = pubkey_hash.to_vec();
+    key.extend_from_slice(utxo_key(txid, vout).as_bytes());
+    key
+}
+
+fn address_tx_key(pubkey_hash: &[u8], height: u64, position: usize) -> Vec<u8> {
+    let mut key = pubkey_hash.to_vec();
+    key.extend_from_slice(&height_key(height));
+    key.extend_from_slice(&(position as u32).to_be_bytes());
+    key
+}
+
+fn parse_utxo_key(key: &[u8]) -> Result<(String, usize)> {
+    std::str::from_utf8(key)
+        .ok()
+        .and_then(|key| key.rsplit_once(':'))
+        .and_then(|(txid, vout)| Some((txid.to_string(), vout.parse().ok()?)))
+        .ok_or_else(|| corrupt_key("UTXO", key))
+}
+
+fn corrupt_key(kind: &str, key: &[u8]) -> RustBtcError {
+    RustBtcError::DeserializationError(format!("invalid {} key {}", kind, hex::encode(key)))
+}
+
+/// Writes collected across buckets and committed together by `Storage::write`,
+/// e.g. a block, its undo data and the UTXOs it creates and spends
+#[derive(Debug, Default)]
+pub struct StorageBatch {
+    batch: WriteBatch,
+}
+
+impl StorageBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store a block by hash without placing it on the active chain
+    pub fn save_block(&mut self, block: &Block) -> Result<()> {
+        self.batch.put(DbTable::Block, block.hash.as_bytes(), &block.serialize()?);
+        Ok(())
+    }
+
+    pub fn delete_block(&mut self, hash: &str) {
+        self.batch.delete(DbTable::Block, hash.as_bytes());
+    }
+
+    /// Make `hash` the active chain's block at `height`
+    pub fn set_block_hash(&mut self, height: u64, hash: &str) {
+        self.batch.put(DbTable::Height, &height_key(height), hash.as_bytes());
+    }
+
+    pub fn delete_block_hash(&mut self, height: u64) {
+        self.batch.delete(DbTable::Height, &height_key(height));
+    }
+
+    pub fn save_block_index(&mut self, entry: &BlockIndex) -> Result<()> {
+        self.batch.put(DbTable::BlockIndex, entry.hash.as_bytes(), &bincode::serialize(entry)?);
+        Ok(())
+    }
+
+    fn set_meta_hash(&mut self, key: &[u8], hash: Option<&str>) {
+        match hash {
+            Some(hash) => self.batch.put(DbTable::Meta, key, hash.as_bytes()),
+            None => self.batch.delete(DbTable::Meta, key),
+        }
+    }
+
+    /// Record `hash` as the active chain tip, or clear it for an empty chain
+    pub fn set_tip(&mut self, hash: Option<&str>) {
+        self.set_meta_hash(TIP_KEY, hash);
+    }
+
+    /// Record the block the stored UTXO set is up to date with
+    pub fn set_utxo_tip(&mut self, hash: Option<&str>) {
+        self.set_meta_hash(UTXO_TIP_KEY, hash);
+    }
+
+    /// Record the block the transaction index is up to date with
+    pub fn set_tx_index_tip(&mut self, hash: Option<&str>) {
+        self.set_meta_hash(TX_INDEX_TIP_KEY, hash);
+    }
+
+    pub fn save_tx_location(&mut self, txid: &str, location: &TxLocation) -> Result<()> {
+        self.batch.put(DbTable::TxIndex, txid.as_bytes(), &bincode::serialize(location)?);
+        Ok(())
+    }
+
+    pub fn delete_tx_location(&mut self, txid: &str) {
+        self.batch.delete(DbTable::TxIndex, txid.as_bytes());
+    }
+
+    /// Record the block the address index is up to date with
+    pub fn set_address_index_tip(&mut self, hash: Option<&str>) {
+        self.set_meta_hash(ADDRESS_INDEX_TIP_KEY, hash);
+    }
+
+    pub fn save_address_utxo(&mut self, pubkey_hash: &[u8], txid: &str, vout: usize, value: Amount) -> Result<()> {
+        self.batch.put(DbTable::AddressUtxo, &address_utxo_key(pubkey_hash, txid, vout), &bincode::serialize(&value)?);
+        Ok(())
+    }
+
+    pub fn delete_address_utxo(&mut self, pubkey_hash: &[u8], txid: &str, vout: usize) {
+        self.batch.delete(DbTable::AddressUtxo, &address_utxo_key(pubkey_hash, txid, vout));
+    }
+
+    pub fn save_address_tx(&mut self, pubkey_hash: &[u8], entry: &AddressTx) -> Result<()> {
+        let key = address_tx_key(pubkey_hash, entry.height, entry.position);
+        self.batch.put(DbTable::AddressHistory, &key, &bincode::serialize(entry)?);
+        Ok(())
+    }
+
+    pub fn delete_address_tx(&mut self, pubkey_hash: &[u8], height: u64, position: usize) {
+        self.batch.delete(DbTable::AddressHistory, &address_tx_key(pubkey_hash, height, position));
+    }
+
+    pub fn save_block_undo(&mut self, hash: &str, undo: &BlockUndo) -> Result<()> {
+        self.batch.put(DbTable::Undo, hash.as_bytes(), &undo.serialize()?);
+        Ok(())
+    }
+
+    pub fn delete_block_undo(&mut self, hash: &str) {
+        self.batch.delete(DbTable::Undo, hash.as_bytes());
+    }
+
+    pub fn save_wallet(&mut self, address: &str, wallet: &WalletData) -> Result<()> {
+        self.batch.put(DbTable::Address, address.as_bytes(), &wallet.serialize()?);
+        Ok(())
+    }
+
+    pub fn delete_wallet(&mut self, address: &str) {
+        self.batch.delete(DbTable::Address, address.as_bytes());
+    }
+
+    pub fn save_utxo(&mut self, txid: &str, vout: usize, coin: &Coin) -> Result<()> {
+        self.batch.put(DbTable::UTXO, utxo_key(txid, vout).as_bytes(), &bincode::serialize(coin)?);
+        Ok(())
+    }
+
+    pub fn delete_utxo(&mut self, txid: &str, vout: usize) {
+        self.batch.delete(DbTable::UTXO, utxo_key(txid, vout).as_bytes());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batch.is_empty()
+    }
+}
 
 pub struct Storage {
     db: Database,
@@ -13,24 +179,150 @@ impl Storage {
         Ok(Storage { db })
     }
 
-    // Block storage operations
+    pub fn with_flush_policy(path: &str, flush_policy: FlushPolicy) -> Result<Self> {
+        let db = Database::with_flush_policy(path, flush_policy)?;
+        Ok(Storage { db })
+    }
+
+    /// In-memory storage that is discarded when dropped
+    pub fn temporary() -> Result<Self> {
+        let db = Database::temporary()?;
+        Ok(Storage { db })
+    }
+
+    /// A second handle on the same database, as if it had been closed and opened again
+    #[cfg(test)]
+    pub(crate) fn reopen(&self) -> Result<Self> {
+        Ok(Storage { db: self.db.reopen()? })
+    }
+
+    // Atomic multi-bucket writes
+    pub fn write(&self, batch: StorageBatch) -> Result<()> {
+        self.db.write(batch.batch)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()
+    }
+
+    // Block storage operations: blocks are keyed by hash, the active chain maps heights to hashes
     pub fn save_block(&self, height: u64, block: &Block) -> Result<()> {
-        let key = height.to_be_bytes();
-        let value = block.serialize()?;
-        self.db.put(DbTable::Block, &key, &value)
+        let mut batch = StorageBatch::new();
+        batch.save_block(block)?;
+        batch.set_block_hash(height, &block.hash);
+        self.write(batch)
     }
 
     pub fn get_block(&self, height: u64) -> Result<Option<Block>> {
-        let key = height.to_be_bytes();
-        match self.db.view(DbTable::Block, &key)? {
+        match self.get_block_hash(height)? {
+            Some(hash) => self.get_block_by_hash(&hash),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_block_by_hash(&self, hash: &str) -> Result<Option<Block>> {
+        match self.db.view(DbTable::Block, hash.as_bytes())? {
             Some(data) => Ok(Some(Block::deserialize(&data)?)),
             None => Ok(None),
         }
     }
 
+    pub fn get_block_hash(&self, height: u64) -> Result<Option<String>> {
+        match self.db.view(DbTable::Height, &height_key(height))? {
+            Some(data) => Ok(Some(String::from_utf8_lossy(&data).into_owned())),
+            None => Ok(None),
+        }
+    }
+
     pub fn delete_block(&self, height: u64) -> Result<()> {
-        let key = height.to_be_bytes();
-        self.db.delete(DbTable::Block, &key)
+        let mut batch = StorageBatch::new();
+        if let Some(hash) = self.get_block_hash(height)? {
+            batch.delete_block(&hash);
+        }
+        batch.delete_block_hash(height);
+        self.write(batch)
+    }
+
+    // Chain state
+    pub fn get_block_index(&self, hash: &str) -> Result<Option<BlockIndex>> {
+        match self.db.view(DbTable::BlockIndex, hash.as_bytes())? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_meta_hash(&self, key: &[u8]) -> Result<Option<String>> {
+        Ok(self.db.view(DbTable::Meta, key)?.map(|data| String::from_utf8_lossy(&data).into_owned()))
+    }
+
+    pub fn get_tip(&self) -> Result<Option<String>> {
+        self.get_meta_hash(TIP_KEY)
+    }
+
+    pub fn get_utxo_tip(&self) -> Result<Option<String>> {
+        self.get_meta_hash(UTXO_TIP_KEY)
+    }
+
+    pub fn get_tx_index_tip(&self) -> Result<Option<String>> {
+        self.get_meta_hash(TX_INDEX_TIP_KEY)
+    }
+
+    pub fn get_tx_location(&self, txid: &str) -> Result<Option<TxLocation>> {
+        match self.db.view(DbTable::TxIndex, txid.as_bytes())? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Drop the whole transaction index together with its tip marker
+    pub fn clear_tx_index(&self) -> Result<()> {
+        self.db.delete(DbTable::Meta, TX_INDEX_TIP_KEY)?;
+        self.db.clear(DbTable::TxIndex)
+    }
+
+    pub fn get_address_index_tip(&self) -> Result<Option<String>> {
+        self.get_meta_hash(ADDRESS_INDEX_TIP_KEY)
+    }
+
+    /// Drop the whole address index together with its tip marker
+    pub fn clear_address_index(&self) -> Result<()> {
+        self.db.delete(DbTable::Meta, ADDRESS_INDEX_TIP_KEY)?;
+        self.db.clear(DbTable::AddressUtxo)?;
+        self.db.clear(DbTable::AddressHistory)
+    }
+
+    /// Unspent outputs paying `pubkey_hash` as (txid, vout, value)
+    pub fn iter_address_utxos(&self, pubkey_hash: &[u8]) -> impl Iterator<Item = Result<(String, usize, Amount)>> {
+        let prefix_len = pubkey_hash.len();
+        self.db.scan_prefix(DbTable::AddressUtxo, pubkey_hash).map(move |entry| {
+            let (key, value) = entry?;
+            let (txid, vout) = parse_utxo_key(&key[prefix_len..])?;
+            Ok((txid, vout, bincode::deserialize(&value)?))
+        })
+    }
+
+    /// Transactions funding or spending from `pubkey_hash`, oldest first
+    pub fn iter_address_txs(&self, pubkey_hash: &[u8]) -> impl DoubleEndedIterator<Item = Result<AddressTx>> {
+        self.db
+            .scan_prefix(DbTable::AddressHistory, pubkey_hash)
+            .map(|entry| Ok(bincode::deserialize(&entry?.1)?))
+    }
+
+    // Undo data is keyed by block hash so that blocks on side branches keep their own records
+    pub fn save_block_undo(&self, hash: &str, undo: &BlockUndo) -> Result<()> {
+        let value = undo.serialize()?;
+        self.db.put(DbTable::Undo, hash.as_bytes(), &value)
+    }
+
+    pub fn get_block_undo(&self, hash: &str) -> Result<Option<BlockUndo>> {
+        match self.db.view(DbTable::Undo, hash.as_bytes())? {
+            Some(data) => Ok(Some(BlockUndo::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete_block_undo(&self, hash: &str) -> Result<()> {
+        self.db.delete(DbTable::Undo, hash.as_bytes())
     }
 
     // Wallet storage operations
@@ -50,62 +342,86 @@ impl Storage {
         self.db.delete(DbTable::Address, address.as_bytes())
     }
 
-    // UTXO storage operations
-    pub fn save_utxo(&self, txid: &str, vout: u32, utxo: &UTXOEntry) -> Result<()> {
-        let key = format!("{}:{}", txid, vout);
-        let value = utxo.serialize()?;
+    // UTXO storage operations, keyed by outpoint
+    pub fn save_utxo(&self, txid: &str, vout: usize, coin: &Coin) -> Result<()> {
+        let key = utxo_key(txid, vout);
+        let value = bincode::serialize(coin)?;
         self.db.put(DbTable::UTXO, key.as_bytes(), &value)
     }
 
-    pub fn get_utxo(&self, txid: &str, vout: u32) -> Result<Option<UTXOEntry>> {
-        let key = format!("{}:{}", txid, vout);
+    pub fn get_utxo(&self, txid: &str, vout: usize) -> Result<Option<Coin>> {
+        let key = utxo_key(txid, vout);
         match self.db.view(DbTable::UTXO, key.as_bytes())? {
-            Some(data) => Ok(Some(UTXOEntry::deserialize(&data)?)),
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
             None => Ok(None),
         }
     }
 
-    pub fn delete_utxo(&self, txid: &str, vout: u32) -> Result<()> {
-        let key = format!("{}:{}", txid, vout);
+    pub fn delete_utxo(&self, txid: &str, vout: usize) -> Result<()> {
+        let key = utxo_key(txid, vout);
         self.db.delete(DbTable::UTXO, key.as_bytes())
     }
 
-    // Iteration methods for each bucket
-    pub fn iter_blocks(&self) -> Result<impl Iterator<Item = (u64, Block)>> {
-        let iter = self.db.iterate(DbTable::Block)?;
-        Ok(iter.filter_map(|(key, value)| {
-            if key.len() == 8 {
-                let height = u64::from_be_bytes(key.as_ref().try_into().ok()?);
-                let block = Block::deserialize(&value).ok()?;
-                Some((height, block))
-            } else {
-                None
-            }
-        }))
-    }
-
-    pub fn iter_wallets(&self) -> Result<impl Iterator<Item = (String, WalletData)>> {
-        let iter = self.db.iterate(DbTable::Address)?;
-        Ok(iter.filter_map(|(key, value)| {
-            let address = String::from_utf8(key.to_vec()).ok()?;
-            let wallet = WalletData::deserialize(&value).ok()?;
-            Some((address, wallet))
-        }))
-    }
-
-    pub fn iter_utxos(&self) -> Result<impl Iterator<Item = (String, UTXOEntry)>> {
-        let iter = self.db.iterate(DbTable::UTXO)?;
-        Ok(iter.filter_map(|(key, value)| {
-            let key_str = String::from_utf8(key.to_vec()).ok()?;
-            let utxo = UTXOEntry::deserialize(&value).ok()?;
-            Some((key_str, utxo))
-        }))
+    /// Storage whose UTXO table is a new, empty tree, so the UTXO set can be rebuilt while the current one stays intact
+    pub fn fresh_utxos(&self) -> Result<Storage> {
+        Ok(Storage { db: self.db.fresh_table(DbTable::UTXO)? })
+    }
+
+    /// Replace the UTXO table with the one filled through `fresh`, writing `batch` atomically with the switch
+    pub fn swap_in_utxos(&self, fresh: &Storage, batch: StorageBatch) -> Result<()> {
+        self.db.swap_in(DbTable::UTXO, &fresh.db, batch.batch)
+    }
+
+    // Iteration methods for each bucket; a read or decode error is yielded in place of the entry
+    /// Active chain blocks in height order, read one at a time
+    pub fn iter_blocks(&self) -> impl Iterator<Item = Result<(u64, Block)>> + '_ {
+        self.db.iterate(DbTable::Height).map(move |entry| {
+            let (key, value) = entry?;
+            let height = key
+                .as_ref()
+                .try_into()
+                .map(u64::from_be_bytes)
+                .map_err(|_| corrupt_key("height", &key))?;
+            let hash = String::from_utf8(value.to_vec())
+                .map_err(|e| RustBtcError::DeserializationError(e.to_string()))?;
+            let block = self
+                .get_block_by_hash(&hash)?
+                .ok_or(RustBtcError::BlockNotFound(hash))?;
+            Ok((height, block))
+        })
+    }
+
+    pub fn iter_block_index(&self) -> impl Iterator<Item = Result<BlockIndex>> {
+        self.db
+            .iterate(DbTable::BlockIndex)
+            .map(|entry| Ok(bincode::deserialize(&entry?.1)?))
+    }
+
+    pub fn iter_wallets(&self) -> impl Iterator<Item = Result<(String, WalletData)>> {
+        self.db.iterate(DbTable::Address).map(|entry| {
+            let (key, value) = entry?;
+            let address = String::from_utf8(key.to_vec()).map_err(|_| corrupt_key("address", &key))?;
+            Ok((address, WalletData::deserialize(&value)?))
+        })
+    }
+
+    pub fn iter_utxos(&self) -> impl Iterator<Item = Result<(String, usize, Coin)>> {
+        self.db.iterate(DbTable::UTXO).map(|entry| {
+            let (key, value) = entry?;
+            let (txid, vout) = parse_utxo_key(&key)?;
+            Ok((txid, vout, bincode::deserialize(&value)?))
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::amount::Amount;
+    use crate::block::BlockHeader;
+    use crate::script::Script;
+    use crate::transaction::TxOutput;
+    use crate::utxo::{Coin, SpentCoin};
     use tempfile::tempdir;
 
     #[test]
@@ -114,20 +430,23 @@ mod tests {
         let storage = Storage::new(temp_dir.path().to_str().unwrap())?;
 
         let block = Block {
-            version: 1,
-            prev_block_hash: "0000000000000000000000000000000000000000000000000000000000000000".to_string(),
-            merkle_root: "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b".to_string(),
-            timestamp: 1231006505,
-            bits: 0x1d00ffff,
-            nonce: 2083236893,
+            header: BlockHeader {
+                version: 1,
+                prev_block_hash: "0000000000000000000000000000000000000000000000000000000000000000".to_string(),
+                merkle_root: "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b".to_string(),
+                timestamp: 1231006505,
+                bits: 0x1d00ffff,
+                nonce: 2083236893,
+            },
             transactions: vec![],
+            hash: String::new(),
+            height: 0,
         };
 
         // Test save and retrieve
         storage.save_block(0, &block)?;
         let retrieved = storage.get_block(0)?.unwrap();
-        assert_eq!(retrieved.version, block.version);
-        assert_eq!(retrieved.prev_block_hash, block.prev_block_hash);
+        assert_eq!(retrieved.header, block.header);
 
         // Test delete
         storage.delete_block(0)?;
@@ -136,5 +455,126 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_block_undo_storage() -> Result<()> {
+        let temp_dir = tempdir().unwrap();
+        let storage = Storage::new(temp_dir.path().to_str().unwrap())?;
+
+        let undo = BlockUndo {
+            spent: vec![SpentCoin {
+                txid: "ab".repeat(32),
+                vout: 1,
+                coin: Coin {
+                    output: TxOutput { value: Amount::from_sat(50), script_pubkey: Script::p2pkh(&[1; 20]) },
+                    height: 7,
+                    is_coinbase: true,
+                    median_time_past: 1_600_000_000,
+                },
+            }],
+        };
+        let hash = "cd".repeat(32);
+
+        storage.save_block_undo(&hash, &undo)?;
+        assert_eq!(storage.get_block_undo(&hash)?, Some(undo));
+
+        storage.delete_block_undo(&hash)?;
+        assert!(storage.get_block_undo(&hash)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_write() -> Result<()> {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_str().unwrap();
+        let hash = "cd".repeat(32);
+        let block = Block::new(vec![], "0".to_string())?;
+        let coin = Coin {
+            output: TxOutput { value: Amount::from_sat(50), script_pubkey: Script::p2pkh(&[1; 20]) },
+            height: 1,
+            is_coinbase: false,
+            median_time_past: 0,
+        };
+
+        let storage = Storage::with_flush_policy(path, FlushPolicy::Manual)?;
+        storage.save_utxo("spent", 0, &coin)?;
+
+        // The block, its undo data and its UTXO changes land together
+        let mut batch = StorageBatch::new();
+        batch.save_block(&block)?;
+        batch.set_block_hash(1, &block.hash);
+        batch.save_block_undo(&hash, &BlockUndo::default())?;
+        batch.save_utxo("created", 0, &coin)?;
+        batch.delete_utxo("spent", 0);
+        storage.write(batch)?;
+        storage.flush()?;
+
+        let storage = storage.reopen()?;
+        assert_eq!(storage.get_block(1)?.map(|b| b.hash), Some(block.hash));
+        assert!(storage.get_block_undo(&hash)?.is_some());
+        assert!(storage.get_utxo("spent", 0)?.is_none());
+        assert_eq!(storage.get_utxo("created", 0)?, Some(coin));
+        assert_eq!(
+            storage.iter_utxos().map(|utxo| utxo.map(|(txid, vout, _)| (txid, vout))).collect::<Result<Vec<_>>>()?,
+            vec![("created".to_string(), 0)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_swap_in_fresh_utxos() -> Result<()> {
+        let temp_dir = tempdir().unwrap();
+        let storage = Storage::new(temp_dir.path().to_str().unwrap())?;
+        let coin = Coin {
+            output: TxOutput { value: Amount::from_sat(50), script_pubkey: Script::p2pkh(&[1; 20]) },
+            height: 1,
+            is_coinbase: false,
+            median_time_past: 0,
+        };
+        let mut batch = StorageBatch::new();
+        batch.save_utxo("old", 0, &coin)?;
+        batch.set_utxo_tip(Some("old_tip"));
+        storage.write(batch)?;
+
+        // Writes to the fresh table stay invisible until it is swapped in
+        let fresh = storage.fresh_utxos()?;
+        fresh.save_utxo("new", 0, &coin)?;
+        assert!(storage.get_utxo("new", 0)?.is_none());
+        assert_eq!(storage.reopen()?.get_utxo("old", 0)?, Some(coin.clone()));
+
+        // An abandoned rebuild leaves nothing behind for the next one
+        let fresh = storage.fresh_utxos()?;
+        assert_eq!(fresh.iter_utxos().count(), 0);
+        fresh.save_utxo("new", 1, &coin)?;
+
+        let mut batch = StorageBatch::new();
+        batch.save_utxo("new", 2, &coin)?;
+        batch.set_utxo_tip(Some("new_tip"));
+        storage.swap_in_utxos(&fresh, batch)?;
+        for storage in [&storage, &storage.reopen()?] {
+            assert_eq!(storage.get_utxo_tip()?.as_deref(), Some("new_tip"));
+            assert!(storage.get_utxo("old", 0)?.is_none());
+            assert_eq!(
+                storage.iter_utxos().map(|utxo| utxo.map(|(txid, vout, _)| (txid, vout))).collect::<Result<Vec<_>>>()?,
+                vec![("new".to_string(), 1), ("new".to_string(), 2)]
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_reports_corrupt_entries() -> Result<()> {
+        let storage = Storage::temporary()?;
+        storage.db.put(DbTable::UTXO, b"no-vout", b"coin")?;
+        storage.db.put(DbTable::BlockIndex, b"hash", b"not an index entry")?;
+
+        assert!(matches!(storage.iter_utxos().next(), Some(Err(RustBtcError::DeserializationError(_)))));
+        assert!(storage.iter_block_index().collect::<Result<Vec<_>>>().is_err());
+
+        Ok(())
+    }
+
     // Add more tests for wallet and UTXO storage...
 }
@@ -1,98 +1,313 @@
-use bs58;
 use sha2::{Sha256, Digest};
 use serde::{Deserialize, Serialize};
 use tracing::{error, debug};
 use bincode;
 
+use crate::amount::{Amount, COIN};
 use crate::error::{Result, RustBtcError};
-use super::utxo::UTXOSet;
-use super::wallet::Wallet;
-use secp256k1::{self, ecdsa};
+use crate::params::ChainParams;
+use super::utxo::{Coin, UTXOSet};
+use super::wallet::{address_to_script_pubkey, hash_pub_key, MultisigAccount, Wallet};
+use secp256k1::{self, ecdsa, schnorr};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rayon::prelude::*;
 
-const SUBSIDY: i64 = 50;
+use crate::script::{verify_script, Instruction, Opcode, Script, ScriptError, SignatureChecker};
+use crate::sigcache::signature_cache;
+use crate::sighash::{self, SighashType};
+
+static VERIFY_CONTEXT: Lazy<secp256k1::Secp256k1<secp256k1::VerifyOnly>> =
+    Lazy::new(secp256k1::Secp256k1::verification_only);
+
+/// 创世区块的出块奖励，之后每经过一个减半周期减半
+pub const SUBSIDY: Amount = Amount::from_sat(50 * COIN);
+
+/// 小于该值的`lock_time`表示区块高度，否则表示Unix时间戳
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// 输入序列号的默认值：不启用相对时间锁；所有输入都是该值时交易的`lock_time`也不生效
+pub const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+/// 序列号设置该位时不启用相对时间锁
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+/// 序列号设置该位时相对时间锁以512秒为单位，否则以区块数为单位
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+/// 序列号中表示相对时间锁数值的部分
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_ffff;
+/// 时间类型的相对时间锁每单位为2^9=512秒
+const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+/// coinbase中见证承诺输出的数据前缀
+pub const WITNESS_COMMITMENT_HEADER: [u8; 4] = [0xaa, 0x21, 0xa9, 0xed];
+
+/// 验证`pubkey`对签名哈希`sighash`的ECDSA签名，验证通过的签名记入签名缓存
+pub fn verify_signature(sighash: &[u8], pubkey: &[u8], signature: &[u8]) -> Result<bool> {
+    if signature.is_empty() || pubkey.is_empty() {
+        error!("缺少签名或公钥");
+        return Err(RustBtcError::InvalidSignature("缺少签名或公钥".to_string()));
+    }
+
+    // 已验证过的签名直接通过
+    let cache = signature_cache();
+    if cache.contains(sighash, pubkey, signature) {
+        debug!("签名缓存命中");
+        return Ok(true);
+    }
+
+    // 解析公钥
+    let public_key = secp256k1::PublicKey::from_slice(pubkey)
+        .map_err(|e| {
+            error!("解析公钥失败: {}", e);
+            RustBtcError::InvalidPublicKey(e.to_string())
+        })?;
+
+    // 签名直接针对交易的签名哈希，与`Transaction::sign`一致
+    let message = secp256k1::Message::from_slice(sighash)
+        .map_err(|e| {
+            error!("创建消息对象失败: {}", e);
+            RustBtcError::InvalidMessage(e.to_string())
+        })?;
+
+    // 解析签名
+    let parsed = ecdsa::Signature::from_compact(signature)
+        .map_err(|e| {
+            error!("解析签名失败: {}", e);
+            RustBtcError::InvalidSignature(e.to_string())
+        })?;
+
+    // 验证签名
+    match VERIFY_CONTEXT.verify_ecdsa(&message, &parsed, &public_key) {
+        Ok(_) => {
+            debug!("签名验证成功");
+            cache.insert(sighash, pubkey, signature);
+            Ok(true)
+        }
+        Err(e) => {
+            error!("签名验证失败: {}", e);
+            Ok(false)
+        }
+    }
+}
+
+/// 验证x-only公钥`pubkey`对签名哈希`sighash`的BIP340 Schnorr签名，验证通过的签名记入签名缓存
+pub fn verify_schnorr_signature(sighash: &[u8], pubkey: &[u8], signature: &[u8]) -> Result<bool> {
+    let cache = signature_cache();
+    if cache.contains(sighash, pubkey, signature) {
+        debug!("签名缓存命中");
+        return Ok(true);
+    }
+
+    let public_key = secp256k1::XOnlyPublicKey::from_slice(pubkey)
+        .map_err(|e| RustBtcError::InvalidPublicKey(e.to_string()))?;
+    let message = secp256k1::Message::from_slice(sighash)
+        .map_err(|e| RustBtcError::InvalidMessage(e.to_string()))?;
+    let parsed = schnorr::Signature::from_slice(signature)
+        .map_err(|e| RustBtcError::InvalidSignature(e.to_string()))?;
+
+    match VERIFY_CONTEXT.verify_schnorr(&parsed, &message, &public_key) {
+        Ok(_) => {
+            cache.insert(sighash, pubkey, signature);
+            Ok(true)
+        }
+        Err(e) => {
+            error!("Schnorr签名验证失败: {}", e);
+            Ok(false)
+        }
+    }
+}
+
+/// 推迟验证的Schnorr签名。执行区块中的脚本时先收集所有密钥路径签名，脚本全部通过后再并行地逐个验证，
+/// 总计算量与逐个验证相同
+#[derive(Default)]
+pub struct ParallelSchnorr {
+    entries: Mutex<Vec<ParallelSchnorrEntry>>,
+}
+
+struct ParallelSchnorrEntry {
+    /// (交易序号, 输入序号)
+    position: (usize, usize),
+    sighash: Vec<u8>,
+    pubkey: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl ParallelSchnorr {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().is_empty()
+    }
+
+    fn push(&self, position: (usize, usize), sighash: Vec<u8>, pubkey: &[u8], signature: &[u8]) {
+        self.entries.lock().push(ParallelSchnorrEntry {
+            position,
+            sighash,
+            pubkey: pubkey.to_vec(),
+            signature: signature.to_vec(),
+        });
+    }
+
+    /// 并行逐个验证收集到的所有签名，返回无效签名中位置最靠前的(交易序号, 输入序号)
+    pub fn verify(&self) -> Option<(usize, usize)> {
+        self.entries
+            .lock()
+            .par_iter()
+            .filter(|entry| !verify_schnorr_signature(&entry.sighash, &entry.pubkey, &entry.signature).unwrap_or(false))
+            .map(|entry| entry.position)
+            .min()
+    }
+}
+
+/// 多签解锁脚本`OP_0 <signature>... <redeem_script>`中已有的签名
+fn multisig_signatures(script_sig: &Script, redeem_script: &Script) -> Vec<Vec<u8>> {
+    let pushes: Vec<&[u8]> = script_sig
+        .instructions()
+        .map_while(|instruction| match instruction {
+            Ok(Instruction::Push(data)) => Some(data),
+            _ => None,
+        })
+        .collect();
+    match pushes.as_slice() {
+        [[], signatures @ .., redeem] if *redeem == redeem_script.as_bytes() => {
+            signatures.iter().map(|signature| signature.to_vec()).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// 脚本中的签名针对正在验证的输入的签名哈希验证，时间锁操作码针对该输入检查
+pub struct TransactionSignatureChecker<'a> {
+    tx: &'a Transaction,
+    input: usize,
+    /// 不为空时Schnorr签名推迟到其中验证，另记录交易在区块中的序号
+    deferred: Option<(&'a ParallelSchnorr, usize)>,
+}
+
+impl<'a> TransactionSignatureChecker<'a> {
+    /// 验证`tx`第`input`个输入时使用的检查器
+    pub fn new(tx: &'a Transaction, input: usize) -> Self {
+        TransactionSignatureChecker { tx, input, deferred: None }
+    }
+
+    /// 将Schnorr签名加入`deferred`而不立即验证的检查器，`tx_index`是交易在区块中的序号
+    pub fn with_deferred(tx: &'a Transaction, input: usize, deferred: &'a ParallelSchnorr, tx_index: usize) -> Self {
+        TransactionSignatureChecker { tx, input, deferred: Some((deferred, tx_index)) }
+    }
+}
+
+impl SignatureChecker for TransactionSignatureChecker<'_> {
+    fn check_sig(&self, signature: &[u8], pubkey: &[u8], script_code: &Script) -> bool {
+        // 签名的最后一个字节是签名类型
+        let Some((&type_byte, signature)) = signature.split_last() else {
+            return false;
+        };
+        let Some(sighash_type) = SighashType::from_byte(type_byte) else {
+            return false;
+        };
+        match self.tx.signature_hash(self.input, script_code, sighash_type) {
+            Ok(sighash) => verify_signature(&sighash, pubkey, signature).unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+
+    fn check_schnorr_sig(&self, signature: &[u8], pubkey: &[u8], script_code: &Script) -> bool {
+        let Some((&type_byte, signature)) = signature.split_last() else {
+            return false;
+        };
+        let Some(sighash_type) = SighashType::from_byte(type_byte) else {
+            return false;
+        };
+        let Ok(sighash) = self.tx.signature_hash(self.input, script_code, sighash_type) else {
+            return false;
+        };
+        // 密钥路径签名是脚本验证的最后一步，推迟验证不会影响脚本的执行路径
+        match self.deferred {
+            Some((deferred, tx_index)) => {
+                deferred.push((tx_index, self.input), sighash, pubkey, signature);
+                true
+            }
+            None => verify_schnorr_signature(&sighash, pubkey, signature).unwrap_or(false),
+        }
+    }
+
+    fn check_lock_time(&self, lock_time: i64) -> bool {
+        let tx_lock_time = self.tx.lock_time as i64;
+        let threshold = LOCKTIME_THRESHOLD as i64;
+        // 高度与时间不能比较
+        if (lock_time < threshold) != (tx_lock_time < threshold) || lock_time > tx_lock_time {
+            return false;
+        }
+        // 输入序列号为最终值时交易的lock_time不生效
+        self.tx.vin[self.input].sequence != SEQUENCE_FINAL
+    }
+
+    fn check_sequence(&self, sequence: i64) -> bool {
+        let tx_sequence = self.tx.vin[self.input].sequence;
+        if tx_sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return false;
+        }
+        let sequence = sequence as u32;
+        let mask = SEQUENCE_LOCKTIME_TYPE_FLAG | SEQUENCE_LOCKTIME_MASK;
+        (sequence & SEQUENCE_LOCKTIME_TYPE_FLAG) == (tx_sequence & SEQUENCE_LOCKTIME_TYPE_FLAG)
+            && (sequence & mask) <= (tx_sequence & mask)
+    }
+}
+
+/// 相对时间锁要求的最小高度和最小中位时间：包含交易的区块高度必须大于`min_height`，
+/// 且该区块之前的中位时间必须大于`min_time`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceLock {
+    pub min_height: i64,
+    pub min_time: i64,
+}
+
+impl SequenceLock {
+    pub fn is_satisfied(&self, height: u64, median_time_past: u32) -> bool {
+        self.min_height < height as i64 && self.min_time < median_time_past as i64
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TxInput {
     pub txid: String,
     pub vout: usize,
-    pub signature: Vec<u8>,
-    pub pubkey: Vec<u8>,
-    pub value: i64,
+    /// 解锁脚本，与被花费输出的锁定脚本一起执行
+    pub script_sig: Script,
+    pub value: Amount,
+    /// 序列号，未设置`SEQUENCE_LOCKTIME_DISABLE_FLAG`时表示相对时间锁
+    pub sequence: u32,
 }
 
 impl TxInput {
-    pub fn new(txid: String, vout: usize, value: i64) -> Self {
+    pub fn new(txid: String, vout: usize, value: Amount) -> Self {
         debug!("创建新的交易输入: txid={}, vout={}, value={}", txid, vout, value);
         TxInput {
             txid,
             vout,
-            signature: Vec::new(),
-            pubkey: Vec::new(),
+            script_sig: Script::new(),
             value,
-        }
-    }
-
-    pub fn verify_signature(&self, data: &[u8]) -> Result<bool> {
-        debug!("验证交易输入签名: txid={}", self.txid);
-        
-        if self.signature.is_empty() || self.pubkey.is_empty() {
-            error!("缺少签名或公钥");
-            return Err(RustBtcError::InvalidSignature("缺少签名或公钥".to_string()));
-        }
-
-        let secp = secp256k1::Secp256k1::new();
-        
-        // 解析公钥
-        let public_key = secp256k1::PublicKey::from_slice(&self.pubkey)
-            .map_err(|e| {
-                error!("解析公钥失败: {}", e);
-                RustBtcError::InvalidPublicKey(e.to_string())
-            })?;
-
-        // 计算数据的哈希
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        let message_hash = hasher.finalize();
-        
-        // 创建消息对象
-        let message = secp256k1::Message::from_slice(&message_hash)
-            .map_err(|e| {
-                error!("创建消息对象失败: {}", e);
-                RustBtcError::InvalidMessage(e.to_string())
-            })?;
-
-        // 解析签名
-        let signature = ecdsa::Signature::from_compact(&self.signature)
-            .map_err(|e| {
-                error!("解析签名失败: {}", e);
-                RustBtcError::InvalidSignature(e.to_string())
-            })?;
-
-        // 验证签名
-        match secp.verify_ecdsa(&message, &signature, &public_key) {
-            Ok(_) => {
-                debug!("签名验证成功");
-                Ok(true)
-            }
-            Err(e) => {
-                error!("签名验证失败: {}", e);
-                Ok(false)
-            }
+            sequence: SEQUENCE_FINAL,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct TxOutput {
-    pub value: i64,
-    pub pubkey_hash: Vec<u8>,
+    pub value: Amount,
+    /// 锁定脚本，花费时需要满足
+    pub script_pubkey: Script,
 }
 
 impl TxOutput {
-    pub fn new(value: i64, address: &str) -> Result<Self> {
+    /// 创建支付到`address`的输出，P2PKH和P2SH地址分别生成对应的锁定脚本
+    pub fn new(value: Amount, address: &str) -> Result<Self> {
         debug!("创建新的交易输出: value={}, address={}", value, address);
         
-        if value <= 0 {
+        if value == Amount::ZERO || !value.is_in_money_range() {
             error!("交易输出金额必须大于0");
             return Err(RustBtcError::InvalidAmount(format!(
                 "交易输出金额 {} 无效",
@@ -100,15 +315,37 @@ impl TxOutput {
             )));
         }
 
-        let pubkey_hash = bs58::decode(address)
-            .into_vec()
-            .map_err(|e| RustBtcError::InvalidAddress(e.to_string()))?;
-
         Ok(TxOutput {
             value,
-            pubkey_hash,
+            script_pubkey: address_to_script_pubkey(address)?,
         })
     }
+
+    /// 以OP_RETURN开头的输出不可花费，不进入UTXO集，金额可以为0
+    pub fn is_unspendable(&self) -> bool {
+        self.script_pubkey.is_op_return()
+    }
+
+    /// 如果是见证承诺输出`OP_RETURN <WITNESS_COMMITMENT_HEADER || commitment>`，返回其中的32字节承诺
+    pub fn witness_commitment(&self) -> Option<&[u8]> {
+        let mut instructions = self.script_pubkey.instructions();
+        match (instructions.next(), instructions.next(), instructions.next()) {
+            (Some(Ok(Instruction::Op(Opcode::Return))), Some(Ok(Instruction::Push(data))), None) => data
+                .strip_prefix(&WITNESS_COMMITMENT_HEADER[..])
+                .filter(|commitment| commitment.len() == 32),
+            _ => None,
+        }
+    }
+
+    /// 金额是否有效：不超过`MAX_MONEY`，且只有不可花费的输出金额可以为0
+    pub fn has_valid_value(&self) -> bool {
+        self.value.is_in_money_range() && (self.value > Amount::ZERO || self.is_unspendable())
+    }
+
+    /// 输出是否是锁定到给定公钥哈希的P2PKH输出
+    pub fn is_locked_with_key(&self, pubkey_hash: &[u8]) -> bool {
+        self.script_pubkey.p2pkh_hash() == Some(pubkey_hash)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -116,33 +353,75 @@ pub struct Transaction {
     pub id: String,
     pub vin: Vec<TxInput>,
     pub vout: Vec<TxOutput>,
+    /// 绝对时间锁：小于`LOCKTIME_THRESHOLD`时为区块高度，否则为Unix时间戳，0表示不锁定
+    pub lock_time: u32,
 }
 
 impl Transaction {
+    /// 从`from_wallet`的P2PKH地址向`to_address`支付`amount`，另付`fee`作为手续费
     pub fn new(
         from_wallet: &Wallet,
         to_address: &str,
-        amount: i64,
+        amount: Amount,
+        fee: Amount,
         utxo_set: &UTXOSet,
     ) -> Result<Transaction> {
-        debug!("创建新的交易: from={}, to={}, amount={}", 
-            from_wallet.get_address(), to_address, amount);
+        debug!("创建新的交易: from={}, to={}, amount={}, fee={}", 
+            from_wallet.get_address(), to_address, amount, fee);
+
+        let mut tx = Self::unsigned(&from_wallet.get_address(), to_address, amount, fee, utxo_set)?;
         
-        if amount <= 0 {
+        // 签名交易
+        tx.sign(from_wallet)?;
+
+        debug!("交易创建成功: {}", tx.id);
+        Ok(tx)
+    }
+
+    /// 从多签账户的P2SH输出中支付，返回尚未签名的交易，由账户成员依次调用`sign_multisig`签名
+    pub fn new_multisig(
+        account: &MultisigAccount,
+        to_address: &str,
+        amount: Amount,
+        fee: Amount,
+        utxo_set: &UTXOSet,
+    ) -> Result<Transaction> {
+        debug!("创建多签交易: from={}, to={}, amount={}, fee={}", account.address(), to_address, amount, fee);
+        Self::unsigned(&account.address(), to_address, amount, fee, utxo_set)
+    }
+
+    /// 从`from_wallet`的P2TR地址中支付，找零回到该地址，所有输入以Schnorr签名
+    pub fn new_taproot(
+        from_wallet: &Wallet,
+        to_address: &str,
+        amount: Amount,
+        fee: Amount,
+        utxo_set: &UTXOSet,
+    ) -> Result<Transaction> {
+        let from_address = from_wallet.get_taproot_address()?;
+        debug!("创建P2TR交易: from={}, to={}, amount={}, fee={}", from_address, to_address, amount, fee);
+        let mut tx = Self::unsigned(&from_address, to_address, amount, fee, utxo_set)?;
+        tx.sign_taproot(from_wallet, SighashType::All)?;
+        Ok(tx)
+    }
+
+    /// 花费`from_address`的UTXO向`to_address`支付，扣除手续费后的找零回到`from_address`
+    fn unsigned(from_address: &str, to_address: &str, amount: Amount, fee: Amount, utxo_set: &UTXOSet) -> Result<Transaction> {
+        if amount == Amount::ZERO {
             error!("交易金额必须大于0");
             return Err(RustBtcError::InvalidAmount(format!(
                 "交易金额 {} 无效",
                 amount
             )));
         }
+        let required = Amount::money_sum([amount, fee]).ok_or_else(|| {
+            RustBtcError::InvalidAmount(format!("交易金额 {} 加手续费 {} 超过最大金额", amount, fee))
+        })?;
 
-        let utxos = utxo_set.find_spendable_outputs(&from_wallet.get_address(), amount)?;
+        let utxos = utxo_set.find_spendable_outputs(from_address, required)?;
         
-        let mut accumulated = 0;
         let mut inputs = Vec::new();
-        
         for utxo in utxos {
-            accumulated += utxo.value;
             inputs.push(TxInput::new(
                 utxo.txid,
                 utxo.vout,
@@ -150,13 +429,15 @@ impl Transaction {
             ));
         }
 
-        if accumulated < amount {
-            error!("余额不足: 需要 {}, 可用 {}", amount, accumulated);
+        let accumulated = Amount::money_sum(inputs.iter().map(|input| input.value))
+            .ok_or_else(|| RustBtcError::InvalidAmount("输入总额超过最大金额".to_string()))?;
+        let Some(change) = accumulated.checked_sub(required) else {
+            error!("余额不足: 需要 {}, 可用 {}", required, accumulated);
             return Err(RustBtcError::InsufficientFunds(format!(
                 "余额不足: 需要 {}, 可用 {}",
-                amount, accumulated
+                required, accumulated
             )));
-        }
+        };
 
         let mut outputs = Vec::new();
         
@@ -164,31 +445,28 @@ impl Transaction {
         outputs.push(TxOutput::new(amount, to_address)?);
         
         // 如果有找零，创建找零输出
-        if accumulated > amount {
-            outputs.push(TxOutput::new(
-                accumulated - amount - 1, // 扣除1个币作为手续费
-                &from_wallet.get_address(),
-            )?);
+        if change > Amount::ZERO {
+            outputs.push(TxOutput::new(change, from_address)?);
         }
 
         let mut tx = Transaction {
             id: String::new(),
             vin: inputs,
             vout: outputs,
+            lock_time: 0,
         };
 
         // 计算交易ID
-        tx.id = tx.hash()?;
-        
-        // 签名交易
-        tx.sign(from_wallet)?;
-
-        debug!("交易创建成功: {}", tx.id);
+        tx.id = tx.txid()?;
         Ok(tx)
     }
 
-    pub fn new_coinbase(to: &str, data: &str) -> Result<Transaction> {
-        debug!("创建coinbase交易: to={}, data={}", to, data);
+    /// 创建高度为`height`的区块的coinbase交易，金额为区块奖励加上区块内交易的手续费总额
+    pub fn new_coinbase(to: &str, data: &str, height: u64, fees: Amount, params: &ChainParams) -> Result<Transaction> {
+        debug!("创建coinbase交易: to={}, data={}, height={}, fees={}", to, data, height, fees);
+        let reward = Amount::money_sum([params.block_subsidy(height), fees]).ok_or_else(|| {
+            RustBtcError::InvalidAmount(format!("区块奖励加手续费 {} 超过最大金额", fees))
+        })?;
         
         let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
@@ -197,39 +475,87 @@ impl Transaction {
         let mut tx = Transaction {
             id: String::new(),
             vin: vec![TxInput::new(
-                format!("0_{}", timestamp), 
+                // 附加数据参与交易ID的计算，使同一时刻创建的coinbase交易互不相同
+                format!("0_{}_{}", timestamp, hex::encode(data)), 
                 0,
-                SUBSIDY,
+                reward,
             )],
-            vout: vec![TxOutput::new(SUBSIDY, to)?],
+            vout: vec![TxOutput::new(reward, to)?],
+            lock_time: 0,
         };
 
-        tx.id = tx.hash()?;
+        tx.id = tx.txid()?;
         debug!("coinbase交易创建成功: {}", tx.id);
         Ok(tx)
     }
 
-    pub fn hash(&self) -> Result<String> {
+    /// 交易ID：不含解锁脚本的交易数据的哈希。签名数据不影响交易ID，转发者替换签名也不会改变它
+    pub fn txid(&self) -> Result<String> {
+        self.hash_data(false)
+    }
+
+    /// 包含解锁脚本（见证数据）的交易哈希，由coinbase中的见证承诺提交到区块
+    pub fn wtxid(&self) -> Result<String> {
+        self.hash_data(true)
+    }
+
+    fn hash_data(&self, include_witness: bool) -> Result<String> {
         debug!("计算交易哈希");
         
-        // 创建一个副本用于计算哈希
+        // 创建一个副本用于计算哈希，交易ID字段本身不参与计算
         let mut tx = self.clone();
+        tx.id = String::new();
         
-        // 清除所有输入的签名和公钥
-        for input in tx.vin.iter_mut() {
-            input.signature = Vec::new();
-            input.pubkey = Vec::new();
+        // 清除所有输入的解锁脚本
+        if !include_witness {
+            for input in tx.vin.iter_mut() {
+                input.script_sig = Script::new();
+            }
         }
         
-        let data = bincode::serialize(&tx)
-            .map_err(|e| RustBtcError::Serialization(e))?;
+        let data = bincode::serialize(&tx)?;
             
         let mut hasher = Sha256::new();
         hasher.update(&data);
         Ok(hex::encode(hasher.finalize()))
     }
 
+    /// 是否带有见证数据，即非空的解锁脚本
+    pub fn has_witness(&self) -> bool {
+        self.vin.iter().any(|input| !input.script_sig.is_empty())
+    }
+
+    /// coinbase交易中的见证承诺，有多个时取最后一个
+    pub fn witness_commitment(&self) -> Option<&[u8]> {
+        self.vout.iter().rev().find_map(TxOutput::witness_commitment)
+    }
+
+    /// 在coinbase交易中写入见证承诺，替换已有的承诺并重新计算交易ID
+    pub fn set_witness_commitment(&mut self, commitment: &[u8; 32]) -> Result<()> {
+        if !self.is_coinbase() {
+            return Err(RustBtcError::InvalidTransaction(format!(
+                "交易 {} 不是coinbase交易，不能包含见证承诺",
+                self.id
+            )));
+        }
+
+        let mut data = WITNESS_COMMITMENT_HEADER.to_vec();
+        data.extend_from_slice(commitment);
+        self.vout.retain(|output| output.witness_commitment().is_none());
+        self.vout.push(TxOutput {
+            value: Amount::ZERO,
+            script_pubkey: Script::op_return(&data),
+        });
+        self.id = self.txid()?;
+        Ok(())
+    }
+
     pub fn sign(&mut self, wallet: &Wallet) -> Result<()> {
+        self.sign_with_type(wallet, SighashType::All)
+    }
+
+    /// 以给定签名类型为花费`wallet`的P2PKH输出的所有输入签名
+    pub fn sign_with_type(&mut self, wallet: &Wallet, sighash_type: SighashType) -> Result<()> {
         debug!("签名交易");
         
         if self.is_coinbase() {
@@ -237,105 +563,287 @@ impl Transaction {
             return Ok(());
         }
 
-        // 计算交易数据的哈希
-        let tx_hash = self.hash()?;
-        let hash_bytes = hex::decode(&tx_hash)
-            .map_err(|e| RustBtcError::HashError(e.to_string()))?;
+        // 被花费的P2PKH输出的锁定脚本
+        let script_code = Script::p2pkh(&hash_pub_key(wallet.get_public_key()));
 
         // 为每个输入签名
-        for input in self.vin.iter_mut() {
-            input.pubkey = wallet.get_public_key().to_vec();
+        for index in 0..self.vin.len() {
+            let signature = self.sign_input(index, wallet, &script_code, sighash_type)?;
+            self.vin[index].script_sig = Script::p2pkh_unlock(&signature, wallet.get_public_key());
             
-            // 使用钱包的sign方法进行签名
-            input.signature = wallet.sign(&hash_bytes)?;
-            
-            debug!("交易输入已签名: txid={}", input.txid);
+            debug!("交易输入已签名: txid={}", self.vin[index].txid);
+        }
+
+        Ok(())
+    }
+
+    /// 用`wallet`为第`index`个输入签名，返回末尾附加了签名类型的签名
+    pub fn sign_input(
+        &self,
+        index: usize,
+        wallet: &Wallet,
+        script_code: &Script,
+        sighash_type: SighashType,
+    ) -> Result<Vec<u8>> {
+        let mut signature = wallet.sign(&self.signature_hash(index, script_code, sighash_type)?)?;
+        signature.push(sighash_type.to_byte());
+        Ok(signature)
+    }
+
+    /// 以给定签名类型为花费`wallet`的P2TR输出的所有输入生成Schnorr签名
+    pub fn sign_taproot(&mut self, wallet: &Wallet, sighash_type: SighashType) -> Result<()> {
+        if self.is_coinbase() {
+            debug!("Coinbase交易无需签名");
+            return Ok(());
+        }
+
+        let script_code = Script::p2tr(&wallet.get_x_only_public_key()?);
+        for index in 0..self.vin.len() {
+            let signature = self.sign_schnorr_input(index, wallet, &script_code, sighash_type)?;
+            self.vin[index].script_sig = Script::new().push_slice(&signature);
+        }
+        Ok(())
+    }
+
+    /// 用`wallet`为第`index`个输入生成Schnorr签名，返回末尾附加了签名类型的签名
+    pub fn sign_schnorr_input(
+        &self,
+        index: usize,
+        wallet: &Wallet,
+        script_code: &Script,
+        sighash_type: SighashType,
+    ) -> Result<Vec<u8>> {
+        let mut signature = wallet.sign_schnorr(&self.signature_hash(index, script_code, sighash_type)?)?;
+        signature.push(sighash_type.to_byte());
+        Ok(signature)
+    }
+
+    /// 以`wallet`的私钥为多签账户的每个输入追加签名。解锁脚本中的签名按公钥在赎回脚本中的顺序排列，
+    /// 因此成员签名的先后不限；同一成员重复签名只保留一份，凑够`required`个签名后不再追加
+    pub fn sign_multisig(&mut self, wallet: &Wallet, account: &MultisigAccount) -> Result<()> {
+        let key_index = account.key_index(wallet.get_public_key()).ok_or_else(|| {
+            RustBtcError::WalletError(format!("钱包 {} 不是多签账户 {} 的成员", wallet.get_address(), account.address()))
+        })?;
+
+        let redeem_script = account.redeem_script();
+        let mut script_sigs = Vec::with_capacity(self.vin.len());
+        for (index, input) in self.vin.iter().enumerate() {
+            // 找出已有签名对应的公钥位置
+            let checker = TransactionSignatureChecker::new(self, index);
+            let mut slots: Vec<Option<Vec<u8>>> = vec![None; account.pubkeys().len()];
+            for existing in multisig_signatures(&input.script_sig, redeem_script) {
+                let position = account
+                    .pubkeys()
+                    .iter()
+                    .position(|pubkey| checker.check_sig(&existing, pubkey, redeem_script));
+                if let Some(position) = position {
+                    slots[position] = Some(existing);
+                }
+            }
+            if slots.iter().flatten().count() < account.required() {
+                slots[key_index] = Some(self.sign_input(index, wallet, redeem_script, SighashType::All)?);
+            }
+
+            // OP_CHECKMULTISIG会多弹出一个元素，因此以OP_0开头
+            script_sigs.push(
+                slots
+                    .into_iter()
+                    .flatten()
+                    .fold(Script::new().push_opcode(Opcode::Op0), |script, signature| script.push_slice(&signature))
+                    .push_slice(redeem_script.as_bytes()),
+            );
+            debug!("多签交易输入已签名: txid={}, 签名者序号={}", input.txid, key_index);
         }
 
+        for (input, script_sig) in self.vin.iter_mut().zip(script_sigs) {
+            input.script_sig = script_sig;
+        }
         Ok(())
     }
 
+    /// 第`index`个输入在给定签名类型下的签名哈希，`script_code`是被花费输出的锁定脚本或P2SH赎回脚本
+    pub fn signature_hash(&self, index: usize, script_code: &Script, sighash_type: SighashType) -> Result<Vec<u8>> {
+        sighash::signature_hash(self, index, script_code, sighash_type)
+    }
+
+    /// 验证第`index`个输入能否花费`spent_output`：解锁脚本与锁定脚本一起执行必须成功
+    pub fn verify_input(&self, index: usize, spent_output: &TxOutput) -> Result<()> {
+        let input = self.vin.get(index).ok_or_else(|| {
+            RustBtcError::InvalidInput(format!("交易 {} 没有第 {} 个输入", self.id, index))
+        })?;
+
+        let checker = TransactionSignatureChecker::new(self, index);
+        verify_script(&input.script_sig, &spent_output.script_pubkey, &checker).map_err(|e| {
+            error!("交易 {} 的第 {} 个输入脚本验证失败: {}", self.id, index, e);
+            RustBtcError::ScriptError(format!("交易 {} 的第 {} 个输入: {}", self.id, index, e))
+        })
+    }
+
+    /// 并行执行多笔交易中所有非coinbase输入的脚本，`spent_outputs[t][i]`是第t笔交易第i个输入花费的输出，
+    /// 其中的Schnorr签名在脚本执行完后统一验证。返回失败的输入中位置最靠前的（交易序号, 输入序号）和原因
+    pub fn find_invalid_input(
+        transactions: &[Transaction],
+        spent_outputs: &[Vec<TxOutput>],
+    ) -> Result<Option<(usize, usize, ScriptError)>> {
+        let checks: Vec<(usize, usize)> = transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| !tx.is_coinbase())
+            .flat_map(|(t, tx)| (0..tx.vin.len()).map(move |i| (t, i)))
+            .collect();
+
+        let deferred = ParallelSchnorr::new();
+        let script_failure = checks.par_iter().find_map_first(|&(t, i)| {
+            let spent_output = spent_outputs.get(t).and_then(|outputs| outputs.get(i))?;
+            let checker = TransactionSignatureChecker::with_deferred(&transactions[t], i, &deferred, t);
+            verify_script(&transactions[t].vin[i].script_sig, &spent_output.script_pubkey, &checker)
+                .err()
+                .map(|e| (t, i, e))
+        });
+
+        let schnorr_failure = deferred.verify().map(|(t, i)| (t, i, ScriptError::SchnorrSig));
+        Ok([script_failure, schnorr_failure]
+            .into_iter()
+            .flatten()
+            .min_by_key(|(t, i, _)| (*t, *i)))
+    }
+
     pub fn verify(&self, utxo_set: &UTXOSet) -> Result<bool> {
+        self.verify_spent_outputs(&self.spent_outputs(utxo_set)?)
+    }
+
+    /// 按各输入花费的输出验证交易：`spent_outputs[i]`是第i个输入花费的输出
+    pub fn verify_spent_outputs(&self, spent_outputs: &[TxOutput]) -> Result<bool> {
         // Coinbase 交易不需要验证
         if self.is_coinbase() {
             return Ok(true);
         }
+        if spent_outputs.len() != self.vin.len() {
+            return Err(RustBtcError::InvalidInput(format!(
+                "交易 {} 有 {} 个输入，但只提供了 {} 个花费的输出",
+                self.id, self.vin.len(), spent_outputs.len()
+            )));
+        }
+        let input_value = self.sum_spent(spent_outputs)?;
 
-        // 计算输入总额
-        let mut input_value = 0;
-        for input in &self.vin {
-            let output = utxo_set.find_transaction_output(&input.txid, input.vout)?;
-            input_value += output.value;
+        // 执行脚本，通过的签名进入缓存
+        if let Some((_, index, e)) =
+            Self::find_invalid_input(std::slice::from_ref(self), &[spent_outputs.to_vec()])?
+        {
+            return Err(RustBtcError::ScriptError(format!(
+                "交易 {} 的第 {} 个输入: {}",
+                self.id, index, e
+            )));
         }
 
         // 计算输出总额
-        let output_value: i64 = self.vout.iter().map(|out| out.value).sum();
+        let output_value = self
+            .output_total()
+            .ok_or_else(|| RustBtcError::InvalidAmount(format!("交易 {} 的输出总额超过最大金额", self.id)))?;
 
-        // 验证输入总额大于输出总额
-        if input_value <= output_value {
+        // 输出总额不能超过输入总额，手续费可以为0，与区块验证一致
+        if input_value < output_value {
             return Err(RustBtcError::InvalidAmount(format!(
-                "输入总额 {} 必须大于输出总额 {}",
-                input_value, output_value
+                "输出总额 {} 超过输入总额 {}",
+                output_value, input_value
             )));
         }
 
         Ok(true)
     }
 
-    pub fn verify_transaction_data(&self) -> Result<bool> {
-        debug!("验证交易数据: {}", self.id);
-        
-        // 验证输入和输出不为空
-        if self.vin.is_empty() || self.vout.is_empty() {
-            error!("交易输入或输出为空");
-            return Ok(false);
+    /// 各输入花费的输出，从`utxo_set`中查找
+    pub fn spent_outputs(&self, utxo_set: &UTXOSet) -> Result<Vec<TxOutput>> {
+        if self.is_coinbase() {
+            return Ok(Vec::new());
         }
+        self.vin
+            .iter()
+            .map(|input| utxo_set.find_transaction_output(&input.txid, input.vout))
+            .collect()
+    }
 
-        // 验证输出金额
-        for output in &self.vout {
-            if output.value <= 0 {
-                error!("交易输出金额无效: {}", output.value);
-                return Ok(false);
-            }
-        }
+    fn sum_spent(&self, spent_outputs: &[TxOutput]) -> Result<Amount> {
+        Amount::money_sum(spent_outputs.iter().map(|output| output.value))
+            .ok_or_else(|| RustBtcError::InvalidAmount(format!("交易 {} 的输入总额超过最大金额", self.id)))
+    }
 
-        // 验证输入总额大于输出总额
-        let input_total: i64 = self.vin.iter().map(|input| input.value).sum();
-        let output_total: i64 = self.vout.iter().map(|output| output.value).sum();
-        if input_total <= output_total {
-            error!("输入总额 {} 必须大于输出总额 {}", input_total, output_total);
-            return Ok(false);
-        }
+    /// 输入花费的输出的金额总和。以UTXO集中的记录为准，不使用输入中自报的金额
+    pub fn input_total(&self, utxo_set: &UTXOSet) -> Result<Amount> {
+        self.sum_spent(&self.spent_outputs(utxo_set)?)
+    }
 
-        debug!("交易数据验证通过");
-        Ok(true)
+    /// 输出金额总和，任一输出或总和超过`MAX_MONEY`时返回None
+    pub fn output_total(&self) -> Option<Amount> {
+        Amount::money_sum(self.vout.iter().map(|output| output.value))
+    }
+
+    /// 交易手续费：花费的输出总额减去输出总额，coinbase交易没有手续费
+    pub fn fee(&self, utxo_set: &UTXOSet) -> Result<Amount> {
+        if self.is_coinbase() {
+            return Ok(Amount::ZERO);
+        }
+        let input_total = self.input_total(utxo_set)?;
+        let output_total = self
+            .output_total()
+            .ok_or_else(|| RustBtcError::InvalidAmount(format!("交易 {} 的输出总额超过最大金额", self.id)))?;
+        input_total.checked_sub(output_total).ok_or_else(|| {
+            RustBtcError::InvalidAmount(format!("输出总额 {} 超过输入总额 {}", output_total, input_total))
+        })
     }
 
-    pub fn calculate_fee_rate(&self) -> f64 {
+    pub fn calculate_fee_rate(&self, utxo_set: &UTXOSet) -> Result<f64> {
         debug!("计算交易费率: {}", self.id);
         
         if self.is_coinbase() {
-            return 0.0;
+            return Ok(0.0);
         }
 
-        let mut input_value = 0;
-        for input in &self.vin {
-            input_value += input.value;
+        let fee = self.fee(utxo_set)?;
+        let size = bincode::serialize(self).unwrap_or_default().len() as f64;
+        
+        if size > 0.0 {
+            Ok(fee.to_sat() as f64 / size)
+        } else {
+            Ok(0.0)
         }
+    }
 
-        let mut output_value = 0;
-        for output in &self.vout {
-            output_value += output.value;
+    /// 交易能否被打包进高度为`height`的区块，`median_time_past`是该区块之前的中位时间
+    pub fn is_final(&self, height: u64, median_time_past: u32) -> bool {
+        if self.lock_time == 0 {
+            return true;
         }
-
-        let fee = input_value - output_value;
-        let size = bincode::serialize(self).unwrap_or(Vec::new()).len() as f64;
-        
-        if size > 0.0 {
-            fee as f64 / size
+        let limit = if self.lock_time < LOCKTIME_THRESHOLD {
+            height
         } else {
-            0.0
+            median_time_past as u64
+        };
+        if (self.lock_time as u64) < limit {
+            return true;
         }
+        self.vin.iter().all(|input| input.sequence == SEQUENCE_FINAL)
+    }
+
+    /// 根据各输入花费的UTXO计算相对时间锁，`coins[i]`是第i个输入花费的UTXO
+    pub fn sequence_lock(&self, coins: &[&Coin]) -> SequenceLock {
+        let mut lock = SequenceLock { min_height: -1, min_time: -1 };
+        if self.is_coinbase() {
+            return lock;
+        }
+        for (input, coin) in self.vin.iter().zip(coins) {
+            if input.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+                continue;
+            }
+            let value = (input.sequence & SEQUENCE_LOCKTIME_MASK) as i64;
+            if input.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+                let min_time = coin.median_time_past as i64 + (value << SEQUENCE_LOCKTIME_GRANULARITY) - 1;
+                lock.min_time = lock.min_time.max(min_time);
+            } else {
+                lock.min_height = lock.min_height.max(coin.height as i64 + value - 1);
+            }
+        }
+        lock
     }
 
     pub fn is_coinbase(&self) -> bool {
@@ -346,7 +854,8 @@ impl Transaction {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::wallet::Wallet;
+    use crate::sighash::{SIGHASH_ALL, SIGHASH_ANYONECANPAY};
+    use crate::wallet::{script_address, Wallet};
 
     fn create_test_wallet() -> Result<Wallet> {
         Wallet::new()
@@ -356,7 +865,7 @@ mod tests {
     fn test_new_coinbase_transaction() -> Result<()> {
         let wallet = create_test_wallet()?;
         let address = wallet.get_address();
-        let tx = Transaction::new_coinbase(&address, "Test Coinbase")?;
+        let tx = Transaction::new_coinbase(&address, "Test Coinbase", 0, Amount::ZERO, &ChainParams::default())?;
         
         assert!(tx.is_coinbase());
         assert_eq!(tx.vin.len(), 1);
@@ -370,24 +879,402 @@ mod tests {
     fn test_transaction_hash() -> Result<()> {
         let wallet = create_test_wallet()?;
         let address = wallet.get_address();
-        let tx = Transaction::new_coinbase(&address, "Test Hash")?;
+        let tx = Transaction::new_coinbase(&address, "Test Hash", 0, Amount::ZERO, &ChainParams::default())?;
         
-        let hash = tx.hash()?;
+        let hash = tx.txid()?;
         assert!(!hash.is_empty());
         assert_eq!(hash.len(), 64);  // SHA-256 produces 32 bytes = 64 hex chars
         
         Ok(())
     }
 
+    #[test]
+    fn test_txid_excludes_signatures() -> Result<()> {
+        let wallet = create_test_wallet()?;
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TxInput::new("ab".repeat(32), 0, Amount::from_sat(50))],
+            vout: vec![TxOutput::new(Amount::from_sat(40), &wallet.get_address())?],
+            lock_time: 0,
+        };
+        tx.id = tx.txid()?;
+        let unsigned_wtxid = tx.wtxid()?;
+
+        // 签名和交易ID字段不影响交易ID，但改变wtxid
+        tx.sign(&wallet)?;
+        assert_eq!(tx.txid()?, tx.id);
+        assert_ne!(tx.wtxid()?, unsigned_wtxid);
+
+        let mut resigned = tx.clone();
+        resigned.sign_with_type(&wallet, SighashType::AllAnyoneCanPay)?;
+        assert_eq!(resigned.txid()?, tx.txid()?);
+        assert_ne!(resigned.wtxid()?, tx.wtxid()?);
+
+        Ok(())
+    }
+
     #[test]
     fn test_transaction_fee_rate() -> Result<()> {
         let wallet = create_test_wallet()?;
         let address = wallet.get_address();
-        let tx = Transaction::new_coinbase(&address, "Test Fee Rate")?;
+        let tx = Transaction::new_coinbase(&address, "Test Fee Rate", 0, Amount::ZERO, &ChainParams::default())?;
         
-        let fee_rate = tx.calculate_fee_rate();
+        let fee_rate = tx.calculate_fee_rate(&UTXOSet::new())?;
         assert!(fee_rate >= 0.0);
         
         Ok(())
     }
+
+    #[test]
+    fn test_fee_uses_spent_outputs() -> Result<()> {
+        let wallet = create_test_wallet()?;
+        let funding = Transaction::new_coinbase(&wallet.get_address(), "Test Fee", 0, Amount::ZERO, &ChainParams::default())?;
+        let mut utxo_set = UTXOSet::new();
+        utxo_set.update(std::slice::from_ref(&funding))?;
+        let spend = |declared: Amount, output: Amount| -> Result<Transaction> {
+            let mut tx = Transaction {
+                id: String::new(),
+                vin: vec![TxInput::new(funding.id.clone(), 0, declared)],
+                vout: vec![TxOutput::new(output, &wallet.get_address())?],
+                lock_time: 0,
+            };
+            tx.id = tx.txid()?;
+            tx.sign(&wallet)?;
+            Ok(tx)
+        };
+        let btc = |n| Amount::from_btc(n).unwrap();
+
+        // 输入中自报的金额不影响手续费
+        let inflated = spend(btc(80), btc(40))?;
+        assert_eq!(inflated.input_total(&utxo_set)?, btc(50));
+        assert_eq!(inflated.fee(&utxo_set)?, btc(10));
+        assert!(spend(btc(80), btc(60))?.fee(&utxo_set).is_err());
+
+        // 0手续费的交易可以通过验证
+        let zero_fee = spend(btc(50), btc(50))?;
+        assert_eq!(zero_fee.fee(&utxo_set)?, Amount::ZERO);
+        assert!(zero_fee.verify(&utxo_set)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_input() -> Result<()> {
+        let owner = Wallet::new()?;
+        let thief = Wallet::new()?;
+        let prev = TxOutput::new(Amount::from_sat(50), &owner.get_address())?;
+
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TxInput::new("ab".repeat(32), 0, Amount::from_sat(50))],
+            vout: vec![TxOutput::new(Amount::from_sat(40), &thief.get_address())?],
+            lock_time: 0,
+        };
+        tx.id = tx.txid()?;
+        tx.sign(&owner)?;
+        tx.verify_input(0, &prev)?;
+
+        let script_failure = |result: Result<()>| match result {
+            Err(RustBtcError::ScriptError(message)) => message,
+            other => panic!("期望脚本验证失败，实际: {:?}", other),
+        };
+
+        // 修改输出后签名失效
+        let mut tampered = tx.clone();
+        tampered.vout[0].value = Amount::from_sat(45);
+        assert!(script_failure(tampered.verify_input(0, &prev)).ends_with(&ScriptError::EvalFalse.to_string()));
+
+        // 用其他公钥签名不能花费该输出
+        let mut stolen = tx.clone();
+        stolen.sign(&thief)?;
+        assert!(script_failure(stolen.verify_input(0, &prev)).ends_with(&ScriptError::EqualVerify.to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sign_anyone_can_pay() -> Result<()> {
+        let owner = Wallet::new()?;
+        let other = Wallet::new()?;
+        let prev = TxOutput::new(Amount::from_sat(50), &owner.get_address())?;
+
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TxInput::new("ab".repeat(32), 0, Amount::from_sat(50))],
+            vout: vec![TxOutput::new(Amount::from_sat(60), &owner.get_address())?],
+            lock_time: 0,
+        };
+        tx.sign_with_type(&owner, SighashType::AllAnyoneCanPay)?;
+        let (&type_byte, _) = tx.vin[0].script_sig.instructions().next().and_then(|instruction| match instruction {
+            Ok(Instruction::Push(signature)) => signature.split_last(),
+            _ => None,
+        }).unwrap();
+        assert_eq!(type_byte, SIGHASH_ALL | SIGHASH_ANYONECANPAY);
+
+        // 其他人追加输入后，已有签名仍然有效；修改输出则失效
+        let mut joined = tx.clone();
+        joined.vin.push(TxInput::new("cd".repeat(32), 1, Amount::from_sat(10)));
+        joined.vin[1].script_sig = Script::p2pkh_unlock(
+            &joined.sign_input(1, &other, &Script::p2pkh(&hash_pub_key(other.get_public_key())), SighashType::All)?,
+            other.get_public_key(),
+        );
+        joined.verify_input(0, &prev)?;
+        joined.verify_input(1, &TxOutput::new(Amount::from_sat(10), &other.get_address())?)?;
+
+        joined.vout[0].value = Amount::from_sat(59);
+        assert!(joined.verify_input(0, &prev).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_invalid_input_fills_cache() -> Result<()> {
+        let owner = Wallet::new()?;
+        let prev = TxOutput::new(Amount::from_sat(50), &owner.get_address())?;
+        let mut txs = Vec::new();
+        for i in 0..3u8 {
+            let mut tx = Transaction {
+                id: String::new(),
+                vin: vec![TxInput::new(hex::encode([i; 32]), 0, Amount::from_sat(50))],
+                vout: vec![TxOutput::new(Amount::from_sat(40), &owner.get_address())?],
+                lock_time: 0,
+            };
+            tx.id = tx.txid()?;
+            tx.sign(&owner)?;
+            txs.push(tx);
+        }
+
+        let sighash = txs[1].signature_hash(0, &prev.script_pubkey, SighashType::All)?;
+        let spent_outputs = vec![vec![prev]; txs.len()];
+
+        assert_eq!(Transaction::find_invalid_input(&txs, &spent_outputs)?, None);
+        let pushes: Vec<&[u8]> = txs[1].vin[0]
+            .script_sig
+            .instructions()
+            .filter_map(|instruction| match instruction {
+                Ok(Instruction::Push(data)) => Some(data),
+                _ => None,
+            })
+            .collect();
+        // 缓存中的签名不含末尾的签名类型
+        let (&type_byte, signature) = pushes[0].split_last().unwrap();
+        assert_eq!(SighashType::from_byte(type_byte), Some(SighashType::All));
+        assert!(signature_cache().contains(&sighash, pushes[1], signature));
+
+        // 篡改的交易不会命中缓存
+        txs[2].vout[0].value = Amount::from_sat(45);
+        assert_eq!(
+            Transaction::find_invalid_input(&txs, &spent_outputs)?,
+            Some((2, 0, ScriptError::EvalFalse))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_spend_p2sh_output() -> Result<()> {
+        let redeem_script = Script::hash_lock(&Sha256::digest(b"secret"));
+        let prev = TxOutput::new(Amount::from_sat(50), &script_address(&redeem_script))?;
+        assert_eq!(prev.script_pubkey, redeem_script.to_p2sh());
+
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TxInput::new("cd".repeat(32), 0, Amount::from_sat(50))],
+            vout: vec![TxOutput::new(Amount::from_sat(40), &Wallet::new()?.get_address())?],
+            lock_time: 0,
+        };
+        tx.id = tx.txid()?;
+        tx.vin[0].script_sig = Script::new().push_slice(b"secret").push_slice(redeem_script.as_bytes());
+        tx.verify_input(0, &prev)?;
+
+        tx.vin[0].script_sig = Script::new().push_slice(b"guess").push_slice(redeem_script.as_bytes());
+        assert!(matches!(tx.verify_input(0, &prev), Err(RustBtcError::ScriptError(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_taproot_spend() -> Result<()> {
+        let owner = Wallet::new()?;
+        let recipient = Wallet::new()?.get_address();
+        let address = owner.get_taproot_address()?;
+
+        let funding = Transaction::new_coinbase(&address, "taproot", 0, Amount::ZERO, &ChainParams::default())?;
+        assert_eq!(funding.vout[0].script_pubkey.p2tr_key(), Some(owner.get_x_only_public_key()?.as_slice()));
+        let mut utxo_set = UTXOSet::new();
+        utxo_set.update(std::slice::from_ref(&funding))?;
+        assert_eq!(utxo_set.get_balance(&address)?, SUBSIDY);
+
+        // 解锁脚本只有一个64字节签名加签名类型
+        let tx = Transaction::new_taproot(&owner, &recipient, Amount::from_sat(30), Amount::ONE_SAT, &utxo_set)?;
+        assert_eq!(tx.vin[0].script_sig.len(), 1 + 64 + 1);
+        assert!(tx.verify(&utxo_set)?);
+
+        let mut tampered = tx.clone();
+        tampered.vout[0].value = Amount::from_sat(31);
+        assert!(tampered.verify_input(0, &funding.vout[0]).unwrap_err().to_string().contains(&ScriptError::SchnorrSig.to_string()));
+
+        // 推迟验证时报告无效签名所在的交易
+        let txs = vec![tx.clone(), tampered, tx];
+        let spent_outputs = vec![funding.vout.clone(); txs.len()];
+        assert_eq!(
+            Transaction::find_invalid_input(&txs, &spent_outputs)?,
+            Some((1, 0, ScriptError::SchnorrSig))
+        );
+        assert_eq!(Transaction::find_invalid_input(&txs[2..], &spent_outputs[2..])?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multisig_spend() -> Result<()> {
+        let members: Vec<Wallet> = (0..3).map(|_| Wallet::new()).collect::<Result<_>>()?;
+        let account = MultisigAccount::new(2, members.iter().map(|w| w.get_public_key().to_vec()).collect())?;
+        let recipient = Wallet::new()?.get_address();
+
+        let funding = Transaction::new_coinbase(&account.address(), "treasury", 0, Amount::ZERO, &ChainParams::default())?;
+        let mut utxo_set = UTXOSet::new();
+        utxo_set.update(std::slice::from_ref(&funding))?;
+        assert_eq!(utxo_set.get_balance(&account.address())?, SUBSIDY);
+
+        let unsigned = Transaction::new_multisig(&account, &recipient, Amount::from_sat(30), Amount::ONE_SAT, &utxo_set)?;
+
+        // 只有一个签名
+        let mut tx = unsigned.clone();
+        tx.sign_multisig(&members[2], &account)?;
+        assert!(matches!(tx.verify(&utxo_set), Err(RustBtcError::ScriptError(_))));
+
+        // 同一成员重复签名不算两个签名
+        tx.sign_multisig(&members[2], &account)?;
+        assert!(matches!(tx.verify(&utxo_set), Err(RustBtcError::ScriptError(_))));
+
+        // 后签名的成员排在公钥顺序靠前的位置
+        tx.sign_multisig(&members[0], &account)?;
+        assert!(tx.verify(&utxo_set)?);
+
+        // 已凑够签名后再签名不改变解锁脚本
+        let complete = tx.vin[0].script_sig.clone();
+        tx.sign_multisig(&members[1], &account)?;
+        assert_eq!(tx.vin[0].script_sig, complete);
+
+        // 签名顺序与公钥顺序不一致
+        let signatures = multisig_signatures(&complete, account.redeem_script());
+        let mut reordered = tx.clone();
+        reordered.vin[0].script_sig = Script::new()
+            .push_opcode(Opcode::Op0)
+            .push_slice(&signatures[1])
+            .push_slice(&signatures[0])
+            .push_slice(account.redeem_script().as_bytes());
+        assert!(matches!(reordered.verify(&utxo_set), Err(RustBtcError::ScriptError(_))));
+
+        // 重复提供同一个签名
+        let mut duplicated = tx.clone();
+        duplicated.vin[0].script_sig = Script::new()
+            .push_opcode(Opcode::Op0)
+            .push_slice(&signatures[0])
+            .push_slice(&signatures[0])
+            .push_slice(account.redeem_script().as_bytes());
+        assert!(matches!(duplicated.verify(&utxo_set), Err(RustBtcError::ScriptError(_))));
+
+        // 非成员不能签名
+        let mut outsider = unsigned.clone();
+        assert!(matches!(outsider.sign_multisig(&Wallet::new()?, &account), Err(RustBtcError::WalletError(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_final_and_sequence_lock() -> Result<()> {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TxInput::new("ab".repeat(32), 0, Amount::from_sat(50)), TxInput::new("cd".repeat(32), 0, Amount::from_sat(50))],
+            vout: vec![TxOutput::new(Amount::from_sat(90), &Wallet::new()?.get_address())?],
+            lock_time: 100,
+        };
+
+        // 所有输入的序列号都是最终值时lock_time不生效
+        assert!(tx.is_final(50, 0));
+        tx.vin[0].sequence = 0;
+        assert!(!tx.is_final(100, 0));
+        assert!(tx.is_final(101, 0));
+
+        tx.lock_time = LOCKTIME_THRESHOLD + 10;
+        assert!(!tx.is_final(1_000, LOCKTIME_THRESHOLD + 10));
+        assert!(tx.is_final(1_000, LOCKTIME_THRESHOLD + 11));
+
+        let coin = |height: u64, median_time_past: u32| Coin {
+            output: tx.vout[0].clone(),
+            height,
+            is_coinbase: false,
+            median_time_past,
+        };
+        let old = coin(10, 1_000);
+        let young = coin(20, 5_000);
+
+        // 第一个输入锁3个区块，第二个输入锁2个512秒
+        tx.vin[0].sequence = 3;
+        tx.vin[1].sequence = SEQUENCE_LOCKTIME_TYPE_FLAG | 2;
+        let lock = tx.sequence_lock(&[&old, &young]);
+        assert_eq!(lock, SequenceLock { min_height: 12, min_time: 5_000 + 1024 - 1 });
+        assert!(!lock.is_satisfied(12, 10_000));
+        assert!(!lock.is_satisfied(13, 6_023));
+        assert!(lock.is_satisfied(13, 6_024));
+
+        tx.vin[1].sequence |= SEQUENCE_LOCKTIME_DISABLE_FLAG;
+        assert_eq!(tx.sequence_lock(&[&old, &young]).min_time, -1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_timelock_opcodes() -> Result<()> {
+        let owner = Wallet::new()?;
+        let p2pkh = |script: Script| {
+            script
+                .push_opcode(Opcode::Drop)
+                .push_opcode(Opcode::Dup)
+                .push_opcode(Opcode::Hash160)
+                .push_slice(&crate::wallet::hash_pub_key(owner.get_public_key()))
+                .push_opcode(Opcode::EqualVerify)
+                .push_opcode(Opcode::CheckSig)
+        };
+        let cltv = TxOutput {
+            value: Amount::from_sat(50),
+            script_pubkey: p2pkh(Script::new().push_int(100).push_opcode(Opcode::CheckLockTimeVerify)),
+        };
+        let csv = TxOutput {
+            value: Amount::from_sat(50),
+            script_pubkey: p2pkh(Script::new().push_int(2).push_opcode(Opcode::CheckSequenceVerify)),
+        };
+
+        // 签名覆盖的是带时间锁的完整锁定脚本
+        let spend = |prev: &TxOutput, lock_time: u32, sequence: u32| -> Result<Transaction> {
+            let mut tx = Transaction {
+                id: String::new(),
+                vin: vec![TxInput { sequence, ..TxInput::new("ab".repeat(32), 0, Amount::from_sat(50)) }],
+                vout: vec![TxOutput::new(Amount::from_sat(40), &owner.get_address())?],
+                lock_time,
+            };
+            tx.id = tx.txid()?;
+            let signature = tx.sign_input(0, &owner, &prev.script_pubkey, SighashType::All)?;
+            tx.vin[0].script_sig = Script::p2pkh_unlock(&signature, owner.get_public_key());
+            Ok(tx)
+        };
+        let unsatisfied = |result: Result<()>| match result {
+            Err(RustBtcError::ScriptError(message)) => message.ends_with(&ScriptError::UnsatisfiedLockTime.to_string()),
+            _ => false,
+        };
+
+        spend(&cltv, 100, 0)?.verify_input(0, &cltv)?;
+        assert!(unsatisfied(spend(&cltv, 99, 0)?.verify_input(0, &cltv)));
+        assert!(unsatisfied(spend(&cltv, LOCKTIME_THRESHOLD, 0)?.verify_input(0, &cltv)));
+        assert!(unsatisfied(spend(&cltv, 100, SEQUENCE_FINAL)?.verify_input(0, &cltv)));
+
+        spend(&csv, 0, 2)?.verify_input(0, &csv)?;
+        assert!(unsatisfied(spend(&csv, 0, 1)?.verify_input(0, &csv)));
+        assert!(unsatisfied(spend(&csv, 0, SEQUENCE_LOCKTIME_TYPE_FLAG | 2)?.verify_input(0, &csv)));
+        assert!(unsatisfied(spend(&csv, 0, SEQUENCE_FINAL)?.verify_input(0, &csv)));
+
+        Ok(())
+    }
 }
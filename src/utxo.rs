@@ -1,230 +1,502 @@
-use std::collections::HashMap;
-use std::fs;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
 
+use lru::LruCache;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info, warn};
 
+use crate::amount::Amount;
+use crate::block::Block;
 use crate::error::{Result, RustBtcError};
+use crate::storage::{Storage, StorageBatch};
 use crate::transaction::{Transaction, TxInput, TxOutput};
+use crate::wallet::address_to_script_pubkey;
 
-const UTXO_TREE_FILE: &str = "data/utxo.dat";
+/// UTXO集中的一项：未花费的输出及其创建信息
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Coin {
+    pub output: TxOutput,
+    /// 创建该输出的区块高度
+    pub height: u64,
+    pub is_coinbase: bool,
+    /// 创建该输出的区块之前的中位时间，时间类型的相对时间锁从这里开始计算
+    pub median_time_past: u32,
+}
+
+impl Coin {
+    /// 在高度`spend_height`的区块中是否可以花费该输出：coinbase输出需要经过`maturity`个区块
+    pub fn is_mature(&self, spend_height: u64, maturity: u64) -> bool {
+        !self.is_coinbase || spend_height >= self.height + maturity
+    }
+}
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// 被区块花费的一个输出，用于断开区块时恢复
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SpentCoin {
+    pub txid: String,
+    pub vout: usize,
+    pub coin: Coin,
+}
+
+/// 区块的撤销数据：按交易和输入的顺序记录该区块花费的所有输出
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct BlockUndo {
+    pub spent: Vec<SpentCoin>,
+}
+
+impl BlockUndo {
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self)
+            .map_err(|e| e.into())
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        bincode::deserialize(data)
+            .map_err(|e| e.into())
+    }
+}
+
+/// 默认最多缓存的存储中UTXO条目数
+pub const DEFAULT_UTXO_CACHE_SIZE: usize = 100_000;
+/// 默认最多保留的未写入修改数，超过后在可以单独写入的时机（如重建UTXO集时）写入存储
+pub const DEFAULT_MAX_PENDING_CHANGES: usize = 100_000;
+
+/// 输出点：交易ID和输出序号
+type OutPoint = (String, usize);
+
+/// UTXO集。有存储时，条目保存在存储的`utxos`树中，内存里只有尚未写入的修改和一个有界的读缓存；
+/// 没有存储时，修改集合就是整个UTXO集
 pub struct UTXOSet {
-    utxos: HashMap<String, Vec<(usize, TxOutput)>>,
+    /// 尚未写入存储的修改，`None`表示该输出已被花费
+    changes: HashMap<OutPoint, Option<Coin>>,
+    /// 从存储中读到的未修改条目，容量满时淘汰最久未使用的项
+    cache: Mutex<LruCache<OutPoint, Coin>>,
+    storage: Option<Arc<Storage>>,
+    /// 未写入修改数的上限，由`flush_if_needed`检查
+    max_pending_changes: usize,
+    /// 存储中的地址索引是否与存储中的UTXO条目一致，一致时按地址查询不需要遍历整个UTXO集
+    address_index: bool,
+    /// 最后连接的区块高度
+    tip_height: Option<u64>,
 }
 
 impl UTXOSet {
     pub fn new() -> Self {
         debug!("创建新的UTXO集");
         UTXOSet {
-            utxos: HashMap::new(),
+            changes: HashMap::new(),
+            cache: Mutex::new(LruCache::new(NonZeroUsize::MIN)),
+            storage: None,
+            max_pending_changes: DEFAULT_MAX_PENDING_CHANGES,
+            address_index: false,
+            tip_height: None,
+        }
+    }
+
+    /// 以`storage`中已写入的UTXO为基础的UTXO集，`tip_height`是这些UTXO对应的区块高度
+    pub fn with_storage(storage: Arc<Storage>, cache_size: usize, tip_height: Option<u64>) -> Self {
+        debug!("从存储打开UTXO集，缓存容量: {}", cache_size);
+        let capacity = NonZeroUsize::new(cache_size).unwrap_or(NonZeroUsize::MIN);
+        UTXOSet {
+            changes: HashMap::new(),
+            cache: Mutex::new(LruCache::new(capacity)),
+            storage: Some(storage),
+            max_pending_changes: DEFAULT_MAX_PENDING_CHANGES,
+            address_index: false,
+            tip_height,
         }
     }
 
     pub fn update(&mut self, block_txs: &[Transaction]) -> Result<()> {
         debug!("更新UTXO集，处理 {} 笔交易", block_txs.len());
-        
-        for tx in block_txs {
-            if !tx.is_coinbase() {
-                debug!("处理非coinbase交易: {}", tx.id);
-                // 移除已花费的输出
-                for input in &tx.vin {
-                    if let Some(outputs) = self.utxos.get_mut(&input.txid) {
-                        debug!("移除已花费的UTXO: txid={}, vout={}", input.txid, input.vout);
-                        outputs.retain(|(vout, _)| *vout != input.vout);
-                        if outputs.is_empty() {
-                            self.utxos.remove(&input.txid);
-                        }
-                    }
+        self.connect_transactions(block_txs, 0, 0)?;
+        self.flush_if_needed()?;
+        info!("UTXO集更新完成，当前有 {} 个未写入的修改", self.changes.len());
+        Ok(())
+    }
+
+    /// 将区块应用到UTXO集，返回断开该区块所需的撤销数据。任一输入不存在时UTXO集保持不变。
+    /// `median_time_past`是该区块之前的中位时间，记录在区块创建的每个输出上
+    pub fn connect_block(&mut self, block: &Block, median_time_past: u32) -> Result<BlockUndo> {
+        debug!("连接区块 {} 到UTXO集，高度: {}", block.hash, block.height);
+        let undo = self.connect_transactions(&block.transactions, block.height, median_time_past)?;
+        self.tip_height = Some(block.height);
+        Ok(undo)
+    }
+
+    /// 利用撤销数据断开区块：删除区块创建的输出并恢复其花费的输出
+    pub fn disconnect_block(&mut self, block: &Block, undo: &BlockUndo) -> Result<()> {
+        debug!("从UTXO集断开区块 {}，高度: {}", block.hash, block.height);
+        let mut spent = undo.spent.clone();
+        self.disconnect_transactions(&block.transactions, &mut spent)?;
+        if !spent.is_empty() {
+            return Err(RustBtcError::UTXOError(format!(
+                "区块 {} 的撤销数据多出 {} 项",
+                block.hash, spent.len()
+            )));
+        }
+        self.tip_height = block.height.checked_sub(1);
+        Ok(())
+    }
+
+    /// 下一个区块的高度，即新交易被打包时的花费高度
+    pub fn next_height(&self) -> u64 {
+        self.tip_height.map_or(0, |height| height + 1)
+    }
+
+    /// 把尚未写入的修改加入`batch`，与链状态一起原子写入后需要调用`mark_flushed`
+    pub fn flush_into(&self, batch: &mut StorageBatch) -> Result<()> {
+        if self.storage.is_none() {
+            return Ok(());
+        }
+        for ((txid, vout), coin) in &self.changes {
+            match coin {
+                Some(coin) => batch.save_utxo(txid, *vout, coin)?,
+                None => batch.delete_utxo(txid, *vout),
+            }
+        }
+        Ok(())
+    }
+
+    /// `flush_into`加入的修改已经写入存储
+    pub fn mark_flushed(&mut self) {
+        if self.storage.is_some() {
+            self.changes.clear();
+        }
+    }
+
+    /// 单独写入尚未写入的修改
+    pub fn flush(&mut self) -> Result<()> {
+        let Some(storage) = self.storage.clone() else {
+            return Ok(());
+        };
+        let mut batch = StorageBatch::new();
+        self.flush_into(&mut batch)?;
+        storage.write(batch)?;
+        self.mark_flushed();
+        Ok(())
+    }
+
+    /// 未写入的修改超过上限时单独写入存储。只能在不需要与其他数据原子写入的时机调用
+    pub fn flush_if_needed(&mut self) -> Result<()> {
+        if self.storage.is_some() && self.changes.len() >= self.max_pending_changes {
+            debug!("未写入的修改达到 {} 个，写入存储", self.changes.len());
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn set_max_pending_changes(&mut self, max_pending_changes: usize) {
+        self.max_pending_changes = max_pending_changes.max(1);
+    }
+
+    /// 按地址查询时是否使用存储中的地址索引。只有与UTXO修改写入同一批次的维护者（即`Blockchain`）才能保证两者一致
+    pub fn set_address_index(&mut self, enabled: bool) {
+        self.address_index = enabled && self.storage.is_some();
+    }
+
+    /// 尚未写入存储的修改数量
+    pub fn pending_changes(&self) -> usize {
+        self.changes.len()
+    }
+
+    fn connect_transactions(&mut self, txs: &[Transaction], height: u64, median_time_past: u32) -> Result<BlockUndo> {
+        let mut undo = BlockUndo::default();
+        for (i, tx) in txs.iter().enumerate() {
+            if let Err(e) = self.connect_transaction(tx, height, median_time_past, &mut undo) {
+                let mut spent = undo.spent;
+                self.disconnect_transactions(&txs[..i], &mut spent)?;
+                return Err(e);
+            }
+        }
+        Ok(undo)
+    }
+
+    fn connect_transaction(
+        &mut self,
+        tx: &Transaction,
+        height: u64,
+        median_time_past: u32,
+        undo: &mut BlockUndo,
+    ) -> Result<()> {
+        // BIP30：交易的输出不能覆盖尚未花费的同名输出
+        for vout in 0..tx.vout.len() {
+            if self.exists_utxo(&tx.id, vout)? {
+                error!("交易 {} 的输出 {} 会覆盖尚未花费的UTXO", tx.id, vout);
+                return Err(RustBtcError::UTXOError(format!(
+                    "UTXO已存在且未花费: txid={}, vout={}",
+                    tx.id, vout
+                )));
+            }
+        }
+
+        if !tx.is_coinbase() {
+            debug!("处理非coinbase交易: {}", tx.id);
+            // 先确认所有输入都可花费，再修改UTXO集
+            let mut seen = HashSet::new();
+            for input in &tx.vin {
+                if !seen.insert((&input.txid, input.vout)) || !self.exists_utxo(&input.txid, input.vout)? {
+                    error!("交易 {} 的输入不存在: txid={}, vout={}", tx.id, input.txid, input.vout);
+                    return Err(RustBtcError::UTXONotFound(format!(
+                        "UTXO不存在: txid={}, vout={}",
+                        input.txid, input.vout
+                    )));
                 }
-            } else {
-                debug!("处理coinbase交易: {}", tx.id);
             }
 
-            // 添加新的未花费输出
-            let mut outputs = Vec::new();
-            for (vout, output) in tx.vout.iter().enumerate() {
-                debug!("添加新的UTXO: txid={}, vout={}, value={}", 
-                    tx.id, vout, output.value);
-                outputs.push((vout, output.clone()));
+            // 移除已花费的输出
+            for input in &tx.vin {
+                debug!("移除已花费的UTXO: txid={}, vout={}", input.txid, input.vout);
+                if let Some(coin) = self.remove_coin(&input.txid, input.vout)? {
+                    undo.spent.push(SpentCoin {
+                        txid: input.txid.clone(),
+                        vout: input.vout,
+                        coin,
+                    });
+                }
             }
-            self.utxos.insert(tx.id.clone(), outputs);
+        } else {
+            debug!("处理coinbase交易: {}", tx.id);
         }
 
-        info!("UTXO集更新完成，当前包含 {} 个交易的UTXO", self.utxos.len());
+        // 添加新的未花费输出
+        for (vout, output) in tx.vout.iter().enumerate().filter(|(_, output)| !output.is_unspendable()) {
+            debug!("添加新的UTXO: txid={}, vout={}, value={}", 
+                tx.id, vout, output.value);
+            self.add_coin(tx.id.clone(), vout, Coin {
+                output: output.clone(),
+                height,
+                is_coinbase: tx.is_coinbase(),
+                median_time_past,
+            });
+        }
         Ok(())
     }
 
-    pub fn verify_input(&self, input: &TxInput) -> Result<bool> {
-        debug!("验证交易输入: txid={}, vout={}", input.txid, input.vout);
-        
-        // 检查UTXO是否存在
-        if let Some(outputs) = self.utxos.get(&input.txid) {
-            if let Some((_, output)) = outputs.iter().find(|(vout, _)| *vout == input.vout) {
-                debug!("找到对应的UTXO，金额: {}", output.value);
-                
-                // 验证金额
-                if output.value != input.value {
-                    error!("UTXO金额不匹配: 期望={}, 实际={}", 
-                        input.value, output.value);
-                    return Ok(false);
+    /// 按逆序撤销交易，`spent`中的撤销记录从末尾依次取出
+    fn disconnect_transactions(&mut self, txs: &[Transaction], spent: &mut Vec<SpentCoin>) -> Result<()> {
+        for tx in txs.iter().rev() {
+            for vout in 0..tx.vout.len() {
+                self.remove_coin(&tx.id, vout)?;
+            }
+            if tx.is_coinbase() {
+                continue;
+            }
+            for input in tx.vin.iter().rev() {
+                let record = spent.pop().ok_or_else(|| {
+                    RustBtcError::UTXOError(format!("交易 {} 缺少撤销数据", tx.id))
+                })?;
+                if record.txid != input.txid || record.vout != input.vout {
+                    return Err(RustBtcError::UTXOError(format!(
+                        "撤销数据 {}:{} 与交易输入 {}:{} 不符",
+                        record.txid, record.vout, input.txid, input.vout
+                    )));
                 }
-                
-                debug!("交易输入验证通过");
-                return Ok(true);
+                debug!("恢复已花费的UTXO: txid={}, vout={}", record.txid, record.vout);
+                self.add_coin(record.txid, record.vout, record.coin);
             }
         }
-        
-        error!("未找到对应的UTXO: txid={}, vout={}", input.txid, input.vout);
-        Ok(false)
+        Ok(())
     }
 
-    pub fn exists_utxo(&self, txid: &str, vout: usize) -> Result<bool> {
-        debug!("检查UTXO是否存在: txid={}, vout={}", txid, vout);
-        if let Some(outputs) = self.utxos.get(txid) {
-            Ok(outputs.iter().any(|(v, _)| *v == vout))
-        } else {
-            Ok(false)
+    fn remove_coin(&mut self, txid: &str, vout: usize) -> Result<Option<Coin>> {
+        let coin = self.get_coin(txid, vout)?;
+        if coin.is_some() {
+            let key = (txid.to_string(), vout);
+            self.cache.get_mut().pop(&key);
+            // 存储中可能有该输出，需要记录删除
+            if self.storage.is_some() {
+                self.changes.insert(key, None);
+            } else {
+                self.changes.remove(&key);
+            }
         }
+        Ok(coin)
     }
 
-    pub fn save(&self) -> Result<()> {
-        info!("保存UTXO集到文件");
-        
-        // 确保目录存在
-        if let Some(parent) = Path::new(UTXO_TREE_FILE).parent() {
-            fs::create_dir_all(parent)
-                .map_err(|e| RustBtcError::Io(e))?;
+    fn add_coin(&mut self, txid: String, vout: usize, coin: Coin) {
+        let key = (txid, vout);
+        self.cache.get_mut().pop(&key);
+        self.changes.insert(key, Some(coin));
+    }
+
+    pub fn get_coin(&self, txid: &str, vout: usize) -> Result<Option<Coin>> {
+        let key = (txid.to_string(), vout);
+        if let Some(change) = self.changes.get(&key) {
+            return Ok(change.clone());
         }
-        
-        let data = bincode::serialize(self)
-            .map_err(|e| RustBtcError::Serialization(e))?;
-            
-        fs::write(UTXO_TREE_FILE, data)
-            .map_err(|e| RustBtcError::Io(e))?;
-            
-        info!("UTXO集保存成功");
-        Ok(())
+        let Some(storage) = &self.storage else {
+            return Ok(None);
+        };
+        if let Some(coin) = self.cache.lock().get(&key) {
+            return Ok(Some(coin.clone()));
+        }
+
+        let coin = storage.get_utxo(txid, vout)?;
+        if let Some(coin) = &coin {
+            self.cache.lock().put(key, coin.clone());
+        }
+        Ok(coin)
     }
 
-    pub fn load() -> Result<Self> {
-        info!("从文件加载UTXO集");
-        
-        if !Path::new(UTXO_TREE_FILE).exists() {
-            warn!("UTXO文件不存在，创建新的UTXO集");
-            return Ok(Self::new());
+    /// 遍历所有未花费输出，未写入的修改优先于存储中的条目，读取存储出错时产生错误
+    fn coins(&self) -> Box<dyn Iterator<Item = Result<(String, usize, Coin)>> + '_> {
+        let pending = self
+            .changes
+            .iter()
+            .filter_map(|((txid, vout), coin)| Some(Ok((txid.clone(), *vout, coin.clone()?))));
+        match &self.storage {
+            Some(storage) => {
+                let stored = storage.iter_utxos().filter(|utxo| {
+                    !matches!(utxo, Ok((txid, vout, _)) if self.changes.contains_key(&(txid.clone(), *vout)))
+                });
+                Box::new(pending.chain(stored))
+            }
+            None => Box::new(pending),
         }
+    }
 
-        let data = fs::read(UTXO_TREE_FILE)
-            .map_err(|e| RustBtcError::Io(e))?;
-            
-        let utxo_set = bincode::deserialize(&data)
-            .map_err(|e| RustBtcError::DeserializationError(e.to_string()))?;
+    pub fn verify_input(&self, input: &TxInput) -> Result<bool> {
+        debug!("验证交易输入: txid={}, vout={}", input.txid, input.vout);
+        
+        // 检查UTXO是否存在
+        if let Some(coin) = self.get_coin(&input.txid, input.vout)? {
+            debug!("找到对应的UTXO，金额: {}", coin.output.value);
             
-        info!("UTXO集加载成功");
-        Ok(utxo_set)
+            // 验证金额
+            if coin.output.value != input.value {
+                error!("UTXO金额不匹配: 期望={}, 实际={}", 
+                    input.value, coin.output.value);
+                return Ok(false);
+            }
+
+            debug!("交易输入验证通过");
+            return Ok(true);
+        }
+        
+        error!("未找到对应的UTXO: txid={}, vout={}", input.txid, input.vout);
+        Ok(false)
     }
 
+    pub fn exists_utxo(&self, txid: &str, vout: usize) -> Result<bool> {
+        debug!("检查UTXO是否存在: txid={}, vout={}", txid, vout);
+        Ok(self.get_coin(txid, vout)?.is_some())
+    }
+
+    /// 从主链重建UTXO集。在存储的新表中重建并分批写入，完成后与UTXO链尾一起原子地替换原来的表；
+    /// 重建期间原来的UTXO集保持不变，中途退出时下次加载会重新重建
     pub fn reindex(&mut self, blockchain: &crate::blockchain::Blockchain) -> Result<()> {
         info!("重建UTXO集索引");
-        self.utxos.clear();
+        let fresh = match &self.storage {
+            Some(storage) => Some(Arc::new(storage.fresh_utxos()?)),
+            None => None,
+        };
+        let mut rebuilt = match &fresh {
+            Some(fresh) => UTXOSet::with_storage(fresh.clone(), self.cache.get_mut().cap().get(), None),
+            None => UTXOSet::new(),
+        };
+        rebuilt.max_pending_changes = self.max_pending_changes;
         
-        // 遍历所有区块
+        // 依次连接所有区块
+        let mut tip = None;
         for block in blockchain.blocks() {
+            let block = block?;
             debug!("处理区块: {}", block.hash);
-            
-            // 处理区块中的所有交易
-            for tx in &block.transactions {
-                debug!("处理交易: {}", tx.id);
-                
-                // 如果不是coinbase交易，移除已花费的输出
-                if !tx.is_coinbase() {
-                    for input in &tx.vin {
-                        debug!("检查移除UTXO: txid={}, vout={}", input.txid, input.vout);
-                        if let Some(outputs) = self.utxos.get_mut(&input.txid) {
-                            debug!("移除已花费的UTXO: txid={}, vout={}", 
-                                input.txid, input.vout);
-                            outputs.retain(|(vout, _)| *vout != input.vout);
-                            if outputs.is_empty() {
-                                self.utxos.remove(&input.txid);
-                            }
-                        }
-                    }
-                }
-                
-                // 添加新的未花费输出
-                let mut outputs = Vec::new();
-                for (vout, output) in tx.vout.iter().enumerate() {
-                    debug!("添加新的UTXO: txid={}, vout={}, value={}", 
-                        tx.id, vout, output.value);
-                    outputs.push((vout, output.clone()));
-                }
-                
-                // 检查是否已存在相同ID的交易
-                if self.utxos.contains_key(&tx.id) {
-                    debug!("警告：发现重复的交易ID: {}", tx.id);
-                    continue;
-                }
-                
-                self.utxos.insert(tx.id.clone(), outputs);
-            }
+            let median_time_past = blockchain.median_time_past(&block.header.prev_block_hash).unwrap_or(0);
+            rebuilt.connect_block(&block, median_time_past)?;
+            rebuilt.flush_if_needed()?;
+            tip = Some(block.hash);
+        }
+
+        if let (Some(storage), Some(fresh)) = (&self.storage, &fresh) {
+            let mut batch = StorageBatch::new();
+            rebuilt.flush_into(&mut batch)?;
+            batch.set_utxo_tip(tip.as_deref());
+            storage.swap_in_utxos(fresh, batch)?;
+            rebuilt.mark_flushed();
         }
+        self.changes = rebuilt.changes;
+        self.cache.get_mut().clear();
+        self.tip_height = rebuilt.tip_height;
         
-        info!("UTXO集索引重建完成，当前包含 {} 个交易的UTXO", self.utxos.len());
+        info!("UTXO集索引重建完成，当前有 {} 个未写入的修改", self.changes.len());
         Ok(())
     }
 
-    pub fn get_balance(&self, address: &str) -> Result<i64> {
+    /// 支付给`address`的未花费输出。启用地址索引且是P2PKH地址时只读取该地址的索引项，否则遍历整个UTXO集
+    fn address_utxos(&self, address: &str) -> Result<Box<dyn Iterator<Item = Result<UTXOInfo>> + '_>> {
+        let script_pubkey = address_to_script_pubkey(address)?;
+        let pubkey_hash = script_pubkey.p2pkh_hash().map(<[u8]>::to_vec);
+        let to_info = |(txid, vout, coin): (String, usize, Coin)| UTXOInfo {
+            txid,
+            vout,
+            value: coin.output.value,
+        };
+
+        match (&self.storage, pubkey_hash) {
+            (Some(storage), Some(pubkey_hash)) if self.address_index => {
+                let pending = self
+                    .changes
+                    .iter()
+                    .filter_map(|((txid, vout), coin)| Some((txid.clone(), *vout, coin.clone()?)))
+                    .filter(move |(_, _, coin)| coin.output.script_pubkey == script_pubkey)
+                    .map(move |utxo| Ok(to_info(utxo)));
+                let stored = storage
+                    .iter_address_utxos(&pubkey_hash)
+                    .filter(|utxo| {
+                        !matches!(utxo, Ok((txid, vout, _)) if self.changes.contains_key(&(txid.clone(), *vout)))
+                    })
+                    .map(|utxo| utxo.map(|(txid, vout, value)| UTXOInfo { txid, vout, value }));
+                Ok(Box::new(pending.chain(stored)))
+            }
+            _ => Ok(Box::new(
+                self.coins()
+                    .filter(move |utxo| {
+                        !matches!(utxo, Ok((_, _, coin)) if coin.output.script_pubkey != script_pubkey)
+                    })
+                    .map(move |utxo| utxo.map(to_info)),
+            )),
+        }
+    }
+
+    pub fn get_balance(&self, address: &str) -> Result<Amount> {
         debug!("计算地址余额: {}", address);
         
-        let mut balance = 0;
-        let address_bytes = bs58::decode(address)
-            .into_vec()
-            .map_err(|e| RustBtcError::InvalidAddress(e.to_string()))?;
-
-        for outputs in self.utxos.values() {
-            for (_, output) in outputs {
-                if output.pubkey_hash == address_bytes {
-                    debug!("找到UTXO: value={}", output.value);
-                    balance += output.value;
-                }
-            }
+        let mut balance = Amount::ZERO;
+        for utxo in self.address_utxos(address)? {
+            let utxo = utxo?;
+            debug!("找到UTXO: value={}", utxo.value);
+            balance = balance
+                .checked_add(utxo.value)
+                .ok_or_else(|| RustBtcError::InvalidAmount(format!("地址 {} 的余额溢出", address)))?;
         }
         
         debug!("地址 {} 的余额为: {}", address, balance);
         Ok(balance)
     }
 
-    pub fn find_spendable_outputs(&self, address: &str, amount: i64) -> Result<Vec<UTXOInfo>> {
+    pub fn find_spendable_outputs(&self, address: &str, amount: Amount) -> Result<Vec<UTXOInfo>> {
         debug!("查找可花费的UTXO: address={}, amount={}", address, amount);
         
         let mut outputs = Vec::new();
-        let mut accumulated = 0;
+        let mut accumulated = Amount::ZERO;
         
-        let address_bytes = bs58::decode(address)
-            .into_vec()
-            .map_err(|e| RustBtcError::InvalidAddress(e.to_string()))?;
+        for utxo in self.address_utxos(address)? {
+            let utxo = utxo?;
+            debug!("找到可用UTXO: txid={}, vout={}, value={}", 
+                utxo.txid, utxo.vout, utxo.value);
+                
+            accumulated = accumulated
+                .checked_add(utxo.value)
+                .ok_or_else(|| RustBtcError::InvalidAmount(format!("地址 {} 的UTXO总额溢出", address)))?;
+            outputs.push(utxo);
             
-        'outer: for (txid, txouts) in &self.utxos {
-            for (vout, output) in txouts {
-                if output.pubkey_hash == address_bytes {
-                    debug!("找到可用UTXO: txid={}, vout={}, value={}", 
-                        txid, vout, output.value);
-                        
-                    accumulated += output.value;
-                    outputs.push(UTXOInfo {
-                        txid: txid.clone(),
-                        vout: *vout,
-                        value: output.value,
-                    });
-                    
-                    if accumulated >= amount {
-                        debug!("已收集足够的UTXO，总额: {}", accumulated);
-                        break 'outer;
-                    }
-                }
+            if accumulated >= amount {
+                debug!("已收集足够的UTXO，总额: {}", accumulated);
+                break;
             }
         }
         
@@ -239,43 +511,66 @@ impl UTXOSet {
         Ok(outputs)
     }
 
+    /// 分页列出支付给`address`的未花费输出，跳过前`offset`个，最多返回`limit`个
+    pub fn list_unspent(&self, address: &str, offset: usize, limit: usize) -> Result<Vec<UTXOInfo>> {
+        debug!("列出地址 {} 的UTXO: offset={}, limit={}", address, offset, limit);
+        self.address_utxos(address)?.skip(offset).take(limit).collect()
+    }
+
     pub fn find_utxo(&self, txid: &str, vout: usize) -> Result<Option<TxOutput>> {
         debug!("查找指定的UTXO: txid={}, vout={}", txid, vout);
-        if let Some(outputs) = self.utxos.get(txid) {
-            if let Some((_, output)) = outputs.iter().find(|(v, _)| *v == vout) {
-                debug!("找到UTXO，金额: {}", output.value);
-                return Ok(Some(output.clone()));
-            }
+        let output = self.get_coin(txid, vout)?.map(|coin| coin.output);
+        match &output {
+            Some(output) => debug!("找到UTXO，金额: {}", output.value),
+            None => debug!("未找到指定的UTXO"),
         }
-        debug!("未找到指定的UTXO");
-        Ok(None)
+        Ok(output)
     }
 
     pub fn find_transaction_output(&self, txid: &str, vout: usize) -> Result<TxOutput> {
         debug!("查找交易输出: txid={}, vout={}", txid, vout);
         
-        // 检查 UTXO 是否存在
-        if !self.exists_utxo(txid, vout)? {
-            return Err(RustBtcError::UTXONotFound(format!(
-                "UTXO不存在: txid={}, vout={}",
-                txid, vout
-            )));
-        }
-        
-        // 获取 UTXO
-        let utxos = self.utxos.get(txid).ok_or_else(|| {
-            RustBtcError::UTXONotFound(format!("UTXO不存在: txid={}", txid))
-        })?;
-        
-        // 获取指定的输出
-        let (_, output) = utxos.get(vout).ok_or_else(|| {
+        // 按输出序号而不是位置查找，前面的输出可能已被花费
+        let coin = self.get_coin(txid, vout)?.ok_or_else(|| {
             RustBtcError::UTXONotFound(format!(
-                "UTXO输出不存在: txid={}, vout={}",
+                "UTXO不存在: txid={}, vout={}",
                 txid, vout
             ))
         })?;
         
-        Ok(output.clone())
+        Ok(coin.output)
+    }
+}
+
+impl Default for UTXOSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 克隆共享同一个存储，各自保留未写入的修改
+impl Clone for UTXOSet {
+    fn clone(&self) -> Self {
+        UTXOSet {
+            changes: self.changes.clone(),
+            cache: Mutex::new(LruCache::new(self.cache.lock().cap())),
+            storage: self.storage.clone(),
+            max_pending_changes: self.max_pending_changes,
+            address_index: self.address_index,
+            tip_height: self.tip_height,
+        }
+    }
+}
+
+impl fmt::Debug for UTXOSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UTXOSet")
+            .field("pending_changes", &self.changes.len())
+            .field("cached", &self.cache.lock().len())
+            .field("persistent", &self.storage.is_some())
+            .field("address_index", &self.address_index)
+            .field("tip_height", &self.tip_height)
+            .finish()
     }
 }
 
@@ -283,17 +578,24 @@ impl UTXOSet {
 pub struct UTXOInfo {
     pub txid: String,
     pub vout: usize,
-    pub value: i64,
+    pub value: Amount,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::params::ChainParams;
+    use crate::wallet::Wallet;
+    use tempfile::tempdir;
 
     fn create_test_wallet() -> Result<Wallet> {
         Wallet::new()
     }
 
+    fn btc(n: u64) -> Amount {
+        Amount::from_btc(n).unwrap()
+    }
+
     #[test]
     fn test_utxo_basic_operations() -> Result<()> {
         let mut utxo_set = UTXOSet::new();
@@ -301,15 +603,15 @@ mod tests {
         let address = wallet.get_address();
         
         // 创建测试交易
-        let tx = Transaction::new_coinbase(&address, "Test UTXO")?;
+        let tx = Transaction::new_coinbase(&address, "Test UTXO", 0, Amount::ZERO, &ChainParams::default())?;
         
         // 添加 UTXO
-        utxo_set.update(&[tx.clone()])?;
+        utxo_set.update(std::slice::from_ref(&tx))?;
         
         // 验证 UTXO 已添加
-        let utxos = utxo_set.find_spendable_outputs(&address, 50)?;
+        let utxos = utxo_set.find_spendable_outputs(&address, btc(50))?;
         assert_eq!(utxos.len(), 1);
-        assert_eq!(utxos[0].value, 50);
+        assert_eq!(utxos[0].value, btc(50));
         
         Ok(())
     }
@@ -319,25 +621,49 @@ mod tests {
         let wallet = create_test_wallet()?;
         let address = wallet.get_address();
         
-        // 创建并保存 UTXO 集
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_str().unwrap();
+        let storage = Storage::new(path)?;
+        
+        // 创建并写入 UTXO 集
         {
-            let mut utxo_set = UTXOSet::new();
-            let tx = Transaction::new_coinbase(&address, "Test Persistence")?;
+            let mut utxo_set = UTXOSet::with_storage(Arc::new(storage.reopen()?), DEFAULT_UTXO_CACHE_SIZE, None);
+            let tx = Transaction::new_coinbase(&address, "Test Persistence", 0, Amount::ZERO, &ChainParams::default())?;
             utxo_set.update(&[tx])?;
-            utxo_set.save()?;
+            utxo_set.flush()?;
         }
         
-        // 加载并验证 UTXO 集
+        // 重新打开存储并验证 UTXO 集
         {
-            let utxo_set = UTXOSet::load()?;
-            let utxos = utxo_set.find_spendable_outputs(&address, 50)?;
+            let storage = Arc::new(storage.reopen()?);
+            let utxo_set = UTXOSet::with_storage(storage, DEFAULT_UTXO_CACHE_SIZE, Some(0));
+            let utxos = utxo_set.find_spendable_outputs(&address, btc(50))?;
             assert_eq!(utxos.len(), 1);
-            assert_eq!(utxos[0].value, 50);
+            assert_eq!(utxos[0].value, btc(50));
         }
         
         Ok(())
     }
 
+    #[test]
+    fn test_pending_changes_are_bounded() -> Result<()> {
+        let wallet = create_test_wallet()?;
+        let address = wallet.get_address();
+        let storage = Arc::new(Storage::temporary()?);
+        let mut utxo_set = UTXOSet::with_storage(storage.clone(), DEFAULT_UTXO_CACHE_SIZE, None);
+        utxo_set.set_max_pending_changes(2);
+
+        for i in 0..5 {
+            let tx = Transaction::new_coinbase(&address, &format!("Test {}", i), 0, Amount::ZERO, &ChainParams::default())?;
+            utxo_set.update(&[tx])?;
+            assert!(utxo_set.pending_changes() < 2);
+        }
+        assert_eq!(storage.iter_utxos().count() + utxo_set.pending_changes(), 5);
+        assert_eq!(utxo_set.get_balance(&address)?, btc(250));
+
+        Ok(())
+    }
+
     #[test]
     fn test_find_spendable_outputs() -> Result<()> {
         let mut utxo_set = UTXOSet::new();
@@ -346,20 +672,131 @@ mod tests {
         
         // 创建多个测试交易
         for i in 0..3 {
-            let tx = Transaction::new_coinbase(&address, &format!("Test {}", i))?;
+            let tx = Transaction::new_coinbase(&address, &format!("Test {}", i), 0, Amount::ZERO, &ChainParams::default())?;
             utxo_set.update(&[tx])?;
         }
         
         // 测试不同金额的查找
-        let utxos = utxo_set.find_spendable_outputs(&address, 50)?;
+        let utxos = utxo_set.find_spendable_outputs(&address, btc(50))?;
         assert_eq!(utxos.len(), 1);  // 需要一个 UTXO 来满足 50 的金额
         
-        let utxos = utxo_set.find_spendable_outputs(&address, 100)?;
+        let utxos = utxo_set.find_spendable_outputs(&address, btc(100))?;
         assert_eq!(utxos.len(), 2);  // 需要两个 UTXO 来满足 100 的金额
         
-        let utxos = utxo_set.find_spendable_outputs(&address, 150)?;
+        let utxos = utxo_set.find_spendable_outputs(&address, btc(150))?;
         assert_eq!(utxos.len(), 3);  // 需要三个 UTXO 来满足 150 的金额
         
         Ok(())
     }
+
+    fn spend(id: &str, inputs: &[(&str, usize, u64)], outputs: Vec<TxOutput>) -> Transaction {
+        Transaction {
+            id: id.to_string(),
+            vin: inputs
+                .iter()
+                .map(|(txid, vout, value)| TxInput::new(txid.to_string(), *vout, Amount::from_sat(*value)))
+                .collect(),
+            vout: outputs,
+            lock_time: 0,
+        }
+    }
+
+    #[test]
+    fn test_connect_and_disconnect_block() -> Result<()> {
+        let wallet = create_test_wallet()?;
+        let address = wallet.get_address();
+        let mut utxo_set = UTXOSet::new();
+
+        let base = Transaction::new_coinbase(&address, "base", 0, Amount::ZERO, &ChainParams::default())?;
+        let mut first = Block::new(vec![base.clone()], "0".to_string())?;
+        first.height = 1;
+        utxo_set.connect_block(&first, 0)?;
+        let before = utxo_set.changes.clone();
+
+        // 同一区块内先花费coinbase，再花费刚创建的输出
+        let coinbase = Transaction::new_coinbase(&address, "second", 0, Amount::ZERO, &ChainParams::default())?;
+        let a = spend("a", &[(&base.id, 0, 50)], vec![
+            TxOutput::new(Amount::from_sat(30), &address)?,
+            TxOutput::new(Amount::from_sat(20), &address)?,
+        ]);
+        let b = spend("b", &[("a", 0, 30)], vec![TxOutput::new(Amount::from_sat(30), &address)?]);
+        let mut block = Block::new(vec![coinbase.clone(), a, b], first.hash.clone())?;
+        block.height = 2;
+
+        let undo = utxo_set.connect_block(&block, 0)?;
+        assert_eq!(undo.spent.len(), 2);
+        assert_eq!(undo.spent[0].coin.height, 1);
+        assert!(undo.spent[0].coin.is_coinbase);
+        assert!(utxo_set.get_coin(&base.id, 0)?.is_none());
+        assert!(utxo_set.get_coin("a", 0)?.is_none());
+        assert_eq!(utxo_set.find_transaction_output("a", 1)?.value, Amount::from_sat(20));
+        assert_eq!(utxo_set.get_coin("b", 0)?.map(|c| c.height), Some(2));
+        assert!(utxo_set.get_coin(&coinbase.id, 0)?.is_some_and(|c| c.is_coinbase));
+
+        utxo_set.disconnect_block(&block, &undo)?;
+        assert_eq!(utxo_set.changes, before);
+
+        // 输入不存在时整个区块被拒绝，UTXO集保持不变
+        let bad = spend("c", &[("missing", 0, 10)], vec![TxOutput::new(Amount::from_sat(10), &address)?]);
+        let good = spend("d", &[(&base.id, 0, 50)], vec![TxOutput::new(Amount::from_sat(50), &address)?]);
+        let block = Block::new(vec![good, bad], first.hash.clone())?;
+        assert!(matches!(utxo_set.connect_block(&block, 0), Err(RustBtcError::UTXONotFound(_))));
+        assert_eq!(utxo_set.changes, before);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_backed_utxo_set() -> Result<()> {
+        let wallet = create_test_wallet()?;
+        let address = wallet.get_address();
+        let storage = Arc::new(Storage::temporary()?);
+        // 缓存只能容纳一个条目，读取多个输出时会不断淘汰
+        let mut utxo_set = UTXOSet::with_storage(storage.clone(), 1, None);
+
+        let base = Transaction::new_coinbase(&address, "base", 0, Amount::ZERO, &ChainParams::default())?;
+        let first = Block::new(vec![base.clone()], "0".to_string())?;
+        utxo_set.connect_block(&first, 0)?;
+        assert!(storage.get_utxo(&base.id, 0)?.is_none());
+        utxo_set.flush()?;
+        assert_eq!(utxo_set.pending_changes(), 0);
+        assert!(storage.get_utxo(&base.id, 0)?.is_some());
+
+        let coinbase = Transaction::new_coinbase(&address, "second", 0, Amount::ZERO, &ChainParams::default())?;
+        let a = spend("a", &[(&base.id, 0, 50)], vec![
+            TxOutput::new(Amount::from_sat(30), &address)?,
+            TxOutput::new(Amount::from_sat(20), &address)?,
+        ]);
+        let mut block = Block::new(vec![coinbase.clone(), a], first.hash.clone())?;
+        block.height = 1;
+        let undo = utxo_set.connect_block(&block, 0)?;
+
+        // 未写入的花费覆盖存储中的条目
+        assert!(storage.get_utxo(&base.id, 0)?.is_some());
+        assert!(!utxo_set.exists_utxo(&base.id, 0)?);
+        utxo_set.flush()?;
+        assert!(storage.get_utxo(&base.id, 0)?.is_none());
+        for _ in 0..2 {
+            assert_eq!(utxo_set.find_utxo("a", 0)?.map(|o| o.value), Some(Amount::from_sat(30)));
+            assert_eq!(utxo_set.find_utxo("a", 1)?.map(|o| o.value), Some(Amount::from_sat(20)));
+        }
+        assert_eq!(utxo_set.get_balance(&address)?, btc(50).checked_add(Amount::from_sat(50)).unwrap());
+
+        // 重新打开后不需要重建即可读取
+        let reopened = UTXOSet::with_storage(storage.clone(), DEFAULT_UTXO_CACHE_SIZE, Some(1));
+        assert_eq!(reopened.next_height(), 2);
+        assert!(reopened.get_coin(&coinbase.id, 0)?.is_some_and(|c| c.is_coinbase));
+        assert_eq!(reopened.get_balance(&address)?, utxo_set.get_balance(&address)?);
+
+        utxo_set.disconnect_block(&block, &undo)?;
+        utxo_set.flush()?;
+        let mut stored = storage
+            .iter_utxos()
+            .map(|utxo| utxo.map(|(txid, vout, _)| (txid, vout)))
+            .collect::<Result<Vec<_>>>()?;
+        stored.sort();
+        assert_eq!(stored, vec![(base.id.clone(), 0)]);
+
+        Ok(())
+    }
 }
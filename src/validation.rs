@@ -0,0 +1,662 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use tracing::{debug, error, info};
+
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::error::{Result, RustBtcError};
+use crate::params::ChainParams;
+use crate::script::ScriptError;
+use crate::transaction::{Transaction, TxOutput};
+use crate::utxo::{BlockUndo, Coin, UTXOSet};
+
+pub const MAX_BLOCK_SIZE: usize = 1_000_000; // 1MB
+
+/// 区块被共识规则拒绝的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockRejection {
+    BadDifficulty { expected: u32, actual: u32 },
+    BadHash,
+    HighHash,
+    TimeTooOld { time: u32, median_time_past: u32 },
+    TimeTooNew { time: u32, max_time: u64 },
+    BadMerkleRoot,
+    BadWitnessCommitment,
+    Oversized { size: usize },
+    MissingCoinbase,
+    MultipleCoinbase { index: usize },
+    EmptyTransaction { txid: String },
+    BadTxid { txid: String },
+    OverwritesUnspent { txid: String, vout: usize },
+    MissingInput { txid: String, vout: usize },
+    DoubleSpend { txid: String, vout: usize },
+    ImmatureCoinbase { txid: String, vout: usize, height: u64 },
+    NonFinal { txid: String, lock_time: u32 },
+    SequenceLocked { txid: String },
+    BadScript { txid: String, input: usize, error: ScriptError },
+    BadOutputValue { txid: String, value: Amount },
+    ValueOutOfRange { txid: String },
+    InputsBelowOutputs { txid: String, input_total: Amount, output_total: Amount },
+    BadCoinbaseValue { value: Amount, limit: Amount },
+}
+
+impl fmt::Display for BlockRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockRejection::BadDifficulty { expected, actual } => {
+                write!(f, "区块难度 {:#010x} 与期望难度 {:#010x} 不符", actual, expected)
+            }
+            BlockRejection::BadHash => write!(f, "区块哈希与区块头不符"),
+            BlockRejection::HighHash => write!(f, "区块哈希未满足难度目标"),
+            BlockRejection::TimeTooOld { time, median_time_past } => {
+                write!(f, "区块时间戳 {} 不大于过去区块的中位时间 {}", time, median_time_past)
+            }
+            BlockRejection::TimeTooNew { time, max_time } => {
+                write!(f, "区块时间戳 {} 超过允许的最大时间 {}", time, max_time)
+            }
+            BlockRejection::BadMerkleRoot => write!(f, "默克尔根与区块交易不符"),
+            BlockRejection::BadWitnessCommitment => write!(f, "见证承诺缺失或与区块交易的签名数据不符"),
+            BlockRejection::Oversized { size } => {
+                write!(f, "区块大小 {} 超过最大限制 {}", size, MAX_BLOCK_SIZE)
+            }
+            BlockRejection::MissingCoinbase => write!(f, "区块的第一笔交易不是coinbase交易"),
+            BlockRejection::MultipleCoinbase { index } => {
+                write!(f, "第 {} 笔交易是多余的coinbase交易", index)
+            }
+            BlockRejection::EmptyTransaction { txid } => write!(f, "交易 {} 的输入或输出为空", txid),
+            BlockRejection::BadTxid { txid } => write!(f, "交易ID {} 与交易内容不符", txid),
+            BlockRejection::OverwritesUnspent { txid, vout } => {
+                write!(f, "交易输出 {}:{} 会覆盖尚未花费的UTXO", txid, vout)
+            }
+            BlockRejection::MissingInput { txid, vout } => {
+                write!(f, "输入引用的UTXO {}:{} 不存在", txid, vout)
+            }
+            BlockRejection::DoubleSpend { txid, vout } => {
+                write!(f, "UTXO {}:{} 在区块内被重复花费", txid, vout)
+            }
+            BlockRejection::ImmatureCoinbase { txid, vout, height } => {
+                write!(f, "高度 {} 的coinbase输出 {}:{} 尚未成熟", height, txid, vout)
+            }
+            BlockRejection::NonFinal { txid, lock_time } => {
+                write!(f, "交易 {} 的时间锁 {} 尚未到期", txid, lock_time)
+            }
+            BlockRejection::SequenceLocked { txid } => {
+                write!(f, "交易 {} 的相对时间锁尚未到期", txid)
+            }
+            BlockRejection::BadScript { txid, input, error } => {
+                write!(f, "交易 {} 的第 {} 个输入脚本验证失败: {}", txid, input, error)
+            }
+            BlockRejection::BadOutputValue { txid, value } => {
+                write!(f, "交易 {} 的输出金额 {} 无效", txid, value)
+            }
+            BlockRejection::ValueOutOfRange { txid } => {
+                write!(f, "交易 {} 的金额总和超过最大金额", txid)
+            }
+            BlockRejection::InputsBelowOutputs { txid, input_total, output_total } => {
+                write!(f, "交易 {} 的输出总额 {} 大于输入总额 {}", txid, output_total, input_total)
+            }
+            BlockRejection::BadCoinbaseValue { value, limit } => {
+                write!(f, "coinbase金额 {} 超过区块奖励与手续费之和 {}", value, limit)
+            }
+        }
+    }
+}
+
+/// 验证区块时用到的链状态
+#[derive(Debug, Clone)]
+pub struct BlockContext<'a> {
+    pub params: &'a ChainParams,
+    /// 区块在链上的高度
+    pub height: u64,
+    /// 由难度调整算法得出的期望难度
+    pub expected_bits: u32,
+    /// 前面区块时间戳的中位数，创世区块为None
+    pub median_time_past: Option<u32>,
+    /// 经对等节点时间偏移调整后的当前时间
+    pub adjusted_time: u64,
+}
+
+fn reject(block: &Block, reason: BlockRejection) -> RustBtcError {
+    error!("区块 {} 被拒绝: {}", block.hash, reason);
+    RustBtcError::BlockRejected(reason)
+}
+
+/// 检查区块头：难度、哈希与工作量证明、时间戳。区块加入区块树之前即可执行
+pub fn check_block_header(block: &Block, ctx: &BlockContext) -> Result<()> {
+    // 难度与工作量证明
+    if block.header.bits != ctx.expected_bits {
+        return Err(reject(block, BlockRejection::BadDifficulty {
+            expected: ctx.expected_bits,
+            actual: block.header.bits,
+        }));
+    }
+    if !block.verify_hash()? {
+        return Err(reject(block, BlockRejection::BadHash));
+    }
+    if !block.check_proof_of_work()? {
+        return Err(reject(block, BlockRejection::HighHash));
+    }
+
+    // 时间戳必须大于过去区块的中位时间，且不能超前网络时间太多
+    if let Some(median_time_past) = ctx.median_time_past {
+        if block.header.timestamp <= median_time_past {
+            return Err(reject(block, BlockRejection::TimeTooOld {
+                time: block.header.timestamp,
+                median_time_past,
+            }));
+        }
+    }
+    let max_time = ctx.adjusted_time + ctx.params.max_future_block_time;
+    if block.header.timestamp as u64 > max_time {
+        return Err(reject(block, BlockRejection::TimeTooNew {
+            time: block.header.timestamp,
+            max_time,
+        }));
+    }
+
+    Ok(())
+}
+
+/// 按共识规则依次验证区块，全部通过后将其连接到UTXO集并返回撤销数据。
+/// 验证失败时返回`RustBtcError::BlockRejected`，UTXO集保持不变
+pub fn connect_block(block: &Block, ctx: &BlockContext, utxo_set: &mut UTXOSet) -> Result<BlockUndo> {
+    debug!("验证区块 {}，高度: {}", block.hash, ctx.height);
+
+    // 1-2. 区块头、工作量证明与时间戳
+    check_block_header(block, ctx)?;
+
+    // 3. 默克尔根与见证承诺，前者覆盖交易ID，后者覆盖签名数据
+    if !block.check_merkle_root()? {
+        return Err(reject(block, BlockRejection::BadMerkleRoot));
+    }
+    if !block.check_witness_commitment()? {
+        return Err(reject(block, BlockRejection::BadWitnessCommitment));
+    }
+
+    // 4. 区块结构与coinbase位置
+    let size = block.serialize()?.len();
+    if size > MAX_BLOCK_SIZE {
+        return Err(reject(block, BlockRejection::Oversized { size }));
+    }
+    if !block.transactions.first().is_some_and(|tx| tx.is_coinbase()) {
+        return Err(reject(block, BlockRejection::MissingCoinbase));
+    }
+    if let Some(index) = block.transactions.iter().skip(1).position(|tx| tx.is_coinbase()) {
+        return Err(reject(block, BlockRejection::MultipleCoinbase { index: index + 1 }));
+    }
+
+    // 5. 逐笔检查交易：时间锁已到期、UTXO存在、区块内无双花、coinbase已成熟、金额在范围内且守恒。
+    // 区块内先出现的交易创建的输出可以被后面的交易花费
+    let mut created: HashMap<(String, usize), Coin> = HashMap::new();
+    let mut spent: HashSet<(String, usize)> = HashSet::new();
+    let mut fees = Amount::ZERO;
+    // 时间锁以该区块之前的中位时间为准
+    let median_time_past = ctx.median_time_past.unwrap_or(0);
+    let mut block_spent_outputs = Vec::with_capacity(block.transactions.len());
+
+    for tx in &block.transactions {
+        if tx.vin.is_empty() || tx.vout.is_empty() {
+            return Err(reject(block, BlockRejection::EmptyTransaction { txid: tx.id.clone() }));
+        }
+        if tx.id != tx.txid()? {
+            return Err(reject(block, BlockRejection::BadTxid { txid: tx.id.clone() }));
+        }
+        if !tx.is_final(ctx.height, median_time_past) {
+            return Err(reject(block, BlockRejection::NonFinal {
+                txid: tx.id.clone(),
+                lock_time: tx.lock_time,
+            }));
+        }
+
+        let out_of_range = || reject(block, BlockRejection::ValueOutOfRange { txid: tx.id.clone() });
+        let mut spent_coins = Vec::with_capacity(tx.vin.len());
+        if !tx.is_coinbase() {
+            for input in &tx.vin {
+                let key = (input.txid.clone(), input.vout);
+                if !spent.insert(key.clone()) {
+                    return Err(reject(block, BlockRejection::DoubleSpend {
+                        txid: input.txid.clone(),
+                        vout: input.vout,
+                    }));
+                }
+                let coin = match created.get(&key) {
+                    Some(coin) => Some(coin.clone()),
+                    None => utxo_set.get_coin(&input.txid, input.vout)?,
+                };
+                let coin = match coin {
+                    Some(coin) => coin,
+                    None => {
+                        return Err(reject(block, BlockRejection::MissingInput {
+                            txid: input.txid.clone(),
+                            vout: input.vout,
+                        }))
+                    }
+                };
+                if !coin.is_mature(ctx.height, ctx.params.coinbase_maturity) {
+                    return Err(reject(block, BlockRejection::ImmatureCoinbase {
+                        txid: input.txid.clone(),
+                        vout: input.vout,
+                        height: coin.height,
+                    }));
+                }
+                spent_coins.push(coin);
+            }
+
+            let coins: Vec<&Coin> = spent_coins.iter().collect();
+            if !tx.sequence_lock(&coins).is_satisfied(ctx.height, median_time_past) {
+                return Err(reject(block, BlockRejection::SequenceLocked { txid: tx.id.clone() }));
+            }
+        }
+        let spent_outputs: Vec<TxOutput> = spent_coins.into_iter().map(|coin| coin.output).collect();
+        let input_total = Amount::money_sum(spent_outputs.iter().map(|output| output.value)).ok_or_else(out_of_range)?;
+        block_spent_outputs.push(spent_outputs);
+
+        if let Some(output) = tx.vout.iter().find(|output| !output.has_valid_value()) {
+            return Err(reject(block, BlockRejection::BadOutputValue {
+                txid: tx.id.clone(),
+                value: output.value,
+            }));
+        }
+        let output_total = tx.output_total().ok_or_else(out_of_range)?;
+
+        if !tx.is_coinbase() {
+            let fee = input_total.checked_sub(output_total).ok_or_else(|| {
+                reject(block, BlockRejection::InputsBelowOutputs {
+                    txid: tx.id.clone(),
+                    input_total,
+                    output_total,
+                })
+            })?;
+            fees = Amount::money_sum([fees, fee]).ok_or_else(out_of_range)?;
+        }
+
+        for (vout, output) in tx.vout.iter().enumerate().filter(|(_, output)| !output.is_unspendable()) {
+            // BIP30：不允许覆盖尚未花费的输出，区块内已被花费的除外
+            let key = (tx.id.clone(), vout);
+            let unspent = created.contains_key(&key) || (!spent.contains(&key) && utxo_set.exists_utxo(&tx.id, vout)?);
+            if unspent {
+                return Err(reject(block, BlockRejection::OverwritesUnspent { txid: tx.id.clone(), vout }));
+            }
+            created.insert(key, Coin {
+                output: output.clone(),
+                height: ctx.height,
+                is_coinbase: tx.is_coinbase(),
+                median_time_past,
+            });
+        }
+    }
+
+    // 6. 并行执行所有输入的脚本，内存池中验证过的签名命中缓存
+    if let Some((t, i, error)) = Transaction::find_invalid_input(&block.transactions, &block_spent_outputs)? {
+        return Err(reject(block, BlockRejection::BadScript {
+            txid: block.transactions[t].id.clone(),
+            input: i,
+            error,
+        }));
+    }
+
+    // 7. coinbase金额不能超过区块奖励加手续费，两者都已在上面检查过范围
+    let coinbase = &block.transactions[0];
+    let coinbase_value = coinbase.output_total().ok_or_else(|| {
+        reject(block, BlockRejection::ValueOutOfRange { txid: coinbase.id.clone() })
+    })?;
+    let limit = ctx.params.block_subsidy(ctx.height).checked_add(fees).ok_or_else(|| {
+        reject(block, BlockRejection::ValueOutOfRange { txid: coinbase.id.clone() })
+    })?;
+    if coinbase_value > limit {
+        return Err(reject(block, BlockRejection::BadCoinbaseValue {
+            value: coinbase_value,
+            limit,
+        }));
+    }
+
+    let undo = utxo_set.connect_block(block, median_time_past)?;
+    info!("区块 {} 验证通过并已连接，手续费: {}", block.hash, fees);
+    Ok(undo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::MAX_MONEY;
+    use crate::script::Script;
+    use crate::sighash::SighashType;
+    use crate::transaction::{TxInput, TxOutput, SEQUENCE_LOCKTIME_TYPE_FLAG};
+    use crate::wallet::{hash_pub_key, Wallet};
+
+    const EASY_BITS: u32 = 0x207fffff;
+
+    fn regtest() -> ChainParams {
+        ChainParams {
+            coinbase_maturity: 0,
+            ..ChainParams::regtest()
+        }
+    }
+
+    fn context(params: &ChainParams, height: u64) -> BlockContext<'_> {
+        BlockContext {
+            params,
+            height,
+            expected_bits: EASY_BITS,
+            median_time_past: Some(1_600_000_000),
+            adjusted_time: 1_600_000_000,
+        }
+    }
+
+    fn mine(transactions: Vec<Transaction>) -> Result<Block> {
+        let mut block = Block::new(transactions, "0".to_string())?;
+        block.header.bits = EASY_BITS;
+        block.header.timestamp = 1_600_000_001;
+        block.mine_block()?;
+        Ok(block)
+    }
+
+    fn coinbase(wallet: &Wallet, tag: &str, params: &ChainParams) -> Result<Transaction> {
+        Transaction::new_coinbase(&wallet.get_address(), tag, 1, Amount::ZERO, params)
+    }
+
+    fn btc(n: u64) -> Amount {
+        Amount::from_btc(n).unwrap()
+    }
+
+    /// 金额以币为单位
+    fn signed_spend(wallet: &Wallet, inputs: &[(&str, usize, u64)], value: u64) -> Result<Transaction> {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: inputs
+                .iter()
+                .map(|(txid, vout, value)| TxInput::new(txid.to_string(), *vout, btc(*value)))
+                .collect(),
+            vout: vec![TxOutput::new(btc(value), &wallet.get_address())?],
+            lock_time: 0,
+        };
+        tx.id = tx.txid()?;
+        tx.sign(wallet)?;
+        Ok(tx)
+    }
+
+    /// 创建一个只包含`wallet`的一个coinbase输出的UTXO集
+    fn funded_utxo_set(wallet: &Wallet) -> Result<(UTXOSet, Transaction)> {
+        let params = regtest();
+        let funding = Transaction::new_coinbase(&wallet.get_address(), "funding", 0, Amount::ZERO, &params)?;
+        let mut utxo_set = UTXOSet::new();
+        utxo_set.update(std::slice::from_ref(&funding))?;
+        Ok((utxo_set, funding))
+    }
+
+    fn rejection(result: Result<BlockUndo>) -> BlockRejection {
+        match result {
+            Err(RustBtcError::BlockRejected(reason)) => reason,
+            other => panic!("区块应当被拒绝: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_connect_valid_block() -> Result<()> {
+        let params = regtest();
+        let wallet = Wallet::new()?;
+        let (mut utxo_set, funding) = funded_utxo_set(&wallet)?;
+
+        // 区块内的第二笔交易花费第一笔交易的输出
+        let first = signed_spend(&wallet, &[(&funding.id, 0, 50)], 45)?;
+        let second = signed_spend(&wallet, &[(&first.id, 0, 45)], 40)?;
+        let coinbase = Transaction::new_coinbase(&wallet.get_address(), "cb", 1, btc(10), &params)?;
+        let block = mine(vec![coinbase, first, second.clone()])?;
+
+        let undo = connect_block(&block, &context(&params, 1), &mut utxo_set)?;
+        assert_eq!(undo.spent.len(), 2);
+        assert!(utxo_set.get_coin(&second.id, 0)?.is_some());
+        assert_eq!(utxo_set.get_balance(&wallet.get_address())?, Amount::money_sum([btc(40), params.block_subsidy(1), btc(10)]).unwrap());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_structural_rejections() -> Result<()> {
+        let params = regtest();
+        let wallet = Wallet::new()?;
+        let (mut utxo_set, _) = funded_utxo_set(&wallet)?;
+
+        let block = mine(vec![coinbase(&wallet, "cb", &params)?])?;
+        let mut ctx = context(&params, 1);
+        ctx.expected_bits = 0x1f00ffff;
+        assert!(matches!(
+            rejection(connect_block(&block, &ctx, &mut utxo_set)),
+            BlockRejection::BadDifficulty { .. }
+        ));
+
+        let mut ctx = context(&params, 1);
+        ctx.median_time_past = Some(block.header.timestamp);
+        assert!(matches!(
+            rejection(connect_block(&block, &ctx, &mut utxo_set)),
+            BlockRejection::TimeTooOld { .. }
+        ));
+
+        let mut ctx = context(&params, 1);
+        ctx.adjusted_time = block.header.timestamp as u64 - params.max_future_block_time - 1;
+        assert!(matches!(
+            rejection(connect_block(&block, &ctx, &mut utxo_set)),
+            BlockRejection::TimeTooNew { .. }
+        ));
+
+        let mut tampered = block.clone();
+        tampered.transactions.push(coinbase(&wallet, "extra", &params)?);
+        tampered.mine_block()?;
+        assert_eq!(
+            rejection(connect_block(&tampered, &context(&params, 1), &mut utxo_set)),
+            BlockRejection::BadMerkleRoot
+        );
+
+        let two_coinbases = mine(vec![coinbase(&wallet, "a", &params)?, coinbase(&wallet, "b", &params)?])?;
+        assert_eq!(
+            rejection(connect_block(&two_coinbases, &context(&params, 1), &mut utxo_set)),
+            BlockRejection::MultipleCoinbase { index: 1 }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_witness_commitment_rejection() -> Result<()> {
+        let params = regtest();
+        let wallet = Wallet::new()?;
+        let (mut utxo_set, funding) = funded_utxo_set(&wallet)?;
+
+        let spend = signed_spend(&wallet, &[(&funding.id, 0, 50)], 40)?;
+        let block = mine(vec![coinbase(&wallet, "cb", &params)?, spend])?;
+        assert!(block.transactions[0].witness_commitment().is_some());
+
+        // 转发者换上另一个同样有效的签名：交易ID和默克尔根不变，但与见证承诺不符
+        let mut relayed = block.clone();
+        let tx = &mut relayed.transactions[1];
+        let script_code = Script::p2pkh(&hash_pub_key(wallet.get_public_key()));
+        let signature = tx.sign_input(0, &wallet, &script_code, SighashType::AllAnyoneCanPay)?;
+        tx.vin[0].script_sig = Script::p2pkh_unlock(&signature, wallet.get_public_key());
+        assert!(relayed.check_merkle_root()?);
+        assert_eq!(
+            rejection(connect_block(&relayed, &context(&params, 1), &mut utxo_set)),
+            BlockRejection::BadWitnessCommitment
+        );
+
+        connect_block(&block, &context(&params, 1), &mut utxo_set)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_rejections() -> Result<()> {
+        let params = regtest();
+        let wallet = Wallet::new()?;
+        let thief = Wallet::new()?;
+        let (mut utxo_set, funding) = funded_utxo_set(&wallet)?;
+        let ctx = context(&params, 1);
+
+        let missing = signed_spend(&wallet, &[(&"ab".repeat(32), 0, 50)], 40)?;
+        let block = mine(vec![coinbase(&wallet, "cb", &params)?, missing])?;
+        assert!(matches!(
+            rejection(connect_block(&block, &ctx, &mut utxo_set)),
+            BlockRejection::MissingInput { .. }
+        ));
+
+        let a = signed_spend(&wallet, &[(&funding.id, 0, 50)], 40)?;
+        let b = signed_spend(&wallet, &[(&funding.id, 0, 50)], 30)?;
+        let block = mine(vec![coinbase(&wallet, "cb", &params)?, a, b])?;
+        assert!(matches!(
+            rejection(connect_block(&block, &ctx, &mut utxo_set)),
+            BlockRejection::DoubleSpend { .. }
+        ));
+
+        let mut forged = signed_spend(&wallet, &[(&funding.id, 0, 50)], 40)?;
+        let script_code = Script::p2pkh(&hash_pub_key(wallet.get_public_key()));
+        let forged_signature = forged.sign_input(0, &thief, &script_code, SighashType::All)?;
+        forged.vin[0].script_sig = Script::p2pkh_unlock(&forged_signature, wallet.get_public_key());
+        let block = mine(vec![coinbase(&wallet, "cb", &params)?, forged])?;
+        assert!(matches!(
+            rejection(connect_block(&block, &ctx, &mut utxo_set)),
+            BlockRejection::BadScript { input: 0, error: ScriptError::EvalFalse, .. }
+        ));
+
+        // 用自己的公钥和签名花费别人的输出
+        let mut stolen = Transaction {
+            id: String::new(),
+            vin: vec![TxInput::new(funding.id.clone(), 0, btc(50))],
+            vout: vec![TxOutput::new(btc(50), &thief.get_address())?],
+            lock_time: 0,
+        };
+        stolen.id = stolen.txid()?;
+        stolen.sign(&thief)?;
+        let block = mine(vec![coinbase(&wallet, "cb", &params)?, stolen])?;
+        assert!(matches!(
+            rejection(connect_block(&block, &ctx, &mut utxo_set)),
+            BlockRejection::BadScript { input: 0, error: ScriptError::EqualVerify, .. }
+        ));
+
+        let inflating = signed_spend(&wallet, &[(&funding.id, 0, 50)], 60)?;
+        let block = mine(vec![coinbase(&wallet, "cb", &params)?, inflating])?;
+        assert!(matches!(
+            rejection(connect_block(&block, &ctx, &mut utxo_set)),
+            BlockRejection::InputsBelowOutputs { .. }
+        ));
+
+        // 被拒绝的区块不改变UTXO集
+        assert!(utxo_set.get_coin(&funding.id, 0)?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_txid_rejections() -> Result<()> {
+        let params = regtest();
+        let wallet = Wallet::new()?;
+        let (mut utxo_set, funding) = funded_utxo_set(&wallet)?;
+        let ctx = context(&params, 1);
+
+        // 默克尔根由交易内容计算，自报的ID冒用别人的交易ID
+        let mut mislabeled = signed_spend(&wallet, &[(&funding.id, 0, 50)], 40)?;
+        mislabeled.id = funding.id.clone();
+        let block = mine(vec![coinbase(&wallet, "cb", &params)?, mislabeled])?;
+        assert!(block.check_merkle_root()?);
+        assert_eq!(
+            rejection(connect_block(&block, &ctx, &mut utxo_set)),
+            BlockRejection::BadTxid { txid: funding.id.clone() }
+        );
+
+        // 重复的coinbase会覆盖尚未花费的同名输出
+        let duplicate = Transaction::new_coinbase(&wallet.get_address(), "funding", 0, Amount::ZERO, &params)?;
+        assert_eq!(duplicate.id, funding.id);
+        let block = mine(vec![duplicate])?;
+        assert_eq!(
+            rejection(connect_block(&block, &ctx, &mut utxo_set)),
+            BlockRejection::OverwritesUnspent { txid: funding.id.clone(), vout: 0 }
+        );
+
+        assert!(utxo_set.get_coin(&funding.id, 0)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_range_rejections() -> Result<()> {
+        let params = regtest();
+        let wallet = Wallet::new()?;
+        let (mut utxo_set, funding) = funded_utxo_set(&wallet)?;
+        let ctx = context(&params, 1);
+
+        let spend = |values: &[Amount]| -> Result<Block> {
+            let mut tx = Transaction {
+                id: String::new(),
+                vin: vec![TxInput::new(funding.id.clone(), 0, btc(50))],
+                vout: values
+                    .iter()
+                    .map(|value| TxOutput { value: *value, ..TxOutput::new(Amount::ONE_SAT, &wallet.get_address()).unwrap() })
+                    .collect(),
+                lock_time: 0,
+            };
+            tx.id = tx.txid()?;
+            tx.sign(&wallet)?;
+            mine(vec![coinbase(&wallet, "cb", &params)?, tx])
+        };
+
+        // 单个输出超过最大金额
+        let too_large = MAX_MONEY.checked_add(Amount::ONE_SAT).unwrap();
+        assert!(matches!(
+            rejection(connect_block(&spend(&[too_large])?, &ctx, &mut utxo_set)),
+            BlockRejection::BadOutputValue { value, .. } if value == too_large
+        ));
+
+        // 每个输出都有效，但总和超过最大金额
+        assert!(matches!(
+            rejection(connect_block(&spend(&[MAX_MONEY, MAX_MONEY])?, &ctx, &mut utxo_set)),
+            BlockRejection::ValueOutOfRange { .. }
+        ));
+
+        // 0金额只允许出现在不可花费的输出中
+        assert!(matches!(
+            rejection(connect_block(&spend(&[Amount::ZERO])?, &ctx, &mut utxo_set)),
+            BlockRejection::BadOutputValue { .. }
+        ));
+
+        connect_block(&spend(&[btc(50)])?, &ctx, &mut utxo_set)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_timelock_rejections() -> Result<()> {
+        let params = regtest();
+        let wallet = Wallet::new()?;
+        let (mut utxo_set, funding) = funded_utxo_set(&wallet)?;
+        let ctx = context(&params, 1);
+
+        let spend = |lock_time: u32, sequence: u32| -> Result<Block> {
+            let mut tx = Transaction {
+                id: String::new(),
+                vin: vec![TxInput { sequence, ..TxInput::new(funding.id.clone(), 0, btc(50)) }],
+                vout: vec![TxOutput::new(btc(40), &wallet.get_address())?],
+                lock_time,
+            };
+            tx.id = tx.txid()?;
+            tx.sign(&wallet)?;
+            mine(vec![coinbase(&wallet, "cb", &params)?, tx])
+        };
+
+        // 高度锁必须小于区块高度，时间锁必须小于之前的中位时间
+        for lock_time in [1, 1_600_000_000] {
+            assert!(matches!(
+                rejection(connect_block(&spend(lock_time, 0)?, &ctx, &mut utxo_set)),
+                BlockRejection::NonFinal { .. }
+            ));
+        }
+
+        // 输入在高度0创建，相对5个区块的锁要到高度5才能打包
+        assert_eq!(
+            rejection(connect_block(&spend(0, 5)?, &ctx, &mut utxo_set)),
+            BlockRejection::SequenceLocked { txid: spend(0, 5)?.transactions[1].id.clone() }
+        );
+
+        // 时间锁已过，且相对512秒的锁早已满足
+        let block = spend(1_599_999_999, SEQUENCE_LOCKTIME_TYPE_FLAG | 1)?;
+        connect_block(&block, &ctx, &mut utxo_set)?;
+        assert!(utxo_set.get_coin(&funding.id, 0)?.is_none());
+
+        Ok(())
+    }
+}
@@ -1,9 +1,9 @@
-use secp256k1::{Secp256k1, Message, SecretKey, PublicKey};
+use secp256k1::{Secp256k1, Message, SecretKey, PublicKey, KeyPair};
 use rand::rngs::OsRng;
 use sha2::{Sha256, Digest};
 use ripemd::Ripemd160;
 use bs58;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use serde::{Serialize, Deserialize};
 use once_cell::sync::Lazy;
@@ -11,13 +11,83 @@ use std::path::Path;
 use hex;
 
 use super::error::{Result, RustBtcError};
+use crate::script::Script;
 
-const VERSION: u8 = 0x00;
+/// P2PKH地址的版本字节
+pub const P2PKH_VERSION: u8 = 0x00;
+/// P2SH地址的版本字节
+pub const P2SH_VERSION: u8 = 0x05;
+/// P2TR地址的版本字节，其后是32字节的x-only公钥
+pub const P2TR_VERSION: u8 = 0x0a;
 const CHECKSUM_LENGTH: usize = 4;
 const WALLET_FILE: &str = "wallet.dat";
 
 static SECP: Lazy<Secp256k1<secp256k1::All>> = Lazy::new(Secp256k1::new);
 
+/// 公钥哈希：RIPEMD160(SHA256(pubkey))
+pub fn hash_pub_key(pub_key: &[u8]) -> Vec<u8> {
+    let pub_hash = Sha256::digest(pub_key);
+    Ripemd160::digest(pub_hash).to_vec()
+}
+
+fn checksum(payload: &[u8]) -> Vec<u8> {
+    let first_hash = Sha256::digest(payload);
+    let second_hash = Sha256::digest(first_hash);
+    second_hash[..CHECKSUM_LENGTH].to_vec()
+}
+
+/// 以Base58Check编码版本字节和20字节哈希（P2TR地址为32字节公钥）
+pub fn encode_address(version: u8, hash: &[u8]) -> String {
+    let mut version_payload = vec![version];
+    version_payload.extend_from_slice(hash);
+
+    let checksum = checksum(&version_payload);
+    version_payload.extend(checksum);
+
+    bs58::encode(version_payload).into_string()
+}
+
+/// 解码Base58Check地址，校验校验和后返回版本字节和其中的哈希或公钥
+pub fn decode_address(address: &str) -> Result<(u8, Vec<u8>)> {
+    let data = bs58::decode(address)
+        .into_vec()
+        .map_err(|e| RustBtcError::InvalidAddress(e.to_string()))?;
+    if data.len() != 1 + 20 + CHECKSUM_LENGTH && data.len() != 1 + 32 + CHECKSUM_LENGTH {
+        return Err(RustBtcError::InvalidAddress(format!("地址 {} 格式无效", address)));
+    }
+    let (payload, check) = data.split_at(data.len() - CHECKSUM_LENGTH);
+    if checksum(payload) != check {
+        return Err(RustBtcError::InvalidAddress(format!("地址 {} 校验和错误", address)));
+    }
+    Ok((payload[0], payload[1..].to_vec()))
+}
+
+/// 解码P2PKH地址，返回其中的公钥哈希
+pub fn address_to_pubkey_hash(address: &str) -> Result<Vec<u8>> {
+    match decode_address(address)? {
+        (P2PKH_VERSION, pubkey_hash) if pubkey_hash.len() == 20 => Ok(pubkey_hash),
+        _ => Err(RustBtcError::InvalidAddress(format!("地址 {} 不是P2PKH地址", address))),
+    }
+}
+
+/// 地址对应的锁定脚本：P2PKH地址锁定到公钥哈希，P2SH地址锁定到赎回脚本的哈希，P2TR地址锁定到x-only公钥
+pub fn address_to_script_pubkey(address: &str) -> Result<Script> {
+    match decode_address(address)? {
+        (P2PKH_VERSION, pubkey_hash) if pubkey_hash.len() == 20 => Ok(Script::p2pkh(&pubkey_hash)),
+        (P2SH_VERSION, script_hash) if script_hash.len() == 20 => Ok(Script::p2sh(&script_hash)),
+        (P2TR_VERSION, output_key) if output_key.len() == 32 => Ok(Script::p2tr(&output_key)),
+        (version, payload) => Err(RustBtcError::InvalidAddress(format!(
+            "地址 {} 的版本 {} 未知或长度 {} 不符",
+            address, version, payload.len()
+        ))),
+    }
+}
+
+/// 赎回脚本的P2SH地址
+pub fn script_address(redeem_script: &Script) -> String {
+    encode_address(P2SH_VERSION, &hash_pub_key(redeem_script.as_bytes()))
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Wallet {
     secret_key: Vec<u8>,
@@ -26,7 +96,7 @@ pub struct Wallet {
 
 impl Wallet {
     pub fn new() -> Result<Wallet> {
-        let mut rng = OsRng::default();
+        let mut rng = OsRng;
         
         // 生成密钥对
         let (secret_key, public_key) = SECP.generate_keypair(&mut rng);
@@ -38,35 +108,25 @@ impl Wallet {
     }
     
     pub fn get_address(&self) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(&self.public_key);
-        let pub_hash = hasher.finalize();
-        
-        let mut hasher = Ripemd160::new();
-        hasher.update(pub_hash);
-        let pub_ripemd = hasher.finalize();
-        
-        let mut version_payload = vec![VERSION];
-        version_payload.extend(pub_ripemd);
-        
-        let mut hasher = Sha256::new();
-        hasher.update(&version_payload);
-        let first_hash = hasher.finalize();
-        
-        let mut hasher = Sha256::new();
-        hasher.update(first_hash);
-        let second_hash = hasher.finalize();
-        
-        let checksum = &second_hash[..CHECKSUM_LENGTH];
-        version_payload.extend(checksum);
-        
-        bs58::encode(version_payload).into_string()
+        encode_address(P2PKH_VERSION, &hash_pub_key(&self.public_key))
     }
     
     pub fn get_public_key(&self) -> &[u8] {
         &self.public_key
     }
 
+    /// 公钥的32字节x-only形式，用于P2TR输出和Schnorr签名
+    pub fn get_x_only_public_key(&self) -> Result<Vec<u8>> {
+        let public_key = PublicKey::from_slice(&self.public_key)
+            .map_err(|e| RustBtcError::InvalidPublicKey(e.to_string()))?;
+        Ok(public_key.x_only_public_key().0.serialize().to_vec())
+    }
+
+    /// 锁定到x-only公钥的P2TR地址
+    pub fn get_taproot_address(&self) -> Result<String> {
+        Ok(encode_address(P2TR_VERSION, &self.get_x_only_public_key()?))
+    }
+
     pub fn get_private_key(&self) -> &[u8] {
         &self.secret_key
     }
@@ -104,6 +164,21 @@ impl Wallet {
         Ok(signature_bytes)
     }
     
+    /// 对32字节数据生成64字节的BIP340 Schnorr签名
+    pub fn sign_schnorr(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if self.secret_key.is_empty() {
+            return Err(RustBtcError::ValidationError("无法使用只读钱包签名".to_string()));
+        }
+
+        let key_pair = KeyPair::from_seckey_slice(&SECP, &self.secret_key)
+            .map_err(|e| RustBtcError::InvalidSignature(e.to_string()))?;
+        let message = Message::from_slice(data)
+            .map_err(|e| RustBtcError::InvalidSignature(e.to_string()))?;
+
+        let signature = SECP.sign_schnorr_with_rng(&message, &key_pair, &mut OsRng);
+        Ok(signature.as_ref().to_vec())
+    }
+    
     pub fn verify(&self, data: &[u8], signature: &[u8]) -> Result<bool> {
         let public_key = PublicKey::from_slice(&self.public_key)
             .map_err(|e| RustBtcError::InvalidSignature(e.to_string()))?;
@@ -118,9 +193,62 @@ impl Wallet {
     }
 }
 
+/// M-of-N多签账户：资金锁定到多签赎回脚本的P2SH地址，花费时需要其中`required`把私钥签名
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultisigAccount {
+    required: usize,
+    pubkeys: Vec<Vec<u8>>,
+    redeem_script: Script,
+}
+
+impl MultisigAccount {
+    pub fn new(required: usize, pubkeys: Vec<Vec<u8>>) -> Result<MultisigAccount> {
+        for pubkey in &pubkeys {
+            PublicKey::from_slice(pubkey)
+                .map_err(|e| RustBtcError::InvalidPublicKey(e.to_string()))?;
+        }
+
+        let mut seen = HashSet::new();
+        if !pubkeys.iter().all(|pubkey| seen.insert(pubkey)) {
+            return Err(RustBtcError::WalletError("多签账户包含重复的公钥".to_string()));
+        }
+
+        let redeem_script = Script::multisig(required, &pubkeys)
+            .map_err(|e| RustBtcError::WalletError(format!("无法创建{}-of-{}多签: {}", required, pubkeys.len(), e)))?;
+
+        Ok(MultisigAccount {
+            required,
+            pubkeys,
+            redeem_script,
+        })
+    }
+
+    pub fn required(&self) -> usize {
+        self.required
+    }
+
+    pub fn pubkeys(&self) -> &[Vec<u8>] {
+        &self.pubkeys
+    }
+
+    pub fn redeem_script(&self) -> &Script {
+        &self.redeem_script
+    }
+
+    pub fn address(&self) -> String {
+        script_address(&self.redeem_script)
+    }
+
+    /// 公钥在赎回脚本中的位置
+    pub fn key_index(&self, pubkey: &[u8]) -> Option<usize> {
+        self.pubkeys.iter().position(|key| key == pubkey)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Wallets {
     wallets: HashMap<String, Wallet>,
+    multisig: HashMap<String, MultisigAccount>,
 }
 
 impl Wallets {
@@ -128,15 +256,23 @@ impl Wallets {
     pub fn new() -> Result<Wallets> {
         if Path::new(WALLET_FILE).exists() {
             let data = fs::read(WALLET_FILE)
-                .map_err(|e| RustBtcError::Io(e))?;
+                .map_err(RustBtcError::Io)?;
                 
             let wallets: Wallets = bincode::deserialize(&data)
+                .or_else(|_| {
+                    // 兼容没有多签账户的旧钱包文件
+                    bincode::deserialize::<HashMap<String, Wallet>>(&data).map(|wallets| Wallets {
+                        wallets,
+                        multisig: HashMap::new(),
+                    })
+                })
                 .map_err(|e: Box<bincode::ErrorKind>| RustBtcError::Serialization(e))?;
                 
             Ok(wallets)
         } else {
             Ok(Wallets {
                 wallets: HashMap::new(),
+                multisig: HashMap::new(),
             })
         }
     }
@@ -152,6 +288,22 @@ impl Wallets {
         Ok(address)
     }
     
+    // 由一组公钥创建M-of-N多签账户，返回其P2SH地址
+    pub fn create_multisig(&mut self, required: usize, pubkeys: Vec<Vec<u8>>) -> Result<String> {
+        let account = MultisigAccount::new(required, pubkeys)?;
+        let address = account.address();
+
+        self.multisig.insert(address.clone(), account);
+        self.save()?;
+
+        Ok(address)
+    }
+
+    // 获取指定地址的多签账户
+    pub fn get_multisig(&self, address: &str) -> Option<&MultisigAccount> {
+        self.multisig.get(address)
+    }
+
     // 获取所有钱包地址
     pub fn get_addresses(&self) -> Vec<String> {
         self.wallets.keys().cloned().collect()
@@ -168,8 +320,83 @@ impl Wallets {
             .map_err(|e: Box<bincode::ErrorKind>| RustBtcError::Serialization(e))?;
 
         fs::write(WALLET_FILE, data)
-            .map_err(|e| RustBtcError::Io(e))?;
+            .map_err(RustBtcError::Io)?;
             
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_to_pubkey_hash() -> Result<()> {
+        let wallet = Wallet::new()?;
+        let address = wallet.get_address();
+        assert_eq!(address_to_pubkey_hash(&address)?, hash_pub_key(wallet.get_public_key()));
+
+        // 修改一个字符后校验和不再匹配
+        let mut corrupted: Vec<char> = address.chars().collect();
+        corrupted[5] = if corrupted[5] == '2' { '3' } else { '2' };
+        let corrupted: String = corrupted.into_iter().collect();
+        assert!(address_to_pubkey_hash(&corrupted).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multisig_account() -> Result<()> {
+        let keys: Vec<Vec<u8>> = (0..3)
+            .map(|_| Wallet::new().map(|wallet| wallet.get_public_key().to_vec()))
+            .collect::<Result<_>>()?;
+
+        let account = MultisigAccount::new(2, keys.clone())?;
+        assert_eq!(account.redeem_script(), &Script::multisig(2, &keys).unwrap());
+        assert_eq!(address_to_script_pubkey(&account.address())?, account.redeem_script().to_p2sh());
+        assert_eq!(account.key_index(&keys[2]), Some(2));
+
+        assert!(MultisigAccount::new(4, keys.clone()).is_err());
+        assert!(MultisigAccount::new(2, vec![keys[0].clone(), keys[0].clone(), keys[1].clone()]).is_err());
+        assert!(MultisigAccount::new(1, vec![vec![1, 2, 3]]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_address_to_script_pubkey() -> Result<()> {
+        let wallet = Wallet::new()?;
+        let pubkey_hash = hash_pub_key(wallet.get_public_key());
+        assert_eq!(address_to_script_pubkey(&wallet.get_address())?, Script::p2pkh(&pubkey_hash));
+
+        let redeem_script = Script::p2pkh(&pubkey_hash);
+        let address = script_address(&redeem_script);
+        assert_eq!(decode_address(&address)?.0, P2SH_VERSION);
+        assert!(address.starts_with('3'));
+        assert_eq!(address_to_script_pubkey(&address)?, redeem_script.to_p2sh());
+        assert!(address_to_pubkey_hash(&address).is_err());
+
+        Ok(())
+    }
+    #[test]
+    fn test_taproot_address_and_schnorr() -> Result<()> {
+        let wallet = Wallet::new()?;
+        let output_key = wallet.get_x_only_public_key()?;
+        assert_eq!(output_key.len(), 32);
+        assert_eq!(&output_key[..], &wallet.get_public_key()[1..]);
+
+        let address = wallet.get_taproot_address()?;
+        assert_eq!(decode_address(&address)?, (P2TR_VERSION, output_key.clone()));
+        assert_eq!(address_to_script_pubkey(&address)?, Script::p2tr(&output_key));
+        assert!(address_to_pubkey_hash(&address).is_err());
+
+        let message = Sha256::digest(b"taproot");
+        let signature = wallet.sign_schnorr(&message)?;
+        assert_eq!(signature.len(), 64);
+        assert!(crate::transaction::verify_schnorr_signature(&message, &output_key, &signature)?);
+        assert!(!crate::transaction::verify_schnorr_signature(&Sha256::digest(b"other"), &output_key, &signature)?);
+        assert!(Wallet::from_public_key(wallet.get_public_key())?.sign_schnorr(&message).is_err());
+
+        Ok(())
+    }
+}